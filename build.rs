@@ -0,0 +1,37 @@
+//! Records what `/version` reports: the commit built from and the enabled
+//! cargo features.
+
+use std::process::Command;
+
+fn main() {
+    // builds outside a checkout, such as from a tarball, may pass it in
+    println!("cargo:rerun-if-env-changed=GIT_HASH");
+    let hash = std::env::var("GIT_HASH").ok().or_else(git_hash).unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=GIT_HASH={hash}");
+
+    let mut features: Vec<_> = std::env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase().replace('_', "-")))
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=ENABLED_FEATURES={}", features.join(","));
+}
+
+fn git_hash() -> Option<String> {
+    let output = Command::new("git").args(["rev-parse", "--short=12", "HEAD"]).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    // rebuild when HEAD moves, whether by checkout or commit
+    if let Some(git_dir) = git(&["rev-parse", "--git-dir"]) {
+        println!("cargo:rerun-if-changed={git_dir}/HEAD");
+        if let Some(head_ref) = git(&["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed={git_dir}/{head_ref}");
+        }
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_owned())
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
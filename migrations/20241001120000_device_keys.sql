@@ -0,0 +1,31 @@
+-- An alias can hold several device keys, each identified by a `kid`.
+-- Existing keys become the `default` device of their alias.
+
+create table device_keys(
+    alias varchar(100) references keymap(name) on delete cascade,
+    kid varchar(64) not null,
+    public_key jsonb not null,
+    created_at timestamptz not null default now(),
+    primary key (alias, kid)
+);
+insert into device_keys (alias, kid, public_key)
+    select name, 'default', public_key from keymap;
+
+alter table keymap drop column public_key;
+-- SHA-256 digest of the owner token handed out on registration.
+-- Aliases registered before owner tokens existed have none.
+alter table keymap add column owner_token_hash bytea;
+
+-- Every message holds one ciphertext per recipient device.
+create table message_copies(
+    message_id uuid references messages(id) on delete cascade,
+    recipient varchar(100) not null,
+    kid varchar(64) not null,
+    content text not null,
+    primary key (message_id, kid),
+    foreign key (recipient, kid) references device_keys(alias, kid) on delete cascade
+);
+insert into message_copies (message_id, recipient, kid, content)
+    select id, recipient, 'default', content from messages;
+
+alter table messages drop column content;
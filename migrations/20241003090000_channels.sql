@@ -0,0 +1,20 @@
+-- Public one-to-many channels. Posts are signed by the alias owner
+-- with the channel's signing key, and can be read by anyone.
+
+create table channels(
+    alias varchar(100) primary key references keymap(name) on delete cascade,
+    title text,
+    signing_key jsonb not null,
+    created_at timestamptz not null default now()
+);
+
+create table channel_posts(
+    id uuid primary key,
+    channel varchar(100) not null references channels(alias) on delete cascade,
+    content text not null,
+    -- a signature can only be used once, so posts can't be replayed
+    signature bytea not null unique,
+    signed_at timestamptz not null,
+    published_at timestamptz not null
+);
+CREATE INDEX channel_posts_cursor_idx ON channel_posts (channel, published_at DESC, id DESC);
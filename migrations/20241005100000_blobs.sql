@@ -0,0 +1,57 @@
+-- Messages expire; rows published before expiry existed never do.
+alter table messages add column expires_at timestamptz;
+CREATE INDEX messages_expires_at_idx ON messages (expires_at);
+
+-- Content addressed store of encrypted attachments, keyed by SHA-256.
+-- `ref_count` counts the messages citing a blob and is kept up to date
+-- by triggers on `message_blobs`.
+create table blobs(
+    hash bytea primary key,
+    size bigint not null,
+    ref_count integer not null default 0,
+    created_at timestamptz not null default now()
+);
+CREATE INDEX blobs_unreferenced_idx ON blobs (created_at) WHERE ref_count = 0;
+
+-- Blob content when the database storage backend is selected.
+create table blob_data(
+    hash bytea primary key references blobs(hash) on delete cascade,
+    data bytea not null
+);
+
+create table message_blobs(
+    message_id uuid references messages(id) on delete cascade,
+    blob_hash bytea references blobs(hash),
+    primary key (message_id, blob_hash)
+);
+
+create function message_blobs_ref_count() returns trigger as $$
+begin
+    if tg_op = 'INSERT' then
+        update blobs set ref_count = ref_count + 1 where hash = new.blob_hash;
+        return new;
+    else
+        update blobs set ref_count = ref_count - 1 where hash = old.blob_hash;
+        return old;
+    end if;
+end;
+$$ language plpgsql;
+
+create trigger message_blobs_ref_count
+    after insert or delete on message_blobs
+    for each row execute function message_blobs_ref_count();
+
+-- Chunked uploads in progress.
+create table blob_uploads(
+    id uuid primary key,
+    size bigint not null,
+    received bigint not null default 0,
+    created_at timestamptz not null default now()
+);
+-- Chunks of uploads in progress when the database storage backend is selected.
+create table blob_upload_chunks(
+    upload_id uuid references blob_uploads(id) on delete cascade,
+    "offset" bigint not null,
+    data bytea not null,
+    primary key (upload_id, "offset")
+);
@@ -0,0 +1,20 @@
+-- Per-recipient message sequence used as the mailbox cursor.
+-- It is assigned while holding the recipient's `keymap` row lock, so
+-- sequence order is also commit order and resuming after a cursor never
+-- skips a message.
+alter table keymap add column last_seq bigint not null default 0;
+alter table messages add column seq bigint;
+
+update messages m set seq = numbered.seq
+from (
+    select id, row_number() over (partition by recipient order by sent_at, id) as seq
+    from messages
+) numbered
+where m.id = numbered.id;
+
+update keymap k set last_seq = coalesce(
+    (select max(seq) from messages m where m.recipient = k.name), 0
+);
+
+alter table messages alter column seq set not null;
+CREATE UNIQUE INDEX messages_recipient_seq_idx ON messages (recipient, seq);
@@ -0,0 +1,33 @@
+-- Webhook an alias owner registered to receive its messages.
+-- The secret signs deliveries, so it is stored as is.
+create table webhooks(
+    alias varchar(100) primary key references keymap(name) on delete cascade,
+    url text not null,
+    secret bytea not null,
+    created_at timestamptz not null default now()
+);
+
+-- Deliveries waiting to be sent, written in the same transaction as
+-- the message so none is lost.
+create table webhook_outbox(
+    id uuid primary key,
+    alias varchar(100) not null references webhooks(alias) on delete cascade,
+    message_id uuid not null references messages(id) on delete cascade,
+    attempts integer not null default 0,
+    next_attempt_at timestamptz not null default now(),
+    created_at timestamptz not null default now()
+);
+CREATE INDEX webhook_outbox_due_idx ON webhook_outbox (next_attempt_at);
+
+-- Every delivery attempt, for the owner to inspect.
+create table webhook_deliveries(
+    id bigserial primary key,
+    alias varchar(100) not null references keymap(name) on delete cascade,
+    delivery_id uuid not null,
+    message_id uuid not null,
+    attempt integer not null,
+    status_code integer,
+    error text,
+    attempted_at timestamptz not null default now()
+);
+CREATE INDEX webhook_deliveries_alias_idx ON webhook_deliveries (alias, attempted_at DESC);
@@ -0,0 +1,11 @@
+-- Web Push subscriptions, as handed out by the browser's PushManager.
+create table push_subscriptions(
+    alias varchar(100) not null references keymap(name) on delete cascade,
+    endpoint text not null,
+    -- uncompressed P-256 public key of the user agent
+    p256dh bytea not null,
+    -- authentication secret of the user agent
+    auth bytea not null,
+    created_at timestamptz not null default now(),
+    primary key (alias, endpoint)
+);
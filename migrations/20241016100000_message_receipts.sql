@@ -0,0 +1,11 @@
+-- What a sender may learn about a message it published. Receipts outlive
+-- the message, so they have no foreign key to it.
+create table message_receipts(
+    message_id uuid primary key,
+    -- sha256 digest of the sender token
+    sender_token_hash bytea not null,
+    expires_at timestamptz,
+    fetched_at timestamptz,
+    deleted_at timestamptz
+);
+CREATE INDEX message_receipts_expires_at_idx ON message_receipts (expires_at);
@@ -0,0 +1,14 @@
+-- Responses to POST requests carrying an Idempotency-Key header, replayed
+-- when a client retries. A row without a status is still being processed.
+create table idempotency_keys(
+    key text not null,
+    path text not null,
+    -- sha256 over the query, authorization and body of the first request
+    request_hash bytea not null,
+    status_code smallint,
+    content_type text,
+    body bytea,
+    created_at timestamptz not null default now(),
+    primary key (key, path)
+);
+CREATE INDEX idempotency_keys_created_at_idx ON idempotency_keys (created_at);
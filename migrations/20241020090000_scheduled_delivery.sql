@@ -0,0 +1,10 @@
+-- Messages published with a deliver-after time stay hidden until then.
+-- They get no `seq` before being delivered, so they are appended to the
+-- mailbox when they become visible and cursors never skip them.
+alter table messages add column deliver_after timestamptz;
+alter table messages alter column seq drop not null;
+CREATE INDEX messages_scheduled_idx ON messages (deliver_after) WHERE seq IS NULL;
+
+alter table message_receipts add column deliver_after timestamptz;
+alter table message_receipts add column cancel_token_hash bytea;
+alter table message_receipts add column cancelled_at timestamptz;
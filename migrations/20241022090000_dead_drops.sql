@@ -0,0 +1,24 @@
+-- Anonymous mailboxes addressed by a random token instead of an alias.
+-- Senders know the drop token, the reader holds a separate read token.
+create table drops(
+    id uuid primary key,
+    -- sha256 digest of the drop token
+    token_hash bytea not null unique,
+    -- sha256 digest of the read token
+    read_token_hash bytea not null unique,
+    public_key jsonb not null,
+    last_seq bigint not null default 0,
+    created_at timestamptz not null default now(),
+    expires_at timestamptz not null
+);
+CREATE INDEX drops_expires_at_idx ON drops (expires_at);
+
+-- A message is addressed to either an alias or a drop, and is removed
+-- along with its drop.
+alter table messages add column drop_id uuid references drops(id) on delete cascade;
+alter table messages add constraint messages_mailbox_check
+    check ((recipient is null) <> (drop_id is null));
+CREATE UNIQUE INDEX messages_drop_seq_idx ON messages (drop_id, seq);
+
+-- Drops have a single key, so their copies reference no device key.
+alter table message_copies alter column recipient drop not null;
@@ -0,0 +1,11 @@
+-- Difficulty an alias asks of its senders, on top of the server's.
+alter table keymap add column pow_difficulty smallint
+    check (pow_difficulty between 0 and 255);
+
+-- Nonces of accepted proof of work stamps, kept until they expire so
+-- no stamp is accepted twice.
+create table spent_stamps(
+    nonce bytea primary key,
+    expires_at timestamptz not null
+);
+CREATE INDEX spent_stamps_expires_at_idx ON spent_stamps (expires_at);
@@ -0,0 +1,9 @@
+-- Nonces of redeemed anonymous tokens. Tokens of a retired issuer key no
+-- longer verify, so their nonces can be forgotten.
+create table spent_tokens(
+    nonce bytea primary key,
+    -- sha256 digest of the issuer key modulus
+    key_id bytea not null,
+    spent_at timestamptz not null default now()
+);
+CREATE INDEX spent_tokens_key_id_idx ON spent_tokens (key_id);
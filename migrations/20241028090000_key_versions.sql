@@ -0,0 +1,24 @@
+-- `key_version` is bumped whenever the device keys of an alias change, so
+-- cached key sets and their ETags can be told stale. Changes are also
+-- announced on the `registry_keys` channel, for the caches of every
+-- instance to drop the alias.
+alter table keymap add column key_version bigint not null default 1;
+
+create function device_keys_changed() returns trigger as $$
+declare
+    changed varchar(100);
+begin
+    if tg_op = 'DELETE' then
+        changed := old.alias;
+    else
+        changed := new.alias;
+    end if;
+    update keymap set key_version = key_version + 1 where name = changed;
+    perform pg_notify('registry_keys', changed);
+    return null;
+end;
+$$ language plpgsql;
+
+create trigger device_keys_changed
+    after insert or update or delete on device_keys
+    for each row execute function device_keys_changed();
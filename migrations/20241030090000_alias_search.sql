@@ -0,0 +1,4 @@
+-- Levenshtein distances for alias search. The similarity threshold of
+-- trigram search is no longer set here: `SET` only lasts for the session
+-- running the migration, so the search sets it per transaction instead.
+create extension if not exists "fuzzystrmatch";
@@ -0,0 +1,8 @@
+-- Idempotency keys are scoped to the client and authorization that sent
+-- them, so nobody replays the response to another client. Stored
+-- responses no longer hold secrets, and those stored before may, so they
+-- are dropped.
+delete from idempotency_keys;
+alter table idempotency_keys add column caller bytea not null;
+alter table idempotency_keys drop constraint idempotency_keys_pkey;
+alter table idempotency_keys add primary key (key, path, caller);
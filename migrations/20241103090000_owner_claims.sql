@@ -0,0 +1,9 @@
+-- Aliases registered before owner tokens existed claim one by proving they
+-- hold one of their device keys: a token is handed out encrypted to the
+-- keys, and only becomes the owner token once presented back.
+create table owner_claims(
+    token_hash bytea primary key,
+    alias varchar(100) not null references keymap(name) on delete cascade,
+    expires_at timestamptz not null
+);
+create index owner_claims_alias on owner_claims (alias);
@@ -0,0 +1,8 @@
+-- Idempotency keys are only stored as a hash of the key, path and
+-- authorization they were sent with, and responses are stored encrypted
+-- under a key derived from them, so they replay verbatim, secrets
+-- included. Stored responses lack those secrets, so they are dropped.
+delete from idempotency_keys;
+alter table idempotency_keys drop constraint idempotency_keys_pkey;
+alter table idempotency_keys drop column key, drop column path, drop column caller;
+alter table idempotency_keys add column key_hash bytea primary key;
@@ -0,0 +1,3 @@
+-- Levenshtein search only compares names of a length that can score above
+-- its threshold.
+create index keymap_name_length on keymap (length(name));
@@ -11,3 +11,10 @@ fi
 cargo build --release
 scp target/release/blindchannel "${PROD_HOST}:${PROD_DIR}/"
 ssh "${PROD_HOST}" "bash ${REPLACE_FILE}"
+
+# the new binary only counts as deployed once it serves requests on a migrated database
+READY_URL="${READY_URL:=http://127.0.0.1:8080/ready}"
+if ! ssh "${PROD_HOST}" "curl --silent --fail --retry 15 --retry-delay 2 --retry-all-errors ${READY_URL}"; then
+    echo >&2 "Deployed binary did not become ready, check ${READY_URL} on ${PROD_HOST}."
+    exit 1
+fi
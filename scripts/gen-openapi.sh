@@ -8,11 +8,8 @@ if ! [ -x "$(command -v openapi-generator-cli)" ]; then
     exit 1
 fi
 
-if [ -z "$1" ]; then
-    echo "Please supply path to openapi.yaml"
-    exit 1
-fi
-
+# the spec is generated by the server, which must be running
+SPEC="${1:-http://localhost:8080/api/openapi.json}"
 
-openapi-generator-cli generate -i "$1" -g typescript-fetch -o web/src/oapi \
+openapi-generator-cli generate -i "$SPEC" -g typescript-fetch -o web/src/oapi \
     --additional-properties=supportsES6=true
\ No newline at end of file
@@ -0,0 +1,57 @@
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+use super::BlobError;
+use crate::domain::blob::BlobHash;
+
+pub(super) async fn write_chunk(
+    conn: &mut PgConnection,
+    id: Uuid,
+    offset: u64,
+    data: &[u8],
+) -> Result<(), BlobError> {
+    sqlx::query!(
+        r#"INSERT INTO blob_upload_chunks (upload_id, "offset", data) VALUES ($1, $2, $3)"#,
+        id,
+        offset as i64,
+        data
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Returns the hash of the uploaded chunks.
+pub(super) async fn hash_upload(conn: &mut PgConnection, id: Uuid) -> Result<BlobHash, BlobError> {
+    let hash = sqlx::query_scalar!(
+        r#"SELECT sha256(string_agg(data, ''::bytea ORDER BY "offset")) as "hash!"
+        FROM blob_upload_chunks WHERE upload_id = $1"#,
+        id
+    )
+    .fetch_one(conn)
+    .await?;
+    BlobHash::try_from(&hash[..]).map_err(|e| sqlx::Error::Decode(e.into()).into())
+}
+
+/// Moves the chunks of a finished upload into `blob_data`.
+/// Must run before the upload row is deleted, which drops its chunks.
+pub(super) async fn store(conn: &mut PgConnection, id: Uuid, hash: BlobHash) -> Result<(), BlobError> {
+    sqlx::query!(
+        r#"INSERT INTO blob_data (hash, data)
+        SELECT $2, string_agg(data, ''::bytea ORDER BY "offset")
+        FROM blob_upload_chunks WHERE upload_id = $1
+        ON CONFLICT (hash) DO NOTHING"#,
+        id,
+        hash.as_bytes()
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+pub(super) async fn read(pool: &PgPool, hash: BlobHash) -> Result<Vec<u8>, BlobError> {
+    sqlx::query_scalar!("SELECT data FROM blob_data WHERE hash = $1", hash.as_bytes())
+        .fetch_optional(pool)
+        .await?
+        .ok_or(BlobError::NotFound)
+}
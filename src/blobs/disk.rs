@@ -0,0 +1,85 @@
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use uuid::Uuid;
+
+use super::BlobError;
+use crate::domain::blob::BlobHash;
+
+fn upload_path(root: &Path, id: Uuid) -> PathBuf {
+    root.join("uploads").join(id.to_string())
+}
+
+/// Blobs are sharded by the first byte of their hash.
+fn blob_path(root: &Path, hash: BlobHash) -> PathBuf {
+    let hex = hash.to_string();
+    root.join("blobs").join(&hex[..2]).join(hex)
+}
+
+pub(super) async fn write_chunk(root: &Path, id: Uuid, offset: u64, data: &[u8]) -> Result<(), BlobError> {
+    let path = upload_path(root, id);
+    tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .await?;
+    // drop leftovers of a chunk whose upload failed halfway
+    file.set_len(offset).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.write_all(data).await?;
+    file.sync_data().await?;
+    Ok(())
+}
+
+/// Hashes the uploaded file and moves it into place if it matches `expected`.
+/// Returns the actual hash.
+pub(super) async fn finish_upload(root: &Path, id: Uuid, expected: BlobHash) -> Result<BlobHash, BlobError> {
+    let path = upload_path(root, id);
+    let mut file = tokio::fs::File::open(&path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    let hash = BlobHash::from_digest(hasher);
+    if hash != expected {
+        discard_upload(root, id).await?;
+        return Ok(hash);
+    }
+    let dest = blob_path(root, hash);
+    tokio::fs::create_dir_all(dest.parent().unwrap()).await?;
+    tokio::fs::rename(&path, &dest).await?;
+    Ok(hash)
+}
+
+pub(super) async fn read(root: &Path, hash: BlobHash) -> Result<Vec<u8>, BlobError> {
+    match tokio::fs::read(blob_path(root, hash)).await {
+        Ok(data) => Ok(data),
+        Err(e) if e.kind() == ErrorKind::NotFound => Err(BlobError::NotFound),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub(super) async fn discard_upload(root: &Path, id: Uuid) -> Result<(), BlobError> {
+    remove_file(&upload_path(root, id)).await
+}
+
+pub(super) async fn remove(root: &Path, hash: BlobHash) -> Result<(), BlobError> {
+    remove_file(&blob_path(root, hash)).await
+}
+
+async fn remove_file(path: &Path) -> Result<(), BlobError> {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,237 @@
+//! Content addressed store for encrypted message attachments.
+//!
+//! Upload sessions and blob metadata always live in Postgres; the content
+//! itself is kept either on local disk or in the `blob_data` table,
+//! depending on [`BlobStorage`].
+
+mod database;
+mod disk;
+
+use std::sync::Arc;
+
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::configuration::{BlobSettings, BlobStorage};
+use crate::domain::blob::BlobHash;
+
+#[derive(Debug)]
+pub enum BlobError {
+    NotFound,
+    /// declared size or chunk exceeds the configured limits
+    TooLarge,
+    /// chunk does not start where the upload left off
+    OffsetMismatch { received: u64 },
+    /// upload is not complete yet
+    Incomplete { received: u64 },
+    /// uploaded content does not match the declared hash
+    HashMismatch,
+    Database(sqlx::Error),
+    Io(std::io::Error),
+}
+impl std::fmt::Display for BlobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => f.write_str("blob or upload not found"),
+            Self::TooLarge => f.write_str("blob exceeds size limit"),
+            Self::OffsetMismatch { received } => {
+                write!(f, "chunk offset does not match {received} bytes received")
+            }
+            Self::Incomplete { received } => write!(f, "upload incomplete, {received} bytes received"),
+            Self::HashMismatch => f.write_str("uploaded content does not match hash"),
+            Self::Database(e) => write!(f, "database error: {e}"),
+            Self::Io(e) => write!(f, "io error: {e}"),
+        }
+    }
+}
+impl From<sqlx::Error> for BlobError {
+    fn from(value: sqlx::Error) -> Self {
+        Self::Database(value)
+    }
+}
+impl From<std::io::Error> for BlobError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BlobInfo {
+    pub hash: BlobHash,
+    pub size: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GcStats {
+    pub uploads: u64,
+    pub blobs: u64,
+}
+
+#[derive(Clone)]
+pub struct BlobStore {
+    pool: PgPool,
+    settings: Arc<BlobSettings>,
+}
+
+impl BlobStore {
+    pub fn new(pool: PgPool, settings: BlobSettings) -> Self {
+        Self {
+            pool,
+            settings: Arc::new(settings),
+        }
+    }
+    pub fn max_chunk_size(&self) -> usize {
+        self.settings.max_chunk_size
+    }
+
+    /// Opens an upload session for a blob of `size` bytes.
+    pub async fn start_upload(&self, size: u64) -> Result<Uuid, BlobError> {
+        if size == 0 || size > self.settings.max_blob_size {
+            return Err(BlobError::TooLarge);
+        }
+        let id = sqlx::query_scalar!(
+            "INSERT INTO blob_uploads (id, size) VALUES (gen_random_uuid(), $1) RETURNING id",
+            size as i64
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(id)
+    }
+
+    /// Writes a chunk at `offset`, which must equal the bytes received so far.
+    /// Returns the new number of bytes received.
+    pub async fn write_chunk(&self, id: Uuid, offset: u64, data: &[u8]) -> Result<u64, BlobError> {
+        if data.len() > self.settings.max_chunk_size {
+            return Err(BlobError::TooLarge);
+        }
+        let mut tx = self.pool.begin().await?;
+        let upload = sqlx::query!(
+            "SELECT size, received FROM blob_uploads WHERE id = $1 FOR UPDATE",
+            id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(BlobError::NotFound)?;
+        let received = upload.received as u64;
+        if offset != received {
+            return Err(BlobError::OffsetMismatch { received });
+        }
+        let total = received + data.len() as u64;
+        if total > upload.size as u64 {
+            return Err(BlobError::TooLarge);
+        }
+        match &self.settings.storage {
+            BlobStorage::Disk { path } => disk::write_chunk(path, id, offset, data).await?,
+            BlobStorage::Database => database::write_chunk(&mut tx, id, offset, data).await?,
+        }
+        sqlx::query!(
+            "UPDATE blob_uploads SET received = $2 WHERE id = $1",
+            id,
+            total as i64
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(total)
+    }
+
+    /// Completes an upload, storing its content under `expected` if the hashes match.
+    /// The upload is discarded on a hash mismatch.
+    pub async fn finish_upload(&self, id: Uuid, expected: BlobHash) -> Result<BlobInfo, BlobError> {
+        let mut tx = self.pool.begin().await?;
+        let upload = sqlx::query!(
+            "SELECT size, received FROM blob_uploads WHERE id = $1 FOR UPDATE",
+            id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(BlobError::NotFound)?;
+        if upload.received != upload.size {
+            return Err(BlobError::Incomplete {
+                received: upload.received as u64,
+            });
+        }
+        let hash = match &self.settings.storage {
+            BlobStorage::Disk { path } => disk::finish_upload(path, id, expected).await?,
+            BlobStorage::Database => database::hash_upload(&mut tx, id).await?,
+        };
+        if hash == expected {
+            // a blob uploaded again restarts its grace period, so garbage
+            // collection doesn't take it before the message referencing it
+            sqlx::query!(
+                "INSERT INTO blobs (hash, size) VALUES ($1, $2) ON CONFLICT (hash) DO UPDATE SET created_at = now()",
+                hash.as_bytes(),
+                upload.size
+            )
+            .execute(&mut *tx)
+            .await?;
+            if let BlobStorage::Database = self.settings.storage {
+                database::store(&mut tx, id, hash).await?;
+            }
+        }
+        sqlx::query!("DELETE FROM blob_uploads WHERE id = $1", id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        if hash != expected {
+            return Err(BlobError::HashMismatch);
+        }
+        Ok(BlobInfo {
+            hash,
+            size: upload.size as u64,
+        })
+    }
+
+    pub async fn read(&self, hash: BlobHash) -> Result<Vec<u8>, BlobError> {
+        match &self.settings.storage {
+            BlobStorage::Disk { path } => {
+                let exists = sqlx::query_scalar!(
+                    r#"SELECT 1 as "found!" FROM blobs WHERE hash = $1"#,
+                    hash.as_bytes()
+                )
+                .fetch_optional(&self.pool)
+                .await?;
+                if exists.is_none() {
+                    return Err(BlobError::NotFound);
+                }
+                disk::read(path, hash).await
+            }
+            BlobStorage::Database => database::read(&self.pool, hash).await,
+        }
+    }
+
+    /// Removes abandoned uploads, and blobs no message has cited
+    /// for longer than the grace period.
+    pub async fn collect_garbage(&self) -> Result<GcStats, BlobError> {
+        let upload_ttl = self.settings.upload_ttl().as_secs_f64();
+        let uploads = sqlx::query_scalar!(
+            "DELETE FROM blob_uploads WHERE created_at < now() - make_interval(secs => $1) RETURNING id",
+            upload_ttl
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let grace = self.settings.gc_grace().as_secs_f64();
+        let blobs = sqlx::query_scalar!(
+            r#"DELETE FROM blobs
+            WHERE ref_count = 0 AND created_at < now() - make_interval(secs => $1)
+            RETURNING hash"#,
+            grace
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        if let BlobStorage::Disk { path } = &self.settings.storage {
+            for id in &uploads {
+                disk::discard_upload(path, *id).await?;
+            }
+            for hash in &blobs {
+                if let Ok(hash) = BlobHash::try_from(&hash[..]) {
+                    disk::remove(path, hash).await?;
+                }
+            }
+        }
+        Ok(GcStats {
+            uploads: uploads.len() as u64,
+            blobs: blobs.len() as u64,
+        })
+    }
+}
@@ -1,4 +1,7 @@
+use std::num::NonZeroU32;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 use config::{Config, FileFormat};
 use secrecy::{ExposeSecret, Secret};
@@ -6,6 +9,8 @@ use serde::Deserialize;
 use sqlx::postgres::{PgConnectOptions, PgSslMode};
 use std::sync::LazyLock;
 
+use crate::ratelimit::{IpNet, Quota};
+
 static ENVIRONMENT: LazyLock<Environment> = LazyLock::new(|| {
     std::env::var("APP_ENVIRONMENT")
         .unwrap_or_else(|_| "local".into())
@@ -31,11 +36,525 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
 pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
+    #[serde(default)]
+    pub messages: MessageSettings,
+    #[serde(default)]
+    pub blobs: BlobSettings,
+    #[serde(default)]
+    pub reaper: ReaperSettings,
+    #[serde(default)]
+    pub webhooks: WebhookSettings,
+    #[serde(default)]
+    pub push: PushSettings,
+    #[serde(default)]
+    pub idempotency: IdempotencySettings,
+    #[serde(default)]
+    pub drops: DropSettings,
+    #[serde(default)]
+    pub pow: PowSettings,
+    #[serde(default)]
+    pub tokens: TokenSettings,
+    #[serde(default)]
+    pub metrics: MetricsSettings,
+    #[serde(default)]
+    pub registry: RegistrySettings,
+    #[serde(default)]
+    pub search: SearchSettings,
 }
 #[derive(Deserialize)]
 pub struct ApplicationSettings {
     pub host: String,
     pub port: u16,
+    #[serde(default)]
+    pub limits: LimitSettings,
+    #[serde(default)]
+    pub rate_limits: RateLimitSettings,
+    #[serde(default)]
+    pub shutdown: ShutdownSettings,
+    /// serves HTTPS rather than plain HTTP when set
+    #[serde(default)]
+    pub tls: Option<TlsSettings>,
+}
+
+/// Bounds on what a single request, or all of them together, may use.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct LimitSettings {
+    /// max size of a request body, in bytes
+    pub body_limit_bytes: usize,
+    /// max size of the body of a published message, in bytes
+    pub publish_body_limit_bytes: usize,
+    /// max size of the body of a message batch, in bytes
+    pub batch_body_limit_bytes: usize,
+    /// seconds a request may take, except long-polls and streams
+    pub request_timeout_secs: u64,
+    /// requests handled at once, further ones are answered with 503
+    pub max_concurrent_requests: usize,
+    /// seconds clients are told to wait after a 503
+    pub retry_after_secs: u64,
+}
+impl Default for LimitSettings {
+    fn default() -> Self {
+        Self {
+            body_limit_bytes: 256 * 1024,
+            publish_body_limit_bytes: 1024 * 1024,
+            batch_body_limit_bytes: 16 * 1024 * 1024,
+            request_timeout_secs: 30,
+            max_concurrent_requests: 1024,
+            retry_after_secs: 1,
+        }
+    }
+}
+impl LimitSettings {
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+}
+
+/// TLS terminated by the server itself, rather than a reverse proxy.
+#[derive(Deserialize, Clone)]
+pub struct TlsSettings {
+    /// PEM file with the certificate chain, leaf first
+    pub cert_path: PathBuf,
+    /// PEM file with the PKCS#8, PKCS#1 or SEC1 private key
+    pub key_path: PathBuf,
+    /// oldest protocol version accepted
+    #[serde(default)]
+    pub min_version: TlsVersion,
+    /// port answering plain HTTP with redirects to HTTPS, none without
+    #[serde(default)]
+    pub redirect_port: Option<u16>,
+    /// seconds between checks of whether the certificate or key changed
+    #[serde(default = "TlsSettings::default_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+}
+impl TlsSettings {
+    fn default_reload_interval_secs() -> u64 {
+        60
+    }
+    pub fn reload_interval(&self) -> Duration {
+        Duration::from_secs(self.reload_interval_secs)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default, Debug, PartialEq)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    V1_2,
+    #[serde(rename = "1.3")]
+    V1_3,
+}
+
+/// How long a shutdown may take.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct ShutdownSettings {
+    /// seconds in-flight requests are given to finish, and then background
+    /// jobs, before they are cut off
+    pub drain_timeout_secs: u64,
+}
+impl Default for ShutdownSettings {
+    fn default() -> Self {
+        Self { drain_timeout_secs: 30 }
+    }
+}
+impl ShutdownSettings {
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout_secs)
+    }
+}
+
+/// Token bucket quotas of the rate limited routes, per client address
+/// unless said otherwise. A route without a quota is not limited.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    /// addresses or CIDR ranges of reverse proxies whose `X-Forwarded-For` is believed
+    pub trusted_proxies: Vec<IpNet>,
+    pub register: Option<Quota>,
+    pub search: Option<Quota>,
+    /// shared by `/publish`, `/publish/batch` and drops
+    pub publish: Option<Quota>,
+    /// messages per recipient, over `/publish` and `/publish/batch`
+    pub publish_per_recipient: Option<Quota>,
+    /// batches of tokens signed, each a private key operation, see [`crate::tokens`]
+    pub issue: Option<Quota>,
+}
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        let quota = |burst, per_minute| {
+            Some(Quota {
+                burst: NonZeroU32::new(burst).unwrap(),
+                per_minute: NonZeroU32::new(per_minute).unwrap(),
+            })
+        };
+        Self {
+            trusted_proxies: Vec::new(),
+            register: quota(10, 2),
+            search: quota(60, 60),
+            publish: quota(120, 120),
+            publish_per_recipient: quota(60, 30),
+            issue: quota(5, 1),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct MessageSettings {
+    /// days until a published message expires, never when unset
+    pub ttl_days: Option<u32>,
+    /// days a sender can still query the status of an expired message
+    pub receipt_retention_days: u32,
+    /// furthest ahead in days a message may be scheduled for delivery
+    pub max_schedule_days: u32,
+    /// how often scheduled messages are checked for delivery
+    pub schedule_interval_secs: u64,
+}
+impl Default for MessageSettings {
+    fn default() -> Self {
+        Self {
+            ttl_days: None,
+            receipt_retention_days: 7,
+            max_schedule_days: 365,
+            schedule_interval_secs: 5,
+        }
+    }
+}
+impl MessageSettings {
+    pub fn ttl(&self) -> Option<chrono::TimeDelta> {
+        self.ttl_days.map(|days| chrono::TimeDelta::days(days.into()))
+    }
+    pub fn receipt_retention(&self) -> Duration {
+        Duration::from_secs(u64::from(self.receipt_retention_days) * 24 * 60 * 60)
+    }
+    pub fn max_schedule(&self) -> chrono::TimeDelta {
+        chrono::TimeDelta::days(self.max_schedule_days.into())
+    }
+    pub fn schedule_interval(&self) -> Duration {
+        Duration::from_secs(self.schedule_interval_secs)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct BlobSettings {
+    pub storage: BlobStorage,
+    /// max size of a whole blob, in bytes
+    pub max_blob_size: u64,
+    /// max size of a single upload chunk, in bytes
+    pub max_chunk_size: usize,
+    /// seconds an unfinished upload is kept
+    pub upload_ttl_secs: u64,
+    /// seconds an unreferenced blob is kept, so it can be cited after upload
+    pub gc_grace_secs: u64,
+}
+impl Default for BlobSettings {
+    fn default() -> Self {
+        Self {
+            storage: BlobStorage::Database,
+            max_blob_size: 25 * 1024 * 1024,
+            max_chunk_size: 1024 * 1024,
+            upload_ttl_secs: 24 * 60 * 60,
+            gc_grace_secs: 60 * 60,
+        }
+    }
+}
+impl BlobSettings {
+    pub fn upload_ttl(&self) -> Duration {
+        Duration::from_secs(self.upload_ttl_secs)
+    }
+    pub fn gc_grace(&self) -> Duration {
+        Duration::from_secs(self.gc_grace_secs)
+    }
+}
+
+/// Where encrypted attachments are kept.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum BlobStorage {
+    /// files under `path`
+    Disk { path: PathBuf },
+    /// rows in the `blob_data` table
+    Database,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct ReaperSettings {
+    /// seconds between runs of the expiry and garbage collection task
+    pub interval_secs: u64,
+}
+impl Default for ReaperSettings {
+    fn default() -> Self {
+        Self { interval_secs: 300 }
+    }
+}
+impl ReaperSettings {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct WebhookSettings {
+    /// seconds between polls of the outbox
+    pub poll_interval_secs: u64,
+    /// seconds to wait for a webhook to answer
+    pub timeout_secs: u64,
+    /// attempts before a delivery is given up
+    pub max_attempts: u32,
+    /// delay before the first retry, doubled on every further one
+    pub base_backoff_secs: u64,
+    /// days delivery log entries are kept
+    pub log_retention_days: u32,
+    /// allow plain http and loopback or private addresses, for local testing
+    pub allow_insecure: bool,
+}
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            poll_interval_secs: 2,
+            timeout_secs: 10,
+            max_attempts: 8,
+            base_backoff_secs: 5,
+            log_retention_days: 7,
+            allow_insecure: false,
+        }
+    }
+}
+impl WebhookSettings {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs)
+    }
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+    pub fn base_backoff(&self) -> Duration {
+        Duration::from_secs(self.base_backoff_secs)
+    }
+    pub fn log_retention(&self) -> Duration {
+        Duration::from_secs(u64::from(self.log_retention_days) * 24 * 60 * 60)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct PushSettings {
+    /// base64url encoded P-256 private key signing VAPID claims.
+    /// Web Push is disabled without one.
+    pub vapid_private_key: Option<Secret<String>>,
+    /// contact of the operator for push services, a `mailto:` or `https:` URL
+    pub vapid_subject: String,
+    /// seconds a push service keeps a notification for an offline device
+    pub ttl_secs: u32,
+    /// seconds to wait for a push service to answer
+    pub timeout_secs: u64,
+    /// allow plain http and loopback or private push endpoints, for local testing
+    pub allow_insecure: bool,
+}
+impl Default for PushSettings {
+    fn default() -> Self {
+        Self {
+            vapid_private_key: None,
+            vapid_subject: "mailto:admin@localhost".into(),
+            ttl_secs: 24 * 60 * 60,
+            timeout_secs: 10,
+            allow_insecure: false,
+        }
+    }
+}
+impl PushSettings {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct IdempotencySettings {
+    /// seconds a response is kept for replay to retried requests
+    pub window_secs: u64,
+}
+impl Default for IdempotencySettings {
+    fn default() -> Self {
+        Self {
+            window_secs: 24 * 60 * 60,
+        }
+    }
+}
+impl IdempotencySettings {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct DropSettings {
+    /// seconds a drop lives when its creator asks for no ttl
+    pub default_ttl_secs: u64,
+    /// longest ttl in seconds a drop may be created with
+    pub max_ttl_secs: u64,
+    /// most messages published to a drop over its lifetime
+    pub max_messages: u32,
+}
+impl Default for DropSettings {
+    fn default() -> Self {
+        Self {
+            default_ttl_secs: 24 * 60 * 60,
+            max_ttl_secs: 30 * 24 * 60 * 60,
+            max_messages: 1000,
+        }
+    }
+}
+
+/// Hashcash proof of work asked of anonymous senders and registrants.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct PowSettings {
+    /// zero bits a stamp needs to publish a message, 0 to not ask for stamps
+    pub difficulty: u8,
+    /// zero bits a stamp needs to register an alias, 0 to not ask for stamps
+    pub register_difficulty: u8,
+    /// most an alias may ask of its senders
+    pub max_difficulty: u8,
+    /// seconds a challenge can be answered
+    pub challenge_ttl_secs: u64,
+    /// key authenticating challenges, shared by every instance.
+    /// A random one is made at startup without.
+    pub secret: Option<Secret<String>>,
+}
+impl Default for PowSettings {
+    fn default() -> Self {
+        Self {
+            difficulty: 16,
+            register_difficulty: 20,
+            max_difficulty: 28,
+            challenge_ttl_secs: 10 * 60,
+            secret: None,
+        }
+    }
+}
+
+/// Anonymous tokens senders may spend instead of proof of work.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct TokenSettings {
+    /// most tokens handed out at once
+    pub max_batch: usize,
+    /// zero bits of proof of work a batch of tokens costs
+    pub issue_difficulty: u8,
+    /// PKCS#8 PEM encoded RSA key signing tokens, shared by every instance.
+    /// A random one is generated at startup without. It must not be used for
+    /// anything else, see [`crate::tokens`].
+    pub issuer_key: Option<Secret<String>>,
+    /// size in bits of a generated key
+    pub generated_key_bits: usize,
+}
+impl Default for TokenSettings {
+    fn default() -> Self {
+        Self {
+            max_batch: 10,
+            issue_difficulty: 22,
+            issuer_key: None,
+            generated_key_bits: 2048,
+        }
+    }
+}
+
+/// Caching of key set lookups, in process and by clients.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct RegistrySettings {
+    /// key sets kept in memory, 0 to always query the database
+    pub cache_capacity: usize,
+    /// seconds a cached key set is served without asking the database,
+    /// bounding how stale it gets if a change notification is missed
+    pub cache_ttl_secs: u64,
+    /// seconds clients may use a key set without revalidating it. With 0,
+    /// they revalidate every time, which costs a 304 and no query.
+    pub max_age_secs: u64,
+}
+impl Default for RegistrySettings {
+    fn default() -> Self {
+        Self {
+            cache_capacity: 10_000,
+            cache_ttl_secs: 300,
+            max_age_secs: 0,
+        }
+    }
+}
+impl RegistrySettings {
+    pub fn cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.cache_ttl_secs)
+    }
+    /// `Cache-Control` of key sets. Keys may be revoked at any time, so
+    /// clients revalidate them unless told otherwise.
+    pub fn cache_control(&self) -> String {
+        match self.max_age_secs {
+            0 => "no-cache".into(),
+            secs => format!("public, max-age={secs}"),
+        }
+    }
+}
+
+/// Alias search. Thresholds are the lowest score, from 0 to 1, of
+/// results; requests may pass their own, down to the minimum. Minimums and
+/// the offset bound keep the registry from being listed page by page.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct SearchSettings {
+    /// of `similarity()` in trigram search
+    pub trigram_threshold: f64,
+    pub min_trigram_threshold: f64,
+    /// of 1 - distance / length in Levenshtein search
+    pub levenshtein_threshold: f64,
+    pub min_levenshtein_threshold: f64,
+    /// results per page unless asked otherwise
+    pub default_limit: u32,
+    pub max_limit: u32,
+    /// most results skipped, past it there are no more pages
+    pub max_offset: u32,
+}
+impl Default for SearchSettings {
+    fn default() -> Self {
+        Self {
+            trigram_threshold: 0.2,
+            min_trigram_threshold: 0.1,
+            levenshtein_threshold: 0.6,
+            min_levenshtein_threshold: 0.4,
+            default_limit: 10,
+            max_limit: 50,
+            max_offset: 200,
+        }
+    }
+}
+
+/// Prometheus metrics served at `/metrics`.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct MetricsSettings {
+    /// token scrapers must send as `Authorization: Bearer <token>`.
+    /// Without, the metrics are public locally and not served in production.
+    pub bearer_token: Option<Secret<String>>,
+    /// seconds between updates of gauges counted from the database
+    pub gauge_interval_secs: u64,
+}
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        Self {
+            bearer_token: None,
+            gauge_interval_secs: 60,
+        }
+    }
+}
+impl MetricsSettings {
+    pub fn gauge_interval(&self) -> Duration {
+        Duration::from_secs(self.gauge_interval_secs)
+    }
 }
 
 #[derive(Deserialize)]
@@ -0,0 +1,92 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+/// SHA-256 digest addressing an encrypted attachment.
+/// Represented as lowercase hex.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(try_from = "String", into = "String")]
+#[schema(value_type = String, pattern = "^[0-9a-f]{64}$")]
+pub struct BlobHash([u8; 32]);
+
+impl BlobHash {
+    pub fn of(data: &[u8]) -> Self {
+        Self(Sha256::digest(data).into())
+    }
+    pub fn from_digest(digest: Sha256) -> Self {
+        Self(digest.finalize().into())
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl TryFrom<&[u8]> for BlobHash {
+    type Error = String;
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        Ok(Self(value.try_into().map_err(|_| "blob hash must be 32 bytes")?))
+    }
+}
+
+impl std::fmt::Display for BlobHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for b in self.0 {
+            write!(f, "{b:02x}")?;
+        }
+        Ok(())
+    }
+}
+impl std::fmt::Debug for BlobHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl FromStr for BlobHash {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 64 {
+            return Err("blob hash must be 64 hex characters".into());
+        }
+        let mut bytes = [0u8; 32];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            let pair = s.get(i * 2..i * 2 + 2).ok_or("blob hash is not valid hex")?;
+            *b = u8::from_str_radix(pair, 16).map_err(|_| "blob hash is not valid hex")?;
+        }
+        Ok(Self(bytes))
+    }
+}
+impl TryFrom<String> for BlobHash {
+    type Error = String;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+impl From<BlobHash> for String {
+    fn from(value: BlobHash) -> Self {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_roundtrips_as_hex() {
+        let hash = BlobHash::of(b"abc");
+        assert_eq!(
+            hash.to_string(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(hash.to_string().parse::<BlobHash>().unwrap(), hash);
+    }
+    #[test]
+    fn invalid_hash_rejects() {
+        for h in ["", "abc", &"g".repeat(64), &"é".repeat(32)] {
+            assert!(h.parse::<BlobHash>().is_err(), "accepted invalid hash");
+        }
+    }
+}
@@ -3,10 +3,12 @@ use std::str::FromStr;
 use base64::engine::general_purpose::URL_SAFE_NO_PAD as b64;
 use base64::Engine;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-// Byte Vector with base64 (de)serialization
-#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Byte vector (de)serialized as unpadded base64url.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
 #[serde(transparent)]
+#[schema(value_type = String)]
 pub struct ByteVec(#[serde(with = "serde_base64")] Vec<u8>);
 
 impl std::fmt::Display for ByteVec {
@@ -0,0 +1,88 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::openapi::schema::{ObjectBuilder, Schema, Type};
+use utoipa::openapi::RefOr;
+use utoipa::{PartialSchema, ToSchema};
+use uuid::Uuid;
+
+use super::bytevec::ByteVec;
+
+/// Opaque keyset pagination cursor pointing at a row by `(timestamp, id)`.
+/// Encoded as base64 of the timestamp in microseconds followed by the uuid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cursor {
+    pub at: DateTime<Utc>,
+    pub id: Uuid,
+}
+
+impl Cursor {
+    pub fn new(at: DateTime<Utc>, id: Uuid) -> Self {
+        Self { at, id }
+    }
+}
+
+impl PartialSchema for Cursor {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .schema_type(Type::String)
+            .description(Some("Opaque pagination cursor"))
+            .into()
+    }
+}
+impl ToSchema for Cursor {}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut bytes = Vec::with_capacity(24);
+        bytes.extend_from_slice(&self.at.timestamp_micros().to_be_bytes());
+        bytes.extend_from_slice(self.id.as_bytes());
+        ByteVec::from(bytes).fmt(f)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes: ByteVec = s.parse().map_err(|_| "cursor is not valid base64")?;
+        if bytes.len() != 24 {
+            return Err("cursor has invalid length".into());
+        }
+        let micros = i64::from_be_bytes(bytes[..8].try_into().unwrap());
+        let at = DateTime::from_timestamp_micros(micros).ok_or("cursor timestamp out of range")?;
+        let id = Uuid::from_slice(&bytes[8..]).unwrap();
+        Ok(Self { at, id })
+    }
+}
+impl TryFrom<String> for Cursor {
+    type Error = String;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+impl From<Cursor> for String {
+    fn from(value: Cursor) -> Self {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_roundtrips() {
+        let at = DateTime::from_timestamp_micros(1_727_000_000_123_456).unwrap();
+        let cursor = Cursor::new(at, Uuid::new_v4());
+        let parsed: Cursor = cursor.to_string().parse().expect("failed parsing cursor");
+        assert_eq!(parsed, cursor);
+    }
+    #[test]
+    fn invalid_cursor_rejects() {
+        for c in ["", "not base64!", "AAAA"] {
+            assert!(c.parse::<Cursor>().is_err(), "accepted invalid cursor");
+        }
+    }
+}
@@ -1,10 +1,17 @@
+use rsa::{BigUint, Oaep, RsaPublicKey};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use std::borrow::Borrow;
+use utoipa::openapi::schema::{ObjectBuilder, Schema, Type};
+use utoipa::openapi::RefOr;
+use utoipa::{PartialSchema, ToSchema};
 
 use super::bytevec::ByteVec;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+/// Name of an alias: 3 to 100 ASCII letters, digits, `.`, `_` or `-`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, ToSchema)]
 #[serde(try_from = "String", into = "String")]
+#[schema(example = "alice")]
 pub struct KeyName(String);
 
 impl KeyName {
@@ -37,9 +44,9 @@ impl TryFrom<String> for KeyName {
         Self::parse(value)
     }
 }
-impl Into<String> for KeyName {
-    fn into(self) -> String {
-        self.0
+impl From<KeyName> for String {
+    fn from(value: KeyName) -> Self {
+        value.0
     }
 }
 impl Borrow<str> for KeyName {
@@ -53,6 +60,55 @@ impl std::fmt::Display for KeyName {
     }
 }
 
+/// Identifies one of the device keys registered under an alias:
+/// 1 to 64 ASCII letters, digits, `.`, `_` or `-`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord, ToSchema)]
+#[serde(try_from = "String", into = "String")]
+#[schema(example = "default")]
+pub struct Kid(String);
+
+impl Kid {
+    pub fn parse(s: String) -> Result<Self, String> {
+        if s.is_empty() {
+            return Err("kid must not be empty".into());
+        }
+        if s.len() > 64 {
+            return Err("kid is too long, maximum length is 64 characters".into());
+        }
+        for c in s.chars() {
+            if !is_valid_char(c) {
+                return Err(format!("kid contains invalid character `{c}`"));
+            }
+        }
+        Ok(Self(s))
+    }
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+/// Keys registered without a `kid` belong to the `default` device.
+impl Default for Kid {
+    fn default() -> Self {
+        Self("default".into())
+    }
+}
+impl TryFrom<String> for Kid {
+    type Error = String;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::parse(value)
+    }
+}
+impl From<Kid> for String {
+    fn from(value: Kid) -> Self {
+        value.0
+    }
+}
+impl std::fmt::Display for Kid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 fn is_valid_char(c: char) -> bool {
     match c {
         '_' | '-' | '.' => true,
@@ -66,9 +122,9 @@ fn is_valid_char(c: char) -> bool {
 // key type, and the algorithm used (RSA-OAEP-256) is not supported by
 // the crate.
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
 pub struct PublicJwk {
-    /// base64 string - must be "AQAB" or "AQAB=="
+    /// base64 string - must be "AQAB" or "AQABAA=="
     pub e: PublicExponent,
 
     /// base64 string containing p*q
@@ -85,21 +141,81 @@ pub struct PublicJwk {
     pub key_use: KeyUse,
 }
 
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+impl PublicJwk {
+    /// Encrypts `msg` with RSAES-OAEP (SHA-256), as clients do.
+    pub fn encrypt(&self, msg: &[u8]) -> Result<Vec<u8>, String> {
+        let key = RsaPublicKey::new(
+            BigUint::from_bytes_be(&self.n),
+            BigUint::from(PublicExponent::VALUE),
+        )
+        .map_err(|e| format!("invalid public key: {e}"))?;
+        key.encrypt(&mut rand::thread_rng(), Oaep::new::<Sha256>(), msg)
+            .map_err(|e| format!("encryption failed: {e}"))
+    }
+}
+
+/// A public key together with the id of the device holding its private half.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(from = "DeviceKeyFields")]
+pub struct DeviceKey {
+    /// key id - defaults to "default" when omitted
+    #[serde(default)]
+    pub kid: Kid,
+
+    #[serde(flatten)]
+    pub jwk: PublicJwk,
+}
+
+/// `DeviceKey` without `flatten`, which would hide which member
+/// of the JWK failed to deserialize.
+#[derive(Deserialize)]
+struct DeviceKeyFields {
+    #[serde(default)]
+    kid: Kid,
+    e: PublicExponent,
+    n: ByteVec,
+    alg: Algorithm,
+    kty: KeyType,
+    #[serde(default, rename = "use")]
+    key_use: KeyUse,
+}
+
+impl From<DeviceKeyFields> for DeviceKey {
+    fn from(f: DeviceKeyFields) -> Self {
+        Self {
+            kid: f.kid,
+            jwk: PublicJwk {
+                e: f.e,
+                n: f.n,
+                alg: f.alg,
+                kty: f.kty,
+                key_use: f.key_use,
+            },
+        }
+    }
+}
+
+/// JWK Set (RFC 7517 section 5) with every device key of an alias.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct JwkSet {
+    pub keys: Vec<DeviceKey>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
 pub enum KeyUse {
     #[serde(rename = "enc")]
     #[default]
     Enc
 }
 
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
 pub enum KeyType {
     #[serde(rename = "RSA")]
     #[default]
     Rsa
 }
 
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
 pub enum Algorithm {
     #[serde(rename = "RSA-OAEP-256")]
     #[default]
@@ -115,19 +231,34 @@ const PUBLIC_EXPONENT_B64_PADDED: &str = "AQABAA==";
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct PublicExponent;
 
+impl PublicExponent {
+    pub const VALUE: u32 = PUBLIC_EXPONENT;
+}
+
 impl Serialize for PublicExponent {
     fn serialize<S: serde::ser::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
         PUBLIC_EXPONENT_B64.serialize(s)
     }
 }
 
+impl PartialSchema for PublicExponent {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .schema_type(Type::String)
+            .enum_values(Some([PUBLIC_EXPONENT_B64, PUBLIC_EXPONENT_B64_PADDED]))
+            .description(Some("RSA public exponent 65537, in base64url"))
+            .into()
+    }
+}
+impl ToSchema for PublicExponent {}
+
 impl<'de> Deserialize<'de> for PublicExponent {
     fn deserialize<D: serde::de::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
         let e = String::deserialize(d)?;
         if e == PUBLIC_EXPONENT_B64 || e == PUBLIC_EXPONENT_B64_PADDED {
             Ok(Self)
         } else {
-            Err(serde::de::Error::custom(&format!(
+            Err(serde::de::Error::custom(format!(
                 "public exponent must be {}",
                 PUBLIC_EXPONENT
             )))
@@ -158,14 +289,7 @@ mod tests {
     }
 
     fn valid_public_key() -> PublicJwk {
-        let n = include_str!("n").parse().unwrap();
-        PublicJwk {
-            e: PublicExponent,
-            alg: Algorithm::RsaOaep256,
-            kty: KeyType::Rsa,
-            key_use: KeyUse::Enc,
-            n
-        }
+        crate::testing::public_key()
     }
     #[test]
     fn jwk_invalid_algo_fails() {
@@ -254,4 +378,38 @@ mod tests {
         assert_eq!(obj["alg"], Value::String("RSA-OAEP-256".into()));
         assert_eq!(obj["use"], Value::String("enc".into()));
     }
+
+    #[test]
+    fn kid_validation() {
+        for k in ["laptop", "phone-2", "a"] {
+            assert!(Kid::parse(k.into()).is_ok(), "failed to parse valid kid");
+        }
+        for k in ["", "my phone", "kid/1", &"x".repeat(65)] {
+            assert!(Kid::parse(k.into()).is_err(), "accepted invalid kid");
+        }
+    }
+    #[test]
+    fn device_key_defaults_kid() {
+        let n = valid_public_key().n;
+        let key_s = format!(r#"{{
+            "kty": "RSA",
+            "n": "{n}",
+            "e": "AQAB",
+            "alg": "RSA-OAEP-256"
+        }}"#);
+        let key = serde_json::from_str::<DeviceKey>(&key_s).expect("failed parsing device key");
+        assert_eq!(key.kid, Kid::default());
+        assert_eq!(key.jwk.n, n);
+    }
+    #[test]
+    fn device_key_serializes_flat() {
+        let key = DeviceKey {
+            kid: Kid::parse("phone".into()).unwrap(),
+            jwk: valid_public_key(),
+        };
+        let json = serde_json::to_value(JwkSet { keys: vec![key] }).unwrap();
+        let obj = json["keys"][0].as_object().expect("jwk set entry is not an object");
+        assert_eq!(obj["kid"], "phone");
+        assert_eq!(obj["kty"], "RSA");
+    }
 }
@@ -1,2 +1,6 @@
+pub mod blob;
 pub mod bytevec;
+pub mod cursor;
 pub mod key;
+pub mod signing;
+pub mod token;
@@ -0,0 +1,131 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use rsa::pss::{Signature, VerifyingKey};
+use rsa::signature::Verifier;
+use rsa::{BigUint, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use utoipa::ToSchema;
+
+use super::bytevec::ByteVec;
+use super::key::{KeyName, KeyType, PublicExponent};
+
+const MIN_MODULUS_BITS: usize = 2048;
+
+/// RSA public key used to verify channel posts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct SigningJwk {
+    /// base64 string - must be "AQAB" or "AQABAA=="
+    pub e: PublicExponent,
+
+    /// base64 string containing p*q
+    pub n: ByteVec,
+
+    /// algorithm used - must be "PS256"
+    pub alg: SigningAlgorithm,
+
+    /// key type - must be "RSA"
+    pub kty: KeyType,
+
+    /// key use - must be "sig"
+    #[serde(default, rename = "use")]
+    pub key_use: SigningKeyUse,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum SigningAlgorithm {
+    #[serde(rename = "PS256")]
+    #[default]
+    Ps256,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum SigningKeyUse {
+    #[serde(rename = "sig")]
+    #[default]
+    Sig,
+}
+
+impl SigningJwk {
+    /// Rejects keys that are malformed or too short to be trusted.
+    pub fn validate(&self) -> Result<(), String> {
+        let key = self.public_key()?;
+        if rsa::traits::PublicKeyParts::n(&key).bits() < MIN_MODULUS_BITS {
+            return Err(format!("signing key must have at least {MIN_MODULUS_BITS} bits"));
+        }
+        Ok(())
+    }
+
+    /// Verifies a RSASSA-PSS (SHA-256) signature over `msg`.
+    pub fn verify(&self, msg: &[u8], signature: &[u8]) -> bool {
+        let Ok(key) = self.public_key() else {
+            return false;
+        };
+        let Ok(signature) = Signature::try_from(signature) else {
+            return false;
+        };
+        VerifyingKey::<Sha256>::new(key).verify(msg, &signature).is_ok()
+    }
+
+    fn public_key(&self) -> Result<RsaPublicKey, String> {
+        RsaPublicKey::new(
+            BigUint::from_bytes_be(&self.n),
+            BigUint::from(PublicExponent::VALUE),
+        )
+        .map_err(|e| format!("invalid signing key: {e}"))
+    }
+}
+
+/// Bytes a channel owner signs to publish a post.
+/// Binding the channel and timestamp keeps a signature from being
+/// replayed on another channel or long after it was made.
+pub fn post_signing_input(channel: &KeyName, signed_at: DateTime<Utc>, content: &str) -> Vec<u8> {
+    format!(
+        "blindchannel-post\n{channel}\n{}\n{content}",
+        signed_at.to_rfc3339_opts(SecondsFormat::Millis, true)
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::pss::BlindedSigningKey;
+    use rsa::signature::{RandomizedSigner, SignatureEncoding};
+    use rsa::traits::PublicKeyParts;
+    use rsa::RsaPrivateKey;
+
+    fn keypair() -> (BlindedSigningKey<Sha256>, SigningJwk) {
+        let private = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+        let jwk = SigningJwk {
+            e: PublicExponent,
+            n: private.n().to_bytes_be().into(),
+            alg: SigningAlgorithm::Ps256,
+            kty: KeyType::Rsa,
+            key_use: SigningKeyUse::Sig,
+        };
+        (BlindedSigningKey::new(private), jwk)
+    }
+
+    #[test]
+    fn valid_signature_verifies() {
+        let (signer, jwk) = keypair();
+        jwk.validate().expect("generated key is invalid");
+        let channel = KeyName::parse("news".into()).unwrap();
+        let msg = post_signing_input(&channel, Utc::now(), "hello");
+        let sig = signer.sign_with_rng(&mut rand::thread_rng(), &msg).to_vec();
+        assert!(jwk.verify(&msg, &sig));
+
+        let other = KeyName::parse("other".into()).unwrap();
+        let replayed = post_signing_input(&other, Utc::now(), "hello");
+        assert!(!jwk.verify(&replayed, &sig), "signature verified for another channel");
+    }
+    #[test]
+    fn signing_jwk_rejects_enc_use() {
+        let (_, jwk) = keypair();
+        let json = serde_json::to_value(&jwk).unwrap();
+        assert_eq!(json["use"], "sig");
+        let mut enc = json.clone();
+        enc["use"] = "enc".into();
+        assert!(serde_json::from_value::<SigningJwk>(enc).is_err());
+    }
+}
@@ -0,0 +1,63 @@
+use std::str::FromStr;
+
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use super::bytevec::ByteVec;
+
+const TOKEN_LEN: usize = 32;
+
+/// Random bearer secret handed to a client exactly once.
+/// Only its SHA-256 digest is ever stored.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SecretToken(ByteVec);
+
+impl SecretToken {
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; TOKEN_LEN];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        Self(bytes.into())
+    }
+    pub fn digest(&self) -> Vec<u8> {
+        Sha256::digest(&self.0).to_vec()
+    }
+}
+
+impl FromStr for SecretToken {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes: ByteVec = s.parse().map_err(|_| "token is not valid base64")?;
+        if bytes.len() != TOKEN_LEN {
+            return Err("token has invalid length".into());
+        }
+        Ok(Self(bytes))
+    }
+}
+
+// The token is only displayed when handing it out, never logged.
+impl std::fmt::Display for SecretToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+impl std::fmt::Debug for SecretToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SecretToken(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_roundtrips() {
+        let token = SecretToken::generate();
+        let parsed: SecretToken = token.to_string().parse().expect("failed parsing token");
+        assert_eq!(parsed.digest(), token.digest());
+    }
+    #[test]
+    fn short_token_rejects() {
+        assert!("AAAA".parse::<SecretToken>().is_err());
+    }
+}
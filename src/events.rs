@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use crate::domain::key::KeyName;
+
+const CAPACITY: usize = 1024;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Upper bound on waiting for the listener, in case it is reconnecting.
+const WATCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Postgres `NOTIFY` channel of a recipient's mailbox.
+/// Aliases can be longer than a channel name, so it's derived from a hash.
+pub fn channel_name(recipient: &KeyName) -> String {
+    let digest = Sha256::digest(recipient.name());
+    let hex: String = digest[..16].iter().map(|b| format!("{b:02x}")).collect();
+    format!("mailbox_{hex}")
+}
+
+enum Command {
+    Watch(KeyName, oneshot::Sender<()>),
+    Unwatch(KeyName),
+}
+
+/// Wakes requests waiting on a mailbox when a message is published to it.
+///
+/// Publishing notifies the recipient's Postgres channel, and a single
+/// [`EventListener`] task per instance listens to the channels of every
+/// mailbox being waited on, so this works across instances.
+/// Events only carry the recipient: waiters read the new messages from
+/// the database after their cursor, so a lagging subscriber can simply
+/// re-query instead of losing messages.
+#[derive(Clone)]
+pub struct MailboxEvents {
+    tx: broadcast::Sender<KeyName>,
+    commands: mpsc::UnboundedSender<Command>,
+    pool: PgPool,
+    /// channel used to wake the listener so it processes commands
+    control: Arc<str>,
+}
+
+impl MailboxEvents {
+    pub fn new(pool: PgPool) -> (Self, EventListener) {
+        let (tx, _) = broadcast::channel(CAPACITY);
+        let (commands, command_rx) = mpsc::unbounded_channel();
+        let control: Arc<str> = format!("mailbox_control_{}", uuid::Uuid::new_v4().simple()).into();
+        let listener = EventListener {
+            tx: tx.clone(),
+            commands: command_rx,
+            pool: pool.clone(),
+            control: control.clone(),
+            watched: HashMap::new(),
+        };
+        let events = Self {
+            tx,
+            commands,
+            pool,
+            control,
+        };
+        (events, listener)
+    }
+
+    /// Starts waiting on `recipient`'s mailbox. Returns once the listener
+    /// is subscribed to its channel, so anything committed afterwards wakes
+    /// the subscription.
+    pub async fn watch(&self, recipient: KeyName) -> Subscription {
+        let rx = self.tx.subscribe();
+        let (ack, acked) = oneshot::channel();
+        let _ = self.commands.send(Command::Watch(recipient.clone(), ack));
+        let woken = sqlx::query("SELECT pg_notify($1, '')")
+            .bind(&*self.control)
+            .execute(&self.pool)
+            .await;
+        match woken {
+            Ok(_) => {
+                let _ = tokio::time::timeout(WATCH_TIMEOUT, acked).await;
+            }
+            Err(e) => tracing::error!("error waking mailbox listener: {e}"),
+        }
+        Subscription {
+            rx,
+            recipient,
+            commands: self.commands.clone(),
+        }
+    }
+}
+
+/// Interest in one mailbox, released on drop.
+pub struct Subscription {
+    rx: broadcast::Receiver<KeyName>,
+    recipient: KeyName,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl Subscription {
+    /// Waits until the mailbox may have new messages.
+    pub async fn changed(&mut self) {
+        loop {
+            match self.rx.recv().await {
+                Ok(recipient) if recipient != self.recipient => {}
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => return,
+                // the sender lives as long as the listener, which never stops
+                Err(broadcast::error::RecvError::Closed) => std::future::pending::<()>().await,
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // processed the next time the listener wakes up
+        let _ = self.commands.send(Command::Unwatch(self.recipient.clone()));
+    }
+}
+
+/// Task forwarding mailbox notifications to [`Subscription`]s.
+pub struct EventListener {
+    tx: broadcast::Sender<KeyName>,
+    commands: mpsc::UnboundedReceiver<Command>,
+    pool: PgPool,
+    control: Arc<str>,
+    watched: HashMap<KeyName, usize>,
+}
+
+impl EventListener {
+    pub async fn run(mut self) {
+        let mut listener = loop {
+            match self.connect().await {
+                Ok(listener) => break listener,
+                Err(e) => {
+                    tracing::error!("error connecting mailbox listener: {e}");
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        };
+        // commands may have been sent before the control channel was listened to
+        self.apply_commands(&mut listener).await;
+        loop {
+            // `try_recv` isn't cancel safe, so commands are picked up
+            // when the control channel is notified instead of selecting
+            match listener.try_recv().await {
+                Ok(Some(n)) if n.channel() == &*self.control => {
+                    self.apply_commands(&mut listener).await;
+                }
+                Ok(Some(n)) => {
+                    if let Ok(recipient) = KeyName::parse(n.payload().into()) {
+                        let _ = self.tx.send(recipient);
+                    }
+                }
+                // notifications, including control ones, may have been
+                // missed while the connection was down
+                Ok(None) => {
+                    self.apply_commands(&mut listener).await;
+                    self.wake_all();
+                }
+                Err(sqlx::Error::PoolClosed) => return,
+                Err(e) => {
+                    tracing::error!("mailbox listener error: {e}");
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    self.apply_commands(&mut listener).await;
+                    self.wake_all();
+                }
+            }
+        }
+    }
+
+    async fn connect(&self) -> sqlx::Result<PgListener> {
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(&self.control).await?;
+        Ok(listener)
+    }
+
+    async fn apply_commands(&mut self, listener: &mut PgListener) {
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                Command::Watch(recipient, ack) => {
+                    let count = self.watched.entry(recipient.clone()).or_default();
+                    *count += 1;
+                    if *count == 1 {
+                        if let Err(e) = listener.listen(&channel_name(&recipient)).await {
+                            tracing::error!("error listening to mailbox: {e}");
+                        }
+                    }
+                    let _ = ack.send(());
+                }
+                Command::Unwatch(recipient) => {
+                    let Some(count) = self.watched.get_mut(&recipient) else {
+                        continue;
+                    };
+                    *count -= 1;
+                    if *count == 0 {
+                        self.watched.remove(&recipient);
+                        if let Err(e) = listener.unlisten(&channel_name(&recipient)).await {
+                            tracing::error!("error unlistening from mailbox: {e}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn wake_all(&self) {
+        for recipient in self.watched.keys() {
+            let _ = self.tx.send(recipient.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_name_fits_identifier_limit() {
+        let long = KeyName::parse("a".repeat(100)).unwrap();
+        let channel = channel_name(&long);
+        assert!(channel.len() <= 63, "channel name exceeds postgres identifier limit");
+        assert_eq!(channel, channel_name(&long));
+        assert_ne!(channel, channel_name(&KeyName::parse("other".into()).unwrap()));
+    }
+}
@@ -0,0 +1,150 @@
+//! Middleware bounding what requests may take from the server: a global
+//! concurrency limit shedding excess load, request timeouts, and a panic
+//! catcher. Each answers with a problem document, like the handlers.
+
+use std::any::Any;
+use std::time::Duration;
+
+use axum::error_handling::HandleErrorLayer;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{BoxError, Router};
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower::load_shed::error::Overloaded;
+use tower::load_shed::LoadShedLayer;
+use tower::timeout::error::Elapsed;
+use tower::timeout::TimeoutLayer;
+use tower::ServiceBuilder;
+use tower_http::catch_panic::CatchPanicLayer;
+
+use crate::configuration::LimitSettings;
+use crate::routes::api::error::ApiError;
+
+/// Answers requests beyond `max_concurrent_requests` with 503 right away
+/// instead of queueing them, and panicking handlers with 500.
+pub fn protect(router: Router, limits: &LimitSettings) -> Router {
+    let retry_after = limits.retry_after_secs;
+    router
+        .layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(move |e: BoxError| async move { overloaded(e, retry_after) }))
+                .layer(LoadShedLayer::new())
+                // shared by every route, unlike `ConcurrencyLimitLayer`
+                .layer(GlobalConcurrencyLimitLayer::new(limits.max_concurrent_requests)),
+        )
+        .layer(CatchPanicLayer::custom(panicked))
+}
+
+/// Answers requests still running after `timeout` with 408.
+pub fn with_timeout<S>(router: Router<S>, timeout: Duration) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    router.layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(timed_out))
+            .layer(TimeoutLayer::new(timeout)),
+    )
+}
+
+fn overloaded(e: BoxError, retry_after: u64) -> Response {
+    if !e.is::<Overloaded>() {
+        tracing::error!("unhandled middleware error: {e}");
+        return ApiError::internal().into_response();
+    }
+    let error = ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "overloaded")
+        .with_detail("the server is handling too many requests");
+    ([(header::RETRY_AFTER, retry_after.to_string())], error).into_response()
+}
+
+async fn timed_out(e: BoxError) -> ApiError {
+    if e.is::<Elapsed>() {
+        ApiError::new(StatusCode::REQUEST_TIMEOUT, "request_timeout")
+    } else {
+        tracing::error!("unhandled middleware error: {e}");
+        ApiError::internal()
+    }
+}
+
+fn panicked(panic: Box<dyn Any + Send + 'static>) -> Response {
+    let message = panic
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| panic.downcast_ref::<&str>().copied())
+        .unwrap_or("unknown panic");
+    tracing::error!("handler panicked: {message}");
+    ApiError::internal().into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use axum::routing::get;
+    use tokio::sync::Notify;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    fn limits(max_concurrent_requests: usize) -> LimitSettings {
+        LimitSettings {
+            max_concurrent_requests,
+            retry_after_secs: 7,
+            ..Default::default()
+        }
+    }
+
+    fn get_request(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    async fn problem_code(response: Response) -> String {
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        problem["code"].as_str().unwrap().to_owned()
+    }
+
+    async fn boom() {
+        panic!("boom")
+    }
+
+    #[tokio::test]
+    async fn panics_become_problems() {
+        let app = protect(Router::new().route("/", get(boom)), &limits(8));
+        let response = app.oneshot(get_request("/")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(problem_code(response).await, "internal_error");
+    }
+
+    #[tokio::test]
+    async fn sheds_load_beyond_limit() {
+        let release = Arc::new(Notify::new());
+        let held = release.clone();
+        let router = Router::new().route(
+            "/slow",
+            get(move || async move { held.notified().await }),
+        );
+        let app = protect(router, &limits(1));
+        let first = tokio::spawn(app.clone().oneshot(get_request("/slow")));
+        tokio::task::yield_now().await;
+        let response = app.clone().oneshot(get_request("/slow")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "7");
+        assert_eq!(problem_code(response).await, "overloaded");
+
+        release.notify_one();
+        assert_eq!(first.await.unwrap().unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn times_out_slow_requests() {
+        let router = Router::new().route(
+            "/",
+            get(|| async { tokio::time::sleep(Duration::from_secs(60)).await }),
+        );
+        let app = with_timeout(router, Duration::from_millis(10));
+        let response = app.oneshot(get_request("/")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(problem_code(response).await, "request_timeout");
+    }
+}
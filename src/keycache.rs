@@ -0,0 +1,249 @@
+//! In-process LRU cache of the key sets of aliases, so looking up hot
+//! aliases doesn't cost a query each time.
+//!
+//! Entries hold the serialized key set and its ETag. Adding or removing a
+//! device key announces the alias on the `registry_keys` channel, and a
+//! [`listen`] task per instance drops it from the cache. Entries also
+//! expire, in case a notification is missed.
+
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::body::Bytes;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as b64;
+use base64::Engine;
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use tokio_util::sync::CancellationToken;
+
+use crate::configuration::RegistrySettings;
+use crate::domain::key::JwkSet;
+
+const CHANNEL: &str = "registry_keys";
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// A serialized key set, as served.
+#[derive(Debug, Clone)]
+pub struct KeySet {
+    pub body: Bytes,
+    /// strong validator, quoted as in the `ETag` header
+    pub etag: String,
+    /// unpadded base64url SHA-256 of `body`, for users to compare out of
+    /// band
+    pub fingerprint: String,
+}
+
+impl KeySet {
+    /// The ETag combines the version of the key set with a digest of it,
+    /// so it changes with any key even if versions were reset.
+    pub fn new(version: i64, keys: &JwkSet) -> Self {
+        let body = serde_json::to_vec(keys).expect("key sets serialize");
+        let digest = Sha256::digest(&body);
+        Self {
+            etag: format!("\"{version}-{}\"", b64.encode(&digest[..16])),
+            fingerprint: b64.encode(digest),
+            body: body.into(),
+        }
+    }
+
+    /// Whether an `If-None-Match` header value names this key set.
+    /// Weak validators match too, as they do for GET.
+    pub fn matches(&self, if_none_match: &str) -> bool {
+        if_none_match
+            .split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == self.etag)
+    }
+}
+
+struct Entry {
+    keys: KeySet,
+    expires_at: Instant,
+}
+
+struct Inner {
+    entries: LruCache<String, Entry>,
+    /// bumped by every invalidation, so lookups racing one don't cache
+    /// what they read before it
+    generation: u64,
+}
+
+#[derive(Clone)]
+pub struct KeyCache {
+    inner: Option<Arc<Mutex<Inner>>>,
+    ttl: Duration,
+    cache_control: Arc<str>,
+}
+
+impl KeyCache {
+    pub fn new(settings: &RegistrySettings) -> Self {
+        Self {
+            inner: NonZeroUsize::new(settings.cache_capacity).map(|capacity| {
+                Arc::new(Mutex::new(Inner {
+                    entries: LruCache::new(capacity),
+                    generation: 0,
+                }))
+            }),
+            ttl: settings.cache_ttl(),
+            cache_control: settings.cache_control().into(),
+        }
+    }
+
+    /// `Cache-Control` header value of key sets.
+    pub fn cache_control(&self) -> &str {
+        &self.cache_control
+    }
+
+    pub fn get(&self, alias: &str) -> Option<KeySet> {
+        let mut inner = self.inner.as_ref()?.lock().unwrap();
+        let found = match inner.entries.get(alias) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.keys.clone()),
+            Some(_) => {
+                inner.entries.pop(alias);
+                None
+            }
+            None => None,
+        };
+        let result = if found.is_some() { "hit" } else { "miss" };
+        metrics::counter!("blindchannel_key_cache_lookups_total", "result" => result).increment(1);
+        found
+    }
+
+    /// Current generation, to pass to [`KeyCache::insert`] with what is
+    /// read from the database afterwards.
+    pub fn generation(&self) -> u64 {
+        self.inner.as_ref().map_or(0, |inner| inner.lock().unwrap().generation)
+    }
+
+    /// Caches `keys`, unless something was invalidated since `generation`.
+    pub fn insert(&self, generation: u64, alias: &str, keys: KeySet) {
+        let Some(inner) = &self.inner else {
+            return;
+        };
+        let mut inner = inner.lock().unwrap();
+        if inner.generation == generation {
+            let expires_at = Instant::now() + self.ttl;
+            inner.entries.put(alias.to_owned(), Entry { keys, expires_at });
+        }
+    }
+
+    pub fn invalidate(&self, alias: &str) {
+        if let Some(inner) = &self.inner {
+            let mut inner = inner.lock().unwrap();
+            inner.generation += 1;
+            inner.entries.pop(alias);
+        }
+    }
+
+    pub fn clear(&self) {
+        if let Some(inner) = &self.inner {
+            let mut inner = inner.lock().unwrap();
+            inner.generation += 1;
+            inner.entries.clear();
+        }
+    }
+}
+
+/// Drops aliases from `cache` as their keys change on any instance, until
+/// `shutdown` is cancelled.
+pub async fn listen(cache: KeyCache, pool: PgPool, shutdown: CancellationToken) {
+    if cache.inner.is_none() {
+        return;
+    }
+    loop {
+        let mut listener = match connect(&pool).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                tracing::error!("error connecting key cache listener: {e}");
+                tokio::select! {
+                    _ = tokio::time::sleep(RECONNECT_DELAY) => continue,
+                    _ = shutdown.cancelled() => return,
+                }
+            }
+        };
+        // changes may have been missed while not listening
+        cache.clear();
+        loop {
+            let notification = tokio::select! {
+                n = listener.try_recv() => n,
+                _ = shutdown.cancelled() => return,
+            };
+            match notification {
+                Ok(Some(n)) => cache.invalidate(n.payload()),
+                Ok(None) => cache.clear(),
+                Err(sqlx::Error::PoolClosed) => return,
+                Err(e) => {
+                    tracing::error!("key cache listener error: {e}");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+async fn connect(pool: &PgPool) -> sqlx::Result<PgListener> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(CHANNEL).await?;
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(capacity: usize) -> KeyCache {
+        KeyCache::new(&RegistrySettings {
+            cache_capacity: capacity,
+            ..Default::default()
+        })
+    }
+
+    fn keys(version: i64) -> KeySet {
+        KeySet::new(version, &JwkSet { keys: Vec::new() })
+    }
+
+    #[test]
+    fn etags_follow_versions_and_match_if_none_match() {
+        let keys = keys(3);
+        assert!(keys.etag.starts_with("\"3-"));
+        assert_ne!(keys.etag, self::keys(4).etag);
+        assert!(keys.matches(&keys.etag));
+        assert!(keys.matches(&format!("\"other\", W/{}", keys.etag)));
+        assert!(keys.matches("*"));
+        assert!(!keys.matches("\"other\""));
+    }
+
+    #[test]
+    fn invalidation_wins_over_racing_lookups() {
+        let cache = cache(2);
+        let generation = cache.generation();
+        cache.insert(generation, "alice", keys(1));
+        assert!(cache.get("alice").is_some());
+
+        // read before alice's keys changed, so not cached
+        let stale = cache.generation();
+        cache.invalidate("alice");
+        cache.insert(stale, "alice", keys(1));
+        assert!(cache.get("alice").is_none());
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = cache(2);
+        let generation = cache.generation();
+        for alias in ["alice", "bob"] {
+            cache.insert(generation, alias, keys(1));
+        }
+        cache.get("alice");
+        cache.insert(generation, "carol", keys(1));
+        assert!(cache.get("bob").is_none());
+        assert!(cache.get("alice").is_some());
+
+        let disabled = self::cache(0);
+        disabled.insert(disabled.generation(), "alice", keys(1));
+        assert!(disabled.get("alice").is_none());
+    }
+}
@@ -1,5 +1,20 @@
+pub mod blobs;
 pub mod configuration;
 pub mod domain;
+pub mod events;
+pub mod hardening;
+pub mod keycache;
+pub mod pow;
+pub mod push;
+pub mod ratelimit;
+pub mod reaper;
 pub mod routes;
+pub mod scheduler;
+pub mod shutdown;
 pub mod startup;
 pub mod telemetry;
+#[cfg(test)]
+mod testing;
+pub mod tls;
+pub mod tokens;
+pub mod webhooks;
@@ -2,7 +2,7 @@ use blindchannel::startup::run;
 use std::process::ExitCode;
 #[tokio::main]
 async fn main() -> ExitCode {
-    let _guard = blindchannel::telemetry::init_subscriber();
+    let guard = blindchannel::telemetry::init_subscriber();
     let settings = match blindchannel::configuration::get_configuration() {
         Ok(s) => s,
         Err(e) => {
@@ -11,7 +11,8 @@ async fn main() -> ExitCode {
         }
     };
     let pool = sqlx::PgPool::connect_lazy_with(settings.database.connect_options());
-    let addr = (settings.application.host, settings.application.port);
-    run(addr, pool).await;
+    run(pool, settings).await;
+    // flush what the log writer still buffers
+    drop(guard);
     ExitCode::SUCCESS
 }
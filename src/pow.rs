@@ -0,0 +1,289 @@
+//! Hashcash style proof of work, making anonymous senders spend CPU time on
+//! every message, registration and batch of tokens.
+//!
+//! A stamp is `1:<bits>:<nonce>:<resource>:<contentHash>:<counter>`, where
+//! - `resource` is the alias published to or registered, or `tokens`,
+//! - `nonce` was handed out by the server for `resource` and expires soon after,
+//! - `contentHash` is the base64url encoded SHA-256 of the request body,
+//! - `counter` is anything the client likes, changed until the SHA-256 of
+//!   the whole stamp starts with `bits` zero bits.
+//!
+//! Nonces are authenticated rather than stored, so handing them out costs
+//! nothing. Each one can be spent once, which callers track.
+
+use std::sync::Arc;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as b64;
+use base64::Engine;
+use chrono::{DateTime, TimeDelta, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use secrecy::ExposeSecret;
+use sha2::{Digest, Sha256};
+
+use crate::configuration::PowSettings;
+
+const VERSION: &str = "1";
+const RANDOM_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+const NONCE_LEN: usize = 8 + RANDOM_LEN + TAG_LEN;
+const MAX_COUNTER_LEN: usize = 64;
+
+/// Hands out nonces and checks stamps made with them.
+#[derive(Clone)]
+pub struct Hashcash {
+    key: Arc<[u8; 32]>,
+    settings: PowSettings,
+}
+
+/// A nonce to make a stamp with.
+#[derive(Debug, Clone)]
+pub struct Challenge {
+    pub nonce: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// The nonce of a valid stamp, to be marked spent until it expires.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spent {
+    pub nonce: Vec<u8>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StampError {
+    Malformed,
+    WrongResource,
+    WrongContent,
+    TooEasy { required: u8 },
+    InvalidNonce,
+    Expired,
+    InsufficientWork,
+}
+impl std::fmt::Display for StampError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "expected `1:<bits>:<nonce>:<resource>:<contentHash>:<counter>`"),
+            Self::WrongResource => write!(f, "the stamp was made for another alias or purpose"),
+            Self::WrongContent => write!(f, "the content hash is not the SHA-256 of the request body"),
+            Self::TooEasy { required } => write!(f, "at least {required} bits are required"),
+            Self::InvalidNonce => write!(f, "the nonce was not issued for this alias or purpose"),
+            Self::Expired => write!(f, "the nonce expired, request a new challenge"),
+            Self::InsufficientWork => write!(f, "the stamp hash does not start with the claimed zero bits"),
+        }
+    }
+}
+
+impl Hashcash {
+    pub fn new(settings: &PowSettings) -> Self {
+        let key = match &settings.secret {
+            Some(secret) => Sha256::digest(secret.expose_secret().as_bytes()).into(),
+            None => {
+                tracing::warn!("no proof of work secret configured, challenges only verify on this instance");
+                let mut key = [0; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                key
+            }
+        };
+        Self {
+            key: Arc::new(key),
+            settings: settings.clone(),
+        }
+    }
+
+    /// Zero bits required to register an alias.
+    pub fn register_difficulty(&self) -> u8 {
+        self.settings.register_difficulty
+    }
+    /// Zero bits required to publish to an alias asking for `own` of its
+    /// senders. Aliases may raise the difficulty, up to the maximum, but
+    /// not lower it.
+    pub fn publish_difficulty(&self, own: Option<u8>) -> u8 {
+        let own = own.unwrap_or(0).min(self.settings.max_difficulty);
+        self.settings.difficulty.max(own)
+    }
+    pub fn max_difficulty(&self) -> u8 {
+        self.settings.max_difficulty
+    }
+
+    pub fn challenge(&self, resource: &str) -> Challenge {
+        let expires_at = Utc::now() + TimeDelta::seconds(self.settings.challenge_ttl_secs as i64);
+        let mut nonce = Vec::with_capacity(NONCE_LEN);
+        nonce.extend_from_slice(&expires_at.timestamp().to_be_bytes());
+        let mut random = [0; RANDOM_LEN];
+        rand::thread_rng().fill_bytes(&mut random);
+        nonce.extend_from_slice(&random);
+        let tag = self.mac(resource, &nonce).finalize().into_bytes();
+        nonce.extend_from_slice(&tag[..TAG_LEN]);
+        Challenge {
+            nonce: b64.encode(nonce),
+            expires_at: DateTime::from_timestamp(expires_at.timestamp(), 0).expect("timestamps round trip"),
+        }
+    }
+
+    fn mac(&self, resource: &str, expiry_and_random: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key[..]).expect("HMAC takes any key size");
+        mac.update(&(resource.len() as u64).to_be_bytes());
+        mac.update(resource.as_bytes());
+        mac.update(expiry_and_random);
+        mac
+    }
+
+    /// Checks that `stamp` was made for `resource` and `body` with a nonce
+    /// of ours, and is worth at least `required` bits.
+    pub fn verify(&self, stamp: &str, resource: &str, body: &[u8], required: u8) -> Result<Spent, StampError> {
+        self.verify_at(stamp, resource, body, required, Utc::now())
+    }
+    fn verify_at(
+        &self,
+        stamp: &str,
+        expected: &str,
+        body: &[u8],
+        required: u8,
+        now: DateTime<Utc>,
+    ) -> Result<Spent, StampError> {
+        let [version, bits, nonce, resource, content, counter] = stamp
+            .split(':')
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| StampError::Malformed)?;
+        let bits: u8 = bits.parse().map_err(|_| StampError::Malformed)?;
+        if version != VERSION || counter.is_empty() || counter.len() > MAX_COUNTER_LEN {
+            return Err(StampError::Malformed);
+        }
+        if resource != expected {
+            return Err(StampError::WrongResource);
+        }
+        if content != content_hash(body) {
+            return Err(StampError::WrongContent);
+        }
+        if bits < required {
+            return Err(StampError::TooEasy { required });
+        }
+
+        let nonce = b64.decode(nonce).map_err(|_| StampError::InvalidNonce)?;
+        if nonce.len() != NONCE_LEN {
+            return Err(StampError::InvalidNonce);
+        }
+        let (signed, tag) = nonce.split_at(8 + RANDOM_LEN);
+        self.mac(resource, signed)
+            .verify_truncated_left(tag)
+            .map_err(|_| StampError::InvalidNonce)?;
+        let expires = i64::from_be_bytes(signed[..8].try_into().expect("8 bytes"));
+        let expires_at = DateTime::from_timestamp(expires, 0).ok_or(StampError::InvalidNonce)?;
+        if expires_at <= now {
+            return Err(StampError::Expired);
+        }
+
+        if leading_zero_bits(&Sha256::digest(stamp.as_bytes())) < u32::from(bits) {
+            return Err(StampError::InsufficientWork);
+        }
+        Ok(Spent { nonce, expires_at })
+    }
+}
+
+/// Base64url encoded SHA-256 of a request body, as stamps carry it.
+pub fn content_hash(body: &[u8]) -> String {
+    b64.encode(Sha256::digest(body))
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for &byte in hash {
+        bits += byte.leading_zeros();
+        if byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashcash() -> Hashcash {
+        Hashcash::new(&PowSettings {
+            secret: Some("test secret".to_owned().into()),
+            ..Default::default()
+        })
+    }
+
+    fn solve(bits: u8, nonce: &str, alias: &str, body: &[u8]) -> String {
+        let prefix = format!("1:{bits}:{nonce}:{alias}:{}:", content_hash(body));
+        (0u64..)
+            .map(|counter| format!("{prefix}{counter:x}"))
+            .find(|stamp| leading_zero_bits(&Sha256::digest(stamp.as_bytes())) >= u32::from(bits))
+            .unwrap()
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0, 0, 0x1f, 0xff]), 19);
+        assert_eq!(leading_zero_bits(&[0x80]), 0);
+        assert_eq!(leading_zero_bits(&[0, 0]), 16);
+    }
+
+    #[test]
+    fn accepts_solved_stamps() {
+        let hashcash = hashcash();
+        let challenge = hashcash.challenge("alice");
+        let stamp = solve(8, &challenge.nonce, "alice", b"{}");
+        let spent = hashcash.verify(&stamp, "alice", b"{}", 8).unwrap();
+        assert_eq!(spent.expires_at, challenge.expires_at);
+        assert_eq!(b64.encode(spent.nonce), challenge.nonce);
+    }
+
+    #[test]
+    fn stamps_are_bound_to_alias_content_and_difficulty() {
+        let hashcash = hashcash();
+        let nonce = hashcash.challenge("alice").nonce;
+        let stamp = solve(8, &nonce, "alice", b"{}");
+        assert_eq!(hashcash.verify(&stamp, "bob", b"{}", 8), Err(StampError::WrongResource));
+        assert_eq!(hashcash.verify(&stamp, "alice", b"{ }", 8), Err(StampError::WrongContent));
+        assert_eq!(
+            hashcash.verify(&stamp, "alice", b"{}", 12),
+            Err(StampError::TooEasy { required: 12 })
+        );
+
+        // a nonce of bob's, or of another server, is no good for alice
+        let foreign = hashcash.challenge("bob").nonce;
+        let stamp = solve(8, &foreign, "alice", b"{}");
+        assert_eq!(hashcash.verify(&stamp, "alice", b"{}", 8), Err(StampError::InvalidNonce));
+        let other = Hashcash::new(&PowSettings::default()).challenge("alice").nonce;
+        let stamp = solve(8, &other, "alice", b"{}");
+        assert_eq!(hashcash.verify(&stamp, "alice", b"{}", 8), Err(StampError::InvalidNonce));
+    }
+
+    #[test]
+    fn rejects_unsolved_and_expired_stamps() {
+        let hashcash = hashcash();
+        let challenge = hashcash.challenge("alice");
+        let prefix = format!("1:32:{}:alice:{}:", challenge.nonce, content_hash(b"{}"));
+        let unsolved = (0..).map(|c| format!("{prefix}{c}")).find(|s| {
+            leading_zero_bits(&Sha256::digest(s.as_bytes())) == 0
+        });
+        assert_eq!(
+            hashcash.verify(&unsolved.unwrap(), "alice", b"{}", 8),
+            Err(StampError::InsufficientWork)
+        );
+
+        let stamp = solve(4, &challenge.nonce, "alice", b"{}");
+        let later = challenge.expires_at + TimeDelta::seconds(1);
+        assert_eq!(hashcash.verify_at(&stamp, "alice", b"{}", 4, later), Err(StampError::Expired));
+        assert_eq!(hashcash.verify("1:4:x:alice", "alice", b"{}", 4), Err(StampError::Malformed));
+    }
+
+    #[test]
+    fn aliases_only_raise_difficulty() {
+        let hashcash = Hashcash::new(&PowSettings {
+            difficulty: 16,
+            max_difficulty: 24,
+            ..Default::default()
+        });
+        assert_eq!(hashcash.publish_difficulty(None), 16);
+        assert_eq!(hashcash.publish_difficulty(Some(8)), 16);
+        assert_eq!(hashcash.publish_difficulty(Some(20)), 20);
+        assert_eq!(hashcash.publish_difficulty(Some(30)), 24);
+    }
+}
@@ -0,0 +1,395 @@
+//! Web Push (RFC 8030) notifications telling subscribed browsers that
+//! an alias received a message.
+//!
+//! Notifications carry no message content. Their payload is still encrypted
+//! for the subscription (RFC 8291), and requests to push services are
+//! authenticated with VAPID (RFC 8292).
+
+use std::sync::Arc;
+
+use aes_gcm::aead::Aead;
+use aes_gcm::{Aes128Gcm, KeyInit, Nonce};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as b64;
+use base64::Engine;
+use chrono::Utc;
+use hkdf::Hkdf;
+use p256::ecdh::diffie_hellman;
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::{PublicKey, SecretKey};
+use rand::RngCore;
+use reqwest::{StatusCode, Url};
+use secrecy::ExposeSecret;
+use serde_json::json;
+use sha2::Sha256;
+use sqlx::PgPool;
+
+use crate::configuration::PushSettings;
+use crate::domain::key::KeyName;
+use crate::webhooks;
+
+/// Length of the subscription's authentication secret.
+pub const AUTH_SECRET_LEN: usize = 16;
+/// Record size announced in the content coding header. Notifications
+/// always fit into a single record.
+const RECORD_SIZE: u32 = 4096;
+/// How long a VAPID token stays valid, at most 24 hours per RFC 8292.
+const VAPID_VALIDITY: chrono::TimeDelta = chrono::TimeDelta::hours(12);
+
+/// Keys of a push subscription, as created by the user agent.
+#[derive(Debug, Clone)]
+pub struct SubscriptionKeys {
+    pub p256dh: PublicKey,
+    pub auth: [u8; AUTH_SECRET_LEN],
+}
+impl SubscriptionKeys {
+    pub fn parse(p256dh: &[u8], auth: &[u8]) -> Result<Self, String> {
+        let p256dh = PublicKey::from_sec1_bytes(p256dh).map_err(|_| "p256dh is not a P-256 public key")?;
+        let auth = auth
+            .try_into()
+            .map_err(|_| format!("auth must be {AUTH_SECRET_LEN} bytes"))?;
+        Ok(Self { p256dh, auth })
+    }
+    pub fn p256dh_bytes(&self) -> Vec<u8> {
+        self.p256dh.to_encoded_point(false).as_bytes().to_vec()
+    }
+}
+
+/// Application server key signing the VAPID tokens.
+pub struct Vapid {
+    key: SigningKey,
+    subject: String,
+}
+impl Vapid {
+    pub fn new(private_key: &str, subject: String) -> Result<Self, String> {
+        let bytes = b64
+            .decode(private_key)
+            .map_err(|_| "vapid private key is not valid base64url")?;
+        let key = SigningKey::from_slice(&bytes).map_err(|_| "vapid private key is not a P-256 scalar")?;
+        Ok(Self { key, subject })
+    }
+    /// base64url encoded uncompressed public key, the `applicationServerKey`
+    /// browsers subscribe with.
+    pub fn public_key(&self) -> String {
+        b64.encode(self.key.verifying_key().to_encoded_point(false).as_bytes())
+    }
+    /// `Authorization` header for a request to `endpoint`.
+    pub fn authorization(&self, endpoint: &Url) -> String {
+        let header = b64.encode(r#"{"typ":"JWT","alg":"ES256"}"#);
+        let claims = b64.encode(
+            json!({
+                "aud": endpoint.origin().ascii_serialization(),
+                "exp": (Utc::now() + VAPID_VALIDITY).timestamp(),
+                "sub": self.subject,
+            })
+            .to_string(),
+        );
+        let signing_input = format!("{header}.{claims}");
+        let signature: Signature = self.key.sign(signing_input.as_bytes());
+        format!(
+            "vapid t={signing_input}.{}, k={}",
+            b64.encode(signature.to_bytes()),
+            self.public_key()
+        )
+    }
+}
+
+/// Encrypts `plaintext` for a subscription with the `aes128gcm`
+/// content coding of RFC 8188, keyed as described in RFC 8291.
+pub fn encrypt(keys: &SubscriptionKeys, plaintext: &[u8]) -> Vec<u8> {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    encrypt_with(keys, plaintext, &salt, &SecretKey::random(&mut rand::rngs::OsRng))
+}
+
+fn encrypt_with(keys: &SubscriptionKeys, plaintext: &[u8], salt: &[u8; 16], secret: &SecretKey) -> Vec<u8> {
+    let server_public = secret.public_key().to_encoded_point(false);
+    let shared = diffie_hellman(secret.to_nonzero_scalar(), keys.p256dh.as_affine());
+
+    let mut key_info = b"WebPush: info\0".to_vec();
+    key_info.extend_from_slice(&keys.p256dh_bytes());
+    key_info.extend_from_slice(server_public.as_bytes());
+    let mut ikm = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&keys.auth), shared.raw_secret_bytes())
+        .expand(&key_info, &mut ikm)
+        .expect("valid hkdf length");
+    let prk = Hkdf::<Sha256>::new(Some(salt), &ikm);
+    let mut cek = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    prk.expand(b"Content-Encoding: aes128gcm\0", &mut cek)
+        .expect("valid hkdf length");
+    prk.expand(b"Content-Encoding: nonce\0", &mut nonce)
+        .expect("valid hkdf length");
+
+    // single record, ended by the last record delimiter
+    let mut record = plaintext.to_vec();
+    record.push(2);
+    let ciphertext = Aes128Gcm::new_from_slice(&cek)
+        .expect("valid aes key length")
+        .encrypt(Nonce::from_slice(&nonce), record.as_slice())
+        .expect("plaintext fits a record");
+
+    let mut body = salt.to_vec();
+    body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+    body.push(server_public.len() as u8);
+    body.extend_from_slice(server_public.as_bytes());
+    body.extend_from_slice(&ciphertext);
+    body
+}
+
+/// Sends an encrypted notification to a push service.
+pub async fn send(
+    client: &reqwest::Client,
+    vapid: &Vapid,
+    endpoint: &Url,
+    keys: &SubscriptionKeys,
+    payload: &[u8],
+    ttl_secs: u32,
+) -> reqwest::Result<StatusCode> {
+    let response = client
+        .post(endpoint.clone())
+        .header(reqwest::header::AUTHORIZATION, vapid.authorization(endpoint))
+        .header(reqwest::header::CONTENT_ENCODING, "aes128gcm")
+        .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+        .header("ttl", ttl_secs)
+        .header("urgency", "normal")
+        .body(encrypt(keys, payload))
+        .send()
+        .await?;
+    Ok(response.status())
+}
+
+/// Notifies the push subscriptions of aliases. Disabled, and a no-op,
+/// when no VAPID key is configured.
+#[derive(Clone)]
+pub struct PushService {
+    pool: PgPool,
+    client: reqwest::Client,
+    vapid: Option<Arc<Vapid>>,
+    ttl_secs: u32,
+    allow_insecure: bool,
+}
+
+impl PushService {
+    pub fn new(pool: PgPool, settings: &PushSettings) -> Self {
+        let vapid = settings.vapid_private_key.as_ref().map(|key| {
+            let vapid = Vapid::new(key.expose_secret(), settings.vapid_subject.clone())
+                .expect("invalid push settings");
+            Arc::new(vapid)
+        });
+        let client = webhooks::client(settings.timeout(), settings.allow_insecure).expect("failed to build push client");
+        Self {
+            pool,
+            client,
+            vapid,
+            ttl_secs: settings.ttl_secs,
+            allow_insecure: settings.allow_insecure,
+        }
+    }
+    pub fn vapid(&self) -> Option<&Vapid> {
+        self.vapid.as_deref()
+    }
+    pub fn allow_insecure(&self) -> bool {
+        self.allow_insecure
+    }
+
+    /// Tells every subscription of `alias` that it received a message.
+    /// Subscriptions the push service reports as gone are removed.
+    #[tracing::instrument(skip(self), name = "sending push notifications")]
+    pub async fn notify(self, alias: KeyName) {
+        let Some(vapid) = &self.vapid else {
+            return;
+        };
+        let subscriptions = sqlx::query!(
+            "SELECT endpoint, p256dh, auth FROM push_subscriptions WHERE alias = $1",
+            alias.name()
+        )
+        .fetch_all(&self.pool)
+        .await;
+        let subscriptions = match subscriptions {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!("database error: {e}");
+                return;
+            }
+        };
+        let payload = json!({ "type": "message", "alias": alias.name() }).to_string();
+        for sub in subscriptions {
+            let (Ok(endpoint), Ok(keys)) = (
+                webhooks::validate_url(&sub.endpoint, self.allow_insecure),
+                SubscriptionKeys::parse(&sub.p256dh, &sub.auth),
+            ) else {
+                continue;
+            };
+            match send(&self.client, vapid, &endpoint, &keys, payload.as_bytes(), self.ttl_secs).await {
+                Ok(status) if status.is_success() => {}
+                Ok(StatusCode::NOT_FOUND | StatusCode::GONE) => {
+                    let deleted = sqlx::query!(
+                        "DELETE FROM push_subscriptions WHERE alias = $1 AND endpoint = $2",
+                        alias.name(),
+                        sub.endpoint
+                    )
+                    .execute(&self.pool)
+                    .await;
+                    if let Err(e) = deleted {
+                        tracing::error!("database error: {e}");
+                    }
+                }
+                Ok(status) => tracing::warn!("push service answered {status}"),
+                Err(e) => tracing::warn!("error sending push notification: {}", e.without_url()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use axum::http::HeaderMap;
+    use p256::ecdsa::signature::Verifier;
+    use p256::ecdsa::VerifyingKey;
+    use std::sync::Mutex;
+
+    const VAPID_KEY: &str = "3jh3SikPJZd9cQu9bvwfbQNLqUtFQmvXy75-QB68S3U";
+
+    struct UserAgent {
+        secret: SecretKey,
+        keys: SubscriptionKeys,
+    }
+    impl UserAgent {
+        fn new() -> Self {
+            let secret = SecretKey::random(&mut rand::rngs::OsRng);
+            let mut auth = [0u8; AUTH_SECRET_LEN];
+            rand::thread_rng().fill_bytes(&mut auth);
+            let keys = SubscriptionKeys {
+                p256dh: secret.public_key(),
+                auth,
+            };
+            Self { secret, keys }
+        }
+        /// Decrypts a notification the way a browser does.
+        fn decrypt(&self, body: &[u8]) -> Vec<u8> {
+            let (salt, rest) = body.split_at(16);
+            let id_len = rest[4] as usize;
+            let (server_public, ciphertext) = rest[5..].split_at(id_len);
+            let server_public = PublicKey::from_sec1_bytes(server_public).unwrap();
+            let shared = p256::ecdh::diffie_hellman(self.secret.to_nonzero_scalar(), server_public.as_affine());
+
+            let mut key_info = b"WebPush: info\0".to_vec();
+            key_info.extend_from_slice(&self.keys.p256dh_bytes());
+            key_info.extend_from_slice(&rest[5..5 + id_len]);
+            let mut ikm = [0u8; 32];
+            Hkdf::<Sha256>::new(Some(&self.keys.auth), shared.raw_secret_bytes())
+                .expand(&key_info, &mut ikm)
+                .unwrap();
+            let prk = Hkdf::<Sha256>::new(Some(salt), &ikm);
+            let mut cek = [0u8; 16];
+            let mut nonce = [0u8; 12];
+            prk.expand(b"Content-Encoding: aes128gcm\0", &mut cek).unwrap();
+            prk.expand(b"Content-Encoding: nonce\0", &mut nonce).unwrap();
+            let mut record = Aes128Gcm::new_from_slice(&cek)
+                .unwrap()
+                .decrypt(Nonce::from_slice(&nonce), ciphertext)
+                .expect("failed decrypting notification");
+            assert_eq!(record.pop(), Some(2), "missing last record delimiter");
+            record
+        }
+    }
+
+    /// Local push service recording the requests it gets and answering `status`.
+    async fn mock_push_service(status: axum::http::StatusCode) -> (Url, Arc<Mutex<Vec<(HeaderMap, Vec<u8>)>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorder = seen.clone();
+        let app = axum::Router::new().route(
+            "/push/:id",
+            axum::routing::post(move |headers: HeaderMap, body: Bytes| async move {
+                recorder.lock().unwrap().push((headers, body.to_vec()));
+                status
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/push/abc", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url.parse().unwrap(), seen)
+    }
+
+    #[test]
+    fn invalid_subscription_keys_rejected() {
+        let ua = UserAgent::new();
+        let p256dh = ua.keys.p256dh_bytes();
+        assert!(SubscriptionKeys::parse(&p256dh, &ua.keys.auth).is_ok());
+        assert!(SubscriptionKeys::parse(&p256dh[1..], &ua.keys.auth).is_err());
+        assert!(SubscriptionKeys::parse(&p256dh, &ua.keys.auth[1..]).is_err());
+    }
+
+    #[test]
+    fn encrypted_payload_decrypts() {
+        let ua = UserAgent::new();
+        let body = encrypt(&ua.keys, b"you have mail");
+        assert_eq!(&body[16..20], &RECORD_SIZE.to_be_bytes());
+        assert_eq!(ua.decrypt(&body), b"you have mail");
+    }
+
+    #[test]
+    fn encrypts_rfc8291_example() {
+        let decode = |s: &str| b64.decode(s).unwrap();
+        let keys = SubscriptionKeys::parse(
+            &decode("BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4"),
+            &decode("BTBZMqHH6r4Tts7J_aSIgg"),
+        )
+        .unwrap();
+        let secret = SecretKey::from_slice(&decode("yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw")).unwrap();
+        let salt = decode("DGv6ra1nlYgDCS1FRnbzlw").try_into().unwrap();
+        let body = encrypt_with(&keys, b"When I grow up, I want to be a watermelon", &salt, &secret);
+        assert_eq!(
+            b64.encode(body),
+            "DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN"
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_to_push_service() {
+        let (endpoint, seen) = mock_push_service(axum::http::StatusCode::CREATED).await;
+        let vapid = Vapid::new(VAPID_KEY, "mailto:admin@example.com".into()).unwrap();
+        let ua = UserAgent::new();
+        let status = send(&reqwest::Client::new(), &vapid, &endpoint, &ua.keys, b"{}", 60)
+            .await
+            .expect("failed sending notification");
+        assert_eq!(status, StatusCode::CREATED);
+
+        let seen = seen.lock().unwrap();
+        let (headers, body) = &seen[0];
+        assert_eq!(headers["content-encoding"], "aes128gcm");
+        assert_eq!(headers["ttl"], "60");
+        assert_eq!(ua.decrypt(body), b"{}");
+
+        let auth = headers["authorization"].to_str().unwrap();
+        let (token, key) = auth
+            .strip_prefix("vapid t=")
+            .and_then(|a| a.split_once(", k="))
+            .expect("malformed vapid header");
+        assert_eq!(key, vapid.public_key());
+        let (signing_input, signature) = token.rsplit_once('.').unwrap();
+        let verifying_key = VerifyingKey::from_sec1_bytes(&b64.decode(key).unwrap()).unwrap();
+        let signature = Signature::from_slice(&b64.decode(signature).unwrap()).unwrap();
+        verifying_key
+            .verify(signing_input.as_bytes(), &signature)
+            .expect("invalid vapid signature");
+        let claims = signing_input.split('.').nth(1).unwrap();
+        let claims: serde_json::Value = serde_json::from_slice(&b64.decode(claims).unwrap()).unwrap();
+        assert_eq!(claims["aud"], endpoint.origin().ascii_serialization());
+        assert_eq!(claims["sub"], "mailto:admin@example.com");
+    }
+
+    #[tokio::test]
+    async fn reports_expired_subscription() {
+        let (endpoint, _) = mock_push_service(axum::http::StatusCode::GONE).await;
+        let vapid = Vapid::new(VAPID_KEY, "mailto:admin@example.com".into()).unwrap();
+        let status = send(&reqwest::Client::new(), &vapid, &endpoint, &UserAgent::new().keys, b"{}", 60)
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::GONE);
+    }
+}
@@ -0,0 +1,503 @@
+//! In-process token bucket rate limiting, as tower layers keyed by client
+//! address or by the recipients of published messages.
+//!
+//! Every response of a limited route carries `RateLimit-Limit`,
+//! `RateLimit-Remaining` and `RateLimit-Reset` headers, and requests over
+//! the limit are answered with 429 and `Retry-After`.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use axum::body::{to_bytes, Body};
+use axum::extract::{ConnectInfo, Request};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use lru::LruCache;
+use serde::Deserialize;
+use tower::{Layer, Service};
+
+use crate::domain::key::KeyName;
+use crate::routes::api::error::ApiError;
+
+pub const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+pub const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+pub const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+/// Buckets kept before the least recently used ones are dropped, which
+/// gives their keys a full bucket again.
+const MAX_TRACKED_KEYS: NonZeroUsize = NonZeroUsize::new(100_000).unwrap();
+
+/// Size and refill rate of a token bucket.
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct Quota {
+    /// requests allowed at once, after a quiet period
+    pub burst: NonZeroU32,
+    /// requests allowed per minute, sustained
+    pub per_minute: NonZeroU32,
+}
+impl Quota {
+    fn refill_interval(&self) -> Duration {
+        Duration::from_secs(60) / self.per_minute.get()
+    }
+}
+
+/// An address, like `10.0.0.1`, or a CIDR range, like `10.0.0.0/8`.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+impl TryFrom<String> for IpNet {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.as_str(), None),
+        };
+        let addr = canonical(addr.parse().map_err(|_| format!("`{s}` is not an IP address"))?);
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(|| format!("`{s}` has an invalid prefix length"))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// IPv4 clients reaching an IPv6 socket appear as mapped addresses.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
+
+/// Finds the address of the client, believing `X-Forwarded-For` only as
+/// far as it was written by trusted proxies.
+#[derive(Clone, Debug, Default)]
+pub struct ClientIp {
+    trusted_proxies: Arc<[IpNet]>,
+}
+impl ClientIp {
+    pub fn new(trusted_proxies: &[IpNet]) -> Self {
+        Self {
+            trusted_proxies: trusted_proxies.into(),
+        }
+    }
+    fn trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+    /// Address of the peer unless it is a trusted proxy. Then proxies
+    /// appended the addresses they were reached from to `X-Forwarded-For`,
+    /// so the client is the rightmost one not belonging to a trusted proxy;
+    /// anything left of it may be forged.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = canonical(peer);
+        if !self.trusted(client) {
+            return client;
+        }
+        let hops: Vec<&str> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .collect();
+        for hop in hops.into_iter().rev() {
+            let Ok(ip) = hop.parse() else {
+                break;
+            };
+            client = canonical(ip);
+            if !self.trusted(client) {
+                break;
+            }
+        }
+        client
+    }
+    /// Key of the bucket of the client sending `request`. Without
+    /// connection info, as in tests, every client shares one bucket.
+    pub fn key(&self, request: &Request) -> String {
+        request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(peer)| client_key(self.client_ip(peer.ip(), request.headers())))
+            .unwrap_or_default()
+    }
+}
+
+/// Key of the bucket of a client. An IPv6 host usually gets a whole /64,
+/// so its addresses share one.
+fn client_key(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => {
+            let prefix = u128::from(ip) & (u128::MAX << 64);
+            format!("{}/64", Ipv6Addr::from(prefix))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// What a request was told by a limiter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decision {
+    pub limit: u32,
+    pub remaining: u32,
+    /// until the bucket is full again
+    pub reset: Duration,
+    /// until enough tokens are available, for denied requests
+    pub retry_after: Option<Duration>,
+}
+impl Decision {
+    fn add_headers(&self, headers: &mut HeaderMap) {
+        // with several limiters on a route, report the one closest to denying
+        let tighter = headers
+            .get(RATELIMIT_REMAINING)
+            .and_then(|v| v.to_str().ok()?.parse::<u32>().ok())
+            .is_none_or(|remaining| self.remaining < remaining);
+        if tighter {
+            headers.insert(RATELIMIT_LIMIT, self.limit.into());
+            headers.insert(RATELIMIT_REMAINING, self.remaining.into());
+            headers.insert(RATELIMIT_RESET, ceil_secs(self.reset).into());
+        }
+    }
+    fn into_response(self) -> Response {
+        let retry_after = ceil_secs(self.retry_after.unwrap_or(self.reset));
+        let error = ApiError::new(StatusCode::TOO_MANY_REQUESTS, "rate_limited")
+            .with_detail("too many requests, retry after the time given in `Retry-After`");
+        let mut response = ([(header::RETRY_AFTER, HeaderValue::from(retry_after))], error).into_response();
+        self.add_headers(response.headers_mut());
+        response
+    }
+}
+
+fn ceil_secs(d: Duration) -> u64 {
+    d.as_secs() + u64::from(d.subsec_nanos() > 0)
+}
+
+/// Token buckets of one quota, keyed by client or recipient. Clones share
+/// the buckets.
+#[derive(Clone)]
+pub struct RateLimiter {
+    quota: Quota,
+    buckets: Arc<Mutex<LruCache<String, Bucket>>>,
+}
+impl RateLimiter {
+    pub fn new(quota: Quota) -> Self {
+        Self::with_capacity(quota, MAX_TRACKED_KEYS)
+    }
+    fn with_capacity(quota: Quota, capacity: NonZeroUsize) -> Self {
+        Self {
+            quota,
+            buckets: Arc::new(Mutex::new(LruCache::new(capacity))),
+        }
+    }
+
+    /// Takes a token from the bucket of every key, counting repeated keys
+    /// repeatedly, or none when any of them has too few.
+    pub fn check(&self, keys: &[String], now: Instant) -> Decision {
+        let mut wanted: HashMap<&str, u32> = HashMap::new();
+        for key in keys {
+            *wanted.entry(key).or_default() += 1;
+        }
+        let burst = f64::from(self.quota.burst.get());
+        let refill = self.quota.refill_interval().as_secs_f64();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let mut tokens: Vec<(&str, u32, f64)> = Vec::with_capacity(wanted.len());
+        for (key, count) in wanted {
+            let available = buckets.peek(key).map_or(burst, |b| {
+                let refilled = now.saturating_duration_since(b.updated).as_secs_f64() / refill;
+                (b.tokens + refilled).min(burst)
+            });
+            tokens.push((key, count, available));
+        }
+        let shortfall = tokens
+            .iter()
+            .map(|&(_, count, available)| f64::from(count) - available)
+            .fold(f64::MIN, f64::max);
+        let allowed = shortfall <= 0.0;
+        let mut least = burst;
+        for &(key, count, available) in &tokens {
+            let left = if allowed { available - f64::from(count) } else { available };
+            buckets.put(key.to_owned(), Bucket { tokens: left, updated: now });
+            least = least.min(left);
+        }
+        Decision {
+            limit: self.quota.burst.get(),
+            remaining: least.max(0.0) as u32,
+            reset: Duration::from_secs_f64((burst - least) * refill),
+            retry_after: (!allowed).then(|| Duration::from_secs_f64(shortfall * refill)),
+        }
+    }
+}
+
+/// What requests are counted against.
+#[derive(Clone)]
+enum KeyBy {
+    Client(ClientIp),
+    /// recipients of a published message or batch, read from a body of at most that many bytes
+    Recipients(usize),
+}
+
+/// Limits requests per client address.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: RateLimiter,
+    key: KeyBy,
+}
+impl RateLimitLayer {
+    pub fn per_client(limiter: RateLimiter, client_ip: ClientIp) -> Self {
+        Self {
+            limiter,
+            key: KeyBy::Client(client_ip),
+        }
+    }
+    /// Limits messages per recipient, for routes taking a `PublishMessage`
+    /// or `PublishBatch` body. Bodies without recipients, or with an invalid
+    /// one, are passed on for the handler to reject, so buckets are only
+    /// kept for valid aliases.
+    pub fn per_recipient(limiter: RateLimiter, max_body: usize) -> Self {
+        Self {
+            limiter,
+            key: KeyBy::Recipients(max_body),
+        }
+    }
+}
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limiter: self.limiter.clone(),
+            key: self.key.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    limiter: RateLimiter,
+    key: KeyBy,
+}
+
+#[derive(Deserialize)]
+struct Recipient {
+    recipient: KeyName,
+}
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Recipients {
+    One(Recipient),
+    Batch { messages: Vec<Recipient> },
+}
+
+impl<S> Service<Request> for RateLimit<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // the clone may not be ready, so call the one that is
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limiter = self.limiter.clone();
+        let key = self.key.clone();
+        Box::pin(async move {
+            let (keys, request) = match key {
+                KeyBy::Client(client_ip) => (vec![client_ip.key(&request)], request),
+                KeyBy::Recipients(max_body) => {
+                    let (parts, body) = request.into_parts();
+                    let Ok(body) = to_bytes(body, max_body).await else {
+                        return Ok(ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "body_too_large").into_response());
+                    };
+                    let keys = match serde_json::from_slice(&body) {
+                        Ok(Recipients::One(one)) => vec![one.recipient.into()],
+                        Ok(Recipients::Batch { messages }) => messages.into_iter().map(|m| m.recipient.into()).collect(),
+                        Err(_) => Vec::new(),
+                    };
+                    (keys, Request::from_parts(parts, Body::from(body)))
+                }
+            };
+            if keys.is_empty() {
+                return inner.call(request).await;
+            }
+            let decision = limiter.check(&keys, Instant::now());
+            if decision.retry_after.is_some() {
+                return Ok(decision.into_response());
+            }
+            let mut response = inner.call(request).await?;
+            decision.add_headers(response.headers_mut());
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::post;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    fn quota(burst: u32, per_minute: u32) -> Quota {
+        Quota {
+            burst: NonZeroU32::new(burst).unwrap(),
+            per_minute: NonZeroU32::new(per_minute).unwrap(),
+        }
+    }
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|&k| k.to_owned()).collect()
+    }
+
+    fn net(s: &str) -> IpNet {
+        IpNet::try_from(s.to_owned()).unwrap()
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let limiter = RateLimiter::new(quota(2, 60));
+        let start = Instant::now();
+        let alice = keys(&["alice"]);
+        assert_eq!(limiter.check(&alice, start).remaining, 1);
+        assert_eq!(limiter.check(&alice, start).remaining, 0);
+        let denied = limiter.check(&alice, start);
+        assert_eq!(denied.retry_after, Some(Duration::from_secs(1)));
+        assert_eq!(denied.reset, Duration::from_secs(2));
+        // other keys have their own bucket
+        assert!(limiter.check(&keys(&["bob"]), start).retry_after.is_none());
+
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.check(&alice, later).retry_after.is_none());
+        assert!(limiter.check(&alice, later).retry_after.is_some());
+    }
+
+    #[test]
+    fn denied_batches_take_nothing() {
+        let limiter = RateLimiter::new(quota(3, 60));
+        let now = Instant::now();
+        assert!(limiter.check(&keys(&["alice", "alice", "bob"]), now).retry_after.is_none());
+        // alice has one token left, bob two
+        let denied = limiter.check(&keys(&["alice", "alice", "bob"]), now);
+        assert_eq!(denied.retry_after, Some(Duration::from_secs(1)));
+        assert_eq!(limiter.check(&keys(&["bob", "bob"]), now).remaining, 0);
+    }
+
+    #[test]
+    fn least_recently_used_buckets_are_dropped() {
+        let limiter = RateLimiter::with_capacity(quota(1, 1), NonZeroUsize::new(2).unwrap());
+        let now = Instant::now();
+        assert!(limiter.check(&keys(&["alice"]), now).retry_after.is_none());
+        assert!(limiter.check(&keys(&["bob"]), now).retry_after.is_none());
+        assert!(limiter.check(&keys(&["alice"]), now).retry_after.is_some());
+        // bob's bucket goes, alice's was used since
+        assert!(limiter.check(&keys(&["carol"]), now).retry_after.is_none());
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+        assert!(limiter.check(&keys(&["alice"]), now).retry_after.is_some());
+        assert!(limiter.check(&keys(&["bob"]), now).retry_after.is_none());
+    }
+
+    #[test]
+    fn parses_networks() {
+        assert!(net("10.0.0.0/8").contains("10.1.2.3".parse().unwrap()));
+        assert!(!net("10.0.0.0/8").contains("11.0.0.1".parse().unwrap()));
+        assert!(net("192.168.1.1").contains("::ffff:192.168.1.1".parse().unwrap()));
+        assert!(net("fd00::/8").contains("fd12::1".parse().unwrap()));
+        assert!(net("0.0.0.0/0").contains("1.2.3.4".parse().unwrap()));
+        assert!(IpNet::try_from("10.0.0.0/33".to_owned()).is_err());
+        assert!(IpNet::try_from("localhost".to_owned()).is_err());
+    }
+
+    #[test]
+    fn trusts_forwarded_for_only_from_proxies() {
+        let client_ip = ClientIp::new(&[net("10.0.0.0/8")]);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "6.6.6.6, 1.2.3.4, 10.0.0.2".parse().unwrap());
+        let proxy = "10.0.0.1".parse().unwrap();
+        assert_eq!(client_ip.client_ip(proxy, &headers), "1.2.3.4".parse::<IpAddr>().unwrap());
+        let direct = "5.6.7.8".parse().unwrap();
+        assert_eq!(client_ip.client_ip(direct, &headers), direct);
+        assert_eq!(client_ip.client_ip(proxy, &HeaderMap::new()), proxy);
+    }
+
+    #[test]
+    fn ipv6_clients_share_their_prefix() {
+        assert_eq!(client_key("2001:db8::1".parse().unwrap()), "2001:db8::/64");
+        assert_eq!(client_key("2001:db8::2".parse().unwrap()), "2001:db8::/64");
+        assert_eq!(client_key("1.2.3.4".parse().unwrap()), "1.2.3.4");
+    }
+
+    #[tokio::test]
+    async fn limits_per_recipient() {
+        let layer = RateLimitLayer::per_recipient(RateLimiter::new(quota(1, 1)), 1024);
+        let app = Router::new().route("/", post(|body: String| async move { body }).layer(layer));
+        let publish = |recipient: &str| {
+            let body = format!(r#"{{"recipient":"{recipient}","content":{{}}}}"#);
+            Request::post("/").body(Body::from(body)).unwrap()
+        };
+
+        let response = app.clone().oneshot(publish("alice")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[RATELIMIT_REMAINING], "0");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(body.starts_with(br#"{"recipient":"alice""#));
+
+        let response = app.clone().oneshot(publish("alice")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "60");
+        assert_eq!(response.headers()[RATELIMIT_LIMIT], "1");
+
+        let batch = r#"{"messages":[{"recipient":"bob"},{"recipient":"alice"}]}"#;
+        let response = app.clone().oneshot(Request::post("/").body(Body::from(batch)).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let response = app.clone().oneshot(publish("bob")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // invalid aliases get no bucket, the handler rejects them
+        for _ in 0..2 {
+            let response = app.clone().oneshot(publish("not an alias")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(!response.headers().contains_key(RATELIMIT_REMAINING));
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use std::time::Duration;
+
+use crate::startup::AppState;
+use crate::telemetry;
+
+/// Periodically deletes expired messages, drops, spent stamps and owner
+/// claims, tokens of retired keys, stale receipts, idempotency keys and
+/// webhook delivery logs, then garbage collects abandoned uploads and blobs
+/// no message cites anymore.
+/// Returns once shutting down, after finishing the current run.
+pub async fn run(state: AppState, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = state.shutdown.cancelled() => return,
+        }
+        reap(&state).await;
+    }
+}
+
+#[tracing::instrument(skip(state), name = "reaping expired data")]
+async fn reap(state: &AppState) {
+    let start = std::time::Instant::now();
+    match delete_expired_messages(&state.pool).await {
+        Ok(n) => {
+            reaped("messages", n);
+            if n > 0 {
+                tracing::info!("deleted {n} expired messages");
+            }
+        }
+        Err(e) => failed("expired messages", e),
+    }
+    match delete_expired_drops(&state.pool).await {
+        Ok(n) => {
+            reaped("drops", n);
+            if n > 0 {
+                tracing::info!("deleted {n} expired drops");
+            }
+        }
+        Err(e) => failed("expired drops", e),
+    }
+    match delete_expired_stamps(&state.pool).await {
+        Ok(n) => reaped("stamps", n),
+        Err(e) => failed("spent stamps", e),
+    }
+    match delete_expired_claims(&state.pool).await {
+        Ok(n) => reaped("owner_claims", n),
+        Err(e) => failed("owner claims", e),
+    }
+    // generated keys differ between instances, which must not forget each other's tokens
+    if state.tokens.is_configured() {
+        match delete_retired_tokens(&state.pool, state.tokens.key_id()).await {
+            Ok(n) => reaped("tokens", n),
+            Err(e) => failed("spent tokens", e),
+        }
+    }
+    let retention = state.messages.receipt_retention().as_secs_f64();
+    match delete_old_receipts(&state.pool, retention).await {
+        Ok(n) => reaped("receipts", n),
+        Err(e) => failed("message receipts", e),
+    }
+    let window = state.idempotency.window().as_secs_f64();
+    match delete_old_idempotency_keys(&state.pool, window).await {
+        Ok(n) => reaped("idempotency_keys", n),
+        Err(e) => failed("idempotency keys", e),
+    }
+    let retention = state.webhooks.log_retention().as_secs_f64();
+    match delete_old_deliveries(&state.pool, retention).await {
+        Ok(n) => reaped("webhook_deliveries", n),
+        Err(e) => failed("webhook delivery logs", e),
+    }
+    match state.blobs.collect_garbage().await {
+        Ok(stats) => {
+            reaped("uploads", stats.uploads);
+            reaped("blobs", stats.blobs);
+            if stats.uploads > 0 || stats.blobs > 0 {
+                tracing::info!(
+                    "removed {} abandoned uploads and {} unreferenced blobs",
+                    stats.uploads,
+                    stats.blobs
+                );
+            }
+        }
+        Err(e) => {
+            telemetry::record_job_error("reaper");
+            tracing::error!("error collecting blobs: {e}");
+        }
+    }
+    telemetry::record_job("reaper", start.elapsed());
+}
+
+fn reaped(kind: &'static str, n: u64) {
+    metrics::counter!("blindchannel_reaped_total", "kind" => kind).increment(n);
+}
+
+fn failed(what: &str, e: sqlx::Error) {
+    telemetry::record_job_error("reaper");
+    tracing::error!("error deleting {what}: {e}");
+}
+
+async fn delete_expired_messages(pool: &sqlx::PgPool) -> sqlx::Result<u64> {
+    let result = sqlx::query!("DELETE FROM messages WHERE expires_at <= now()")
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+/// Deleting a drop also deletes its messages.
+async fn delete_expired_drops(pool: &sqlx::PgPool) -> sqlx::Result<u64> {
+    let result = sqlx::query!("DELETE FROM drops WHERE expires_at <= now()")
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+/// Stamps of expired challenges are rejected anyway.
+async fn delete_expired_stamps(pool: &sqlx::PgPool) -> sqlx::Result<u64> {
+    let result = sqlx::query!("DELETE FROM spent_stamps WHERE expires_at <= now()")
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+/// Owner tokens not presented back in time are refused anyway.
+async fn delete_expired_claims(pool: &sqlx::PgPool) -> sqlx::Result<u64> {
+    let result = sqlx::query!("DELETE FROM owner_claims WHERE expires_at <= now()")
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+/// Tokens of another key than the current one no longer verify.
+async fn delete_retired_tokens(pool: &sqlx::PgPool, key_id: &[u8]) -> sqlx::Result<u64> {
+    let result = sqlx::query!("DELETE FROM spent_tokens WHERE key_id <> $1", key_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+async fn delete_old_deliveries(pool: &sqlx::PgPool, retention_secs: f64) -> sqlx::Result<u64> {
+    let result = sqlx::query!(
+        "DELETE FROM webhook_deliveries WHERE attempted_at < now() - make_interval(secs => $1)",
+        retention_secs
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+async fn delete_old_receipts(pool: &sqlx::PgPool, retention_secs: f64) -> sqlx::Result<u64> {
+    let result = sqlx::query!(
+        r#"DELETE FROM message_receipts
+        WHERE expires_at < now() - make_interval(secs => $1)
+            OR deleted_at < now() - make_interval(secs => $1)"#,
+        retention_secs
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+async fn delete_old_idempotency_keys(pool: &sqlx::PgPool, window_secs: f64) -> sqlx::Result<u64> {
+    let result = sqlx::query!(
+        "DELETE FROM idempotency_keys WHERE created_at < now() - make_interval(secs => $1)",
+        window_secs
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
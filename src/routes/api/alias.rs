@@ -1,67 +1,455 @@
-use crate::domain::key::{PublicJwk, KeyName};
+use std::collections::HashMap;
+
+use crate::configuration::SearchSettings;
+use crate::domain::key::{DeviceKey, JwkSet, Kid, KeyName, PublicJwk};
+use crate::keycache::{KeyCache, KeySet};
 use axum::extract::State;
-use axum::http::StatusCode;
-use axum::Json;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
 use sqlx::PgPool;
-use axum::extract::Path;
 use serde::{Serialize, Deserialize};
+use utoipa::{IntoParams, ToSchema};
+
+use super::error::ApiError;
+use super::extract::{Json, Path, Query};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Results of the deprecated `/search/{alias}`.
+const LEGACY_SEARCH_RESULTS: i64 = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Path)]
 pub struct Params {
     alias: KeyName,
 }
-#[tracing::instrument(skip(pool), name = "get public_key by name")]
+
+/// Returns the device keys registered under an alias. Clients revalidate
+/// them with `If-None-Match`, which is answered with 304 while the keys
+/// are unchanged.
+#[utoipa::path(
+    get,
+    path = "/registry/{alias}",
+    params(
+        Params,
+        ("If-None-Match" = Option<String>, Header, description = "`ETag` of the key set the client has"),
+    ),
+    responses(
+        (
+            status = 200,
+            description = "Device keys of the alias",
+            body = JwkSet,
+            headers(
+                ("ETag" = String, description = "Changes whenever a device key is added or removed"),
+                ("Cache-Control" = String),
+            ),
+        ),
+        (status = 304, description = "The key set is unchanged"),
+        (status = 404, description = "No such alias"),
+    ),
+)]
+#[tracing::instrument(skip(pool, cache, headers), name = "get device keys by name")]
 pub async fn fetch_alias(
     State(pool): State<PgPool>,
+    State(cache): State<KeyCache>,
     Path(params): Path<Params>,
-) -> Result<Json<PublicJwk>, StatusCode> {
-    let key = match get_key_by_name(&pool, params.alias.name()).await {
-        Ok(k) => k,
-        Err(sqlx::Error::RowNotFound) => return Err(StatusCode::NOT_FOUND),
-        Err(e) => {
-            tracing::error!("database error: {e}");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    };
-    Ok(Json(key))
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let name = params.alias.name();
+    let keys = key_sets(&pool, &cache, &[name.to_owned()])
+        .await?
+        .remove(name)
+        .ok_or_else(|| ApiError::not_found("alias_not_found"))?;
+    let validators = [
+        (header::ETAG, keys.etag.clone()),
+        (header::CACHE_CONTROL, cache.cache_control().to_owned()),
+    ];
+    let unchanged = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|tags| keys.matches(tags));
+    if unchanged {
+        return Ok((StatusCode::NOT_MODIFIED, validators).into_response());
+    }
+    Ok((validators, [(header::CONTENT_TYPE, "application/json")], keys.body).into_response())
 }
 
-#[tracing::instrument(skip(pool), name = "name fuzzy search")]
-pub async fn search_alias(
+/// How search matches aliases against the query.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    /// the alias itself, scored 1
+    Exact,
+    /// aliases starting with the query, scored by how much of them it is
+    Prefix,
+    /// aliases sharing trigrams with the query, scored by `similarity()`
+    #[default]
+    Trigram,
+    /// aliases a few edits away, scored 1 - distance / length of the longer
+    Levenshtein,
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchParams {
+    /// alias, or start of one, to look for
+    q: KeyName,
+    #[serde(default)]
+    mode: SearchMode,
+    /// lowest score of results in trigram and Levenshtein search, the
+    /// configured one if absent. It may not be below the configured minimum.
+    #[param(minimum = 0, maximum = 1)]
+    threshold: Option<f64>,
+    /// max results to fetch
+    #[param(minimum = 1)]
+    limit: Option<u32>,
+    /// results to skip, up to the configured maximum
+    offset: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SearchResult {
+    alias: String,
+    /// from 0 to 1, 1 being the query itself
+    score: f64,
+    /// unpadded base64url SHA-256 of the key set served for the alias
+    fingerprint: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SearchPage {
+    /// an exact match first, then best scores first
+    results: Vec<SearchResult>,
+    /// offset of the next page, absent on the last page or past the
+    /// maximum offset
+    #[serde(rename = "nextOffset", skip_serializing_if = "Option::is_none")]
+    next_offset: Option<u32>,
+}
+
+/// Searches registered aliases. Results carry the fingerprint of their
+/// key set, for users to check they found who they meant.
+#[utoipa::path(
+    get,
+    path = "/search",
+    params(SearchParams),
+    responses(
+        (status = 200, description = "A page of matching aliases", body = SearchPage),
+        (status = 400, description = "Invalid query, threshold or offset"),
+    ),
+)]
+#[tracing::instrument(skip(pool, cache, settings), name = "alias search")]
+pub async fn search(
     State(pool): State<PgPool>,
-    Path(params): Path<Params>,
-) -> Result<Json<Vec<String>>, StatusCode> {
-    let names = match name_fuzzy_search(&pool, params.alias.name()).await {
-        Ok(names) => names,
-        Err(e) => {
-            tracing::error!("database error: {e}");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
+    State(cache): State<KeyCache>,
+    State(settings): State<SearchSettings>,
+    Query(params): Query<SearchParams>,
+) -> Result<Json<SearchPage>, ApiError> {
+    let threshold = threshold(&params, &settings)?;
+    let limit = params.limit.unwrap_or(settings.default_limit).clamp(1, settings.max_limit.max(1));
+    let offset = params.offset.unwrap_or(0);
+    if offset > settings.max_offset {
+        let max = settings.max_offset;
+        return Err(ApiError::bad_request("invalid_query").with_field("offset", format!("must be at most {max}")));
+    }
+    // one more than asked, to tell whether there is a next page
+    let mut matches = find_aliases(
+        &pool,
+        params.mode,
+        params.q.name(),
+        threshold,
+        limit as i64 + 1,
+        offset as i64,
+    )
+    .await?;
+    let next_offset = (matches.len() > limit as usize)
+        .then(|| offset.saturating_add(limit))
+        .filter(|&next| next <= settings.max_offset);
+    matches.truncate(limit as usize);
+    let names: Vec<_> = matches.iter().map(|m| m.name.clone()).collect();
+    let mut keys = key_sets(&pool, &cache, &names).await?;
+    // aliases whose keys were all removed since are left out
+    let results = matches
+        .into_iter()
+        .filter_map(|m| {
+            let keys = keys.remove(&m.name)?;
+            Some(SearchResult {
+                alias: m.name,
+                score: m.score,
+                fingerprint: keys.fingerprint,
+            })
+        })
+        .collect();
+    Ok(Json(SearchPage { results, next_offset }))
+}
+
+/// The threshold asked for, or the configured one, checked against the
+/// configured minimum of the mode.
+fn threshold(params: &SearchParams, settings: &SearchSettings) -> Result<f64, ApiError> {
+    let (default, min) = match params.mode {
+        SearchMode::Levenshtein => (settings.levenshtein_threshold, settings.min_levenshtein_threshold),
+        SearchMode::Trigram => (settings.trigram_threshold, settings.min_trigram_threshold),
+        // ignored by these
+        SearchMode::Exact | SearchMode::Prefix => (settings.trigram_threshold, 0.0),
+    };
+    let Some(threshold) = params.threshold else {
+        return Ok(default);
     };
-    Ok(Json(names))
+    if !(min..=1.0).contains(&threshold) {
+        return Err(ApiError::bad_request("invalid_query")
+            .with_field("threshold", format!("must be between {min} and 1")));
+    }
+    Ok(threshold)
 }
 
-async fn get_key_by_name(pool: &PgPool, name: &str) -> sqlx::Result<PublicJwk> {
-    let key_str = sqlx::query!(
-        r#"SELECT public_key as "key: sqlx::types::Json<PublicJwk>"
-        FROM keymap WHERE name = $1"#,
-        name
+/// Returns up to 10 registered aliases similar to the given one, most
+/// similar first. Superseded by `/search`.
+#[deprecated = "use `/search`"]
+#[utoipa::path(
+    get,
+    path = "/search/{alias}",
+    params(Params),
+    responses((status = 200, description = "Similar aliases", body = Vec<String>)),
+)]
+#[tracing::instrument(skip(pool, settings), name = "name fuzzy search")]
+pub async fn search_alias(
+    State(pool): State<PgPool>,
+    State(settings): State<SearchSettings>,
+    Path(params): Path<Params>,
+) -> Result<Json<Vec<String>>, ApiError> {
+    let name = params.alias.name();
+    let matches = find_aliases(
+        &pool,
+        SearchMode::Trigram,
+        name,
+        settings.trigram_threshold,
+        LEGACY_SEARCH_RESULTS,
+        0,
     )
-    .fetch_one(pool)
     .await?;
-    Ok(key_str.key.0)
+    Ok(Json(matches.into_iter().map(|m| m.name).collect()))
 }
 
-async fn name_fuzzy_search(pool: &PgPool, name: &str) -> sqlx::Result<Vec<String>> {
-    let names = sqlx::query!(
-        r#"SELECT name
-        FROM keymap
-        WHERE name % $1
-        ORDER BY similarity(name, $1) DESC, name
-        LIMIT 10"#,
-        name
+/// Served key sets of the aliases in `names` which have device keys,
+/// from the cache where possible.
+async fn key_sets(pool: &PgPool, cache: &KeyCache, names: &[String]) -> sqlx::Result<HashMap<String, KeySet>> {
+    let mut found = HashMap::with_capacity(names.len());
+    let mut missing = Vec::new();
+    for name in names {
+        match cache.get(name) {
+            Some(keys) => {
+                found.insert(name.clone(), keys);
+            }
+            None => missing.push(name.clone()),
+        }
+    }
+    if missing.is_empty() {
+        return Ok(found);
+    }
+    let generation = cache.generation();
+    for (name, (version, keys)) in get_keys_by_names(pool, &missing).await? {
+        if keys.keys.is_empty() {
+            continue;
+        }
+        let keys = KeySet::new(version, &keys);
+        cache.insert(generation, &name, keys.clone());
+        found.insert(name, keys);
+    }
+    Ok(found)
+}
+
+/// The version and device keys of each registered alias in `names`.
+async fn get_keys_by_names(pool: &PgPool, names: &[String]) -> sqlx::Result<HashMap<String, (i64, JwkSet)>> {
+    let rows = sqlx::query!(
+        r#"SELECT k.name, k.key_version, d.kid as "kid?", d.public_key as "key?: sqlx::types::Json<PublicJwk>"
+        FROM keymap k LEFT JOIN device_keys d ON d.alias = k.name
+        WHERE k.name = ANY($1) ORDER BY d.created_at, d.kid"#,
+        names
     )
     .fetch_all(pool)
     .await?;
-    Ok(names.into_iter().map(|r| r.name).collect())
+    let mut found: HashMap<String, (i64, JwkSet)> = HashMap::new();
+    for r in rows {
+        let (_, keys) = found
+            .entry(r.name)
+            .or_insert_with(|| (r.key_version, JwkSet { keys: Vec::new() }));
+        if let (Some(kid), Some(key)) = (r.kid, r.key) {
+            keys.keys.push(DeviceKey {
+                kid: Kid::parse(kid).map_err(|e| sqlx::Error::Decode(e.into()))?,
+                jwk: key.0,
+            });
+        }
+    }
+    Ok(found)
+}
+
+struct Match {
+    name: String,
+    score: f64,
+}
+
+/// Registered aliases with device keys matching `q`, an exact match
+/// first, then best scores first. `threshold` only applies to trigram and
+/// Levenshtein search.
+async fn find_aliases(
+    pool: &PgPool,
+    mode: SearchMode,
+    q: &str,
+    threshold: f64,
+    limit: i64,
+    offset: i64,
+) -> sqlx::Result<Vec<Match>> {
+    match mode {
+        SearchMode::Exact => {
+            sqlx::query_as!(
+                Match,
+                r#"SELECT k.name, 1::float8 as "score!" FROM keymap k
+                WHERE k.name = $1 AND EXISTS (SELECT 1 FROM device_keys d WHERE d.alias = k.name)
+                LIMIT $2 OFFSET $3"#,
+                q,
+                limit,
+                offset
+            )
+            .fetch_all(pool)
+            .await
+        }
+        SearchMode::Prefix => {
+            sqlx::query_as!(
+                Match,
+                r#"SELECT k.name, length($1)::float8 / length(k.name) as "score!" FROM keymap k
+                WHERE k.name LIKE $2 ESCAPE '\' AND EXISTS (SELECT 1 FROM device_keys d WHERE d.alias = k.name)
+                ORDER BY k.name = $1 DESC, 2 DESC, k.name
+                LIMIT $3 OFFSET $4"#,
+                q,
+                like_prefix(q),
+                limit,
+                offset
+            )
+            .fetch_all(pool)
+            .await
+        }
+        SearchMode::Trigram => {
+            // `%` compares against the threshold setting, set for this
+            // transaction only so pooled connections don't keep it
+            let mut tx = pool.begin().await?;
+            sqlx::query!(
+                "SELECT set_config('pg_trgm.similarity_threshold', $1, true)",
+                threshold.to_string()
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            let matches = sqlx::query_as!(
+                Match,
+                r#"SELECT k.name, similarity(k.name, $1)::float8 as "score!" FROM keymap k
+                WHERE k.name % $1 AND EXISTS (SELECT 1 FROM device_keys d WHERE d.alias = k.name)
+                ORDER BY k.name = $1 DESC, 2 DESC, k.name
+                LIMIT $2 OFFSET $3"#,
+                q,
+                limit,
+                offset
+            )
+            .fetch_all(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            Ok(matches)
+        }
+        SearchMode::Levenshtein => {
+            // only names of a length that can score high enough are
+            // compared, up to the distance the threshold allows them
+            let (shortest, longest) = levenshtein_lengths(q.len(), threshold);
+            sqlx::query_as!(
+                Match,
+                r#"SELECT name as "name!", score as "score!" FROM (
+                    SELECT k.name,
+                        (l - levenshtein_less_equal(k.name, $1, ceil((1 - $2::float8) * l)::int))::float8 / l as score
+                    FROM keymap k, greatest(length(k.name), length($1)) l
+                    WHERE length(k.name) BETWEEN $5 AND $6
+                        AND EXISTS (SELECT 1 FROM device_keys d WHERE d.alias = k.name)
+                ) s
+                WHERE score >= $2
+                ORDER BY name = $1 DESC, score DESC, name
+                LIMIT $3 OFFSET $4"#,
+                q,
+                threshold,
+                limit,
+                offset,
+                shortest,
+                longest
+            )
+            .fetch_all(pool)
+            .await
+        }
+    }
+}
+
+/// Lengths of the names a Levenshtein search for a query `q_len` long may
+/// match. A name `n` long is at least `|n - q_len|` edits away, so it can
+/// only reach `threshold` if `threshold * q_len <= n <= q_len / threshold`.
+/// The bounds are rounded outwards, scores are compared exactly.
+fn levenshtein_lengths(q_len: usize, threshold: f64) -> (i32, i32) {
+    const SLACK: f64 = 1e-9;
+    let q_len = q_len as f64;
+    let shortest = (threshold * q_len - SLACK).ceil();
+    let longest = (q_len / threshold + SLACK).floor();
+    (shortest as i32, longest as i32)
+}
+
+/// `LIKE` pattern of the values starting with `prefix`, which may itself
+/// hold `_`.
+fn like_prefix(prefix: &str) -> String {
+    let mut pattern = String::with_capacity(prefix.len() + 1);
+    for c in prefix.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thresholds_stay_above_the_minimum() {
+        let settings = SearchSettings::default();
+        let params = |mode, threshold| SearchParams {
+            q: KeyName::parse("alice".to_owned()).unwrap(),
+            mode,
+            threshold,
+            limit: None,
+            offset: None,
+        };
+        let threshold = |mode, asked| threshold(&params(mode, asked), &settings).map_err(|e| e.code());
+        assert_eq!(threshold(SearchMode::Levenshtein, None), Ok(0.6));
+        assert_eq!(threshold(SearchMode::Levenshtein, Some(0.4)), Ok(0.4));
+        assert_eq!(threshold(SearchMode::Levenshtein, Some(0.0)), Err("invalid_query"));
+        assert_eq!(threshold(SearchMode::Trigram, Some(0.05)), Err("invalid_query"));
+        assert_eq!(threshold(SearchMode::Trigram, Some(1.5)), Err("invalid_query"));
+        assert_eq!(threshold(SearchMode::Prefix, Some(0.0)), Ok(0.0));
+    }
+
+    #[test]
+    fn levenshtein_lengths_bound_the_score() {
+        assert_eq!(levenshtein_lengths(5, 0.6), (3, 8));
+        assert_eq!(levenshtein_lengths(10, 0.5), (5, 20));
+        assert_eq!(levenshtein_lengths(5, 1.0), (5, 5));
+    }
+
+    #[sqlx::test]
+    async fn levenshtein_search_skips_names_too_far(pool: PgPool) {
+        for name in ["alice", "alic", "alicia", "al", "alice_in_wonderland", "bob"] {
+            crate::testing::register(&pool, name, &["k1"]).await;
+        }
+        let found = find_aliases(&pool, SearchMode::Levenshtein, "alice", 0.6, 10, 0)
+            .await
+            .unwrap();
+        let names: Vec<&str> = found.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["alice", "alic", "alicia"]);
+    }
+
+    #[test]
+    fn escapes_like_wildcards() {
+        assert_eq!(like_prefix("alice"), "alice%");
+        assert_eq!(like_prefix("al_ice.b"), "al\\_ice.b%");
+    }
 }
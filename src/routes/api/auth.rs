@@ -0,0 +1,53 @@
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::header::AUTHORIZATION;
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use sqlx::PgPool;
+
+use crate::domain::key::KeyName;
+use crate::domain::token::SecretToken;
+
+use super::error::ApiError;
+
+/// Token taken from an `Authorization: Bearer <token>` header.
+pub struct Bearer(pub SecretToken);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Bearer {
+    type Rejection = ApiError;
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .and_then(|t| t.trim().parse().ok())
+            .ok_or_else(|| {
+                ApiError::new(StatusCode::UNAUTHORIZED, "missing_token")
+                    .with_detail("expected `Authorization: Bearer <token>`")
+            })?;
+        Ok(Self(token))
+    }
+}
+
+/// Checks that `token` is the owner token of `alias`.
+pub async fn is_owner(pool: &PgPool, alias: &KeyName, token: &SecretToken) -> sqlx::Result<bool> {
+    let row = sqlx::query!(
+        "SELECT 1 as found FROM keymap WHERE name = $1 AND owner_token_hash = $2",
+        alias.name(),
+        token.digest()
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.is_some())
+}
+
+/// Fails with 403 unless `token` is the owner token of `alias`.
+pub async fn require_owner(pool: &PgPool, alias: &KeyName, token: &SecretToken) -> Result<(), ApiError> {
+    if is_owner(pool, alias, token).await? {
+        Ok(())
+    } else {
+        Err(ApiError::new(StatusCode::FORBIDDEN, "not_owner"))
+    }
+}
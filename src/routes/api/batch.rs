@@ -0,0 +1,389 @@
+use std::collections::HashSet;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use sqlx::{Connection, PgPool};
+use utoipa::ToSchema;
+
+use crate::configuration::MessageSettings;
+use crate::push::PushService;
+
+use super::error::{ApiError, FieldError};
+use super::extract::Json;
+use super::messages::{insert_msg_in, rejection, PublishMessage, Published};
+use super::pow::{Action, Payment, StampHeader, Stampable, Stamped, TokenHeader};
+
+const MAX_BATCH: usize = 100;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchMode {
+    /// publish every message or none
+    #[default]
+    Atomic,
+    /// publish the valid messages, report the others
+    Partial,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PublishBatch {
+    #[schema(min_items = 1, max_items = 100)]
+    pub messages: Vec<PublishMessage>,
+    #[serde(default)]
+    pub mode: BatchMode,
+}
+
+/// Outcome of one message of a batch, in request order. Published
+/// messages also carry the members of `Published`.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BatchItem {
+    /// status the message would have gotten from `/publish`, or 424
+    /// when it was valid but not published because another one was rejected
+    pub status: u16,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    #[schema(ignore)]
+    pub published: Option<Published>,
+    /// problem code the message would have gotten from `/publish`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+impl BatchItem {
+    fn rejected(error: ApiError) -> Self {
+        Self {
+            status: error.status().as_u16(),
+            published: None,
+            code: Some(error.code()),
+            error: error.detail().map(str::to_owned),
+            errors: error.errors().to_vec(),
+        }
+    }
+}
+
+impl Stampable for PublishBatch {
+    const ACTION: Action = Action::Publish;
+    fn resources(&self) -> Vec<&str> {
+        self.messages.iter().map(|m| m.recipient.name()).collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BatchResult {
+    pub results: Vec<BatchItem>,
+}
+
+/// Publishes many messages in one transaction. In atomic mode a single
+/// rejected message fails the batch with 422; in partial mode the batch
+/// answers 200 with the outcome of each message.
+#[utoipa::path(
+    post,
+    path = "/publish/batch",
+    params(StampHeader, TokenHeader),
+    request_body = PublishBatch,
+    responses(
+        (status = 201, description = "Every message was published", body = BatchResult),
+        (status = 200, description = "Partial mode: outcome of each message", body = BatchResult),
+        (status = 400, description = "Empty or oversized batch"),
+        (status = 403, description = "Missing or invalid proof of work stamps or tokens"),
+        (status = 409, description = "A stamp or token was already spent"),
+        (status = 422, description = "Atomic mode: a message was rejected, none were published", body = BatchResult),
+    ),
+)]
+#[tracing::instrument(
+    skip_all,
+    fields(size = batch.messages.len(), mode = ?batch.mode),
+    name = "publishing message batch"
+)]
+pub async fn publish_batch(
+    State(pool): State<PgPool>,
+    State(settings): State<MessageSettings>,
+    State(push): State<PushService>,
+    Stamped(batch, payment): Stamped<PublishBatch>,
+) -> Result<Response, ApiError> {
+    if batch.messages.is_empty() || batch.messages.len() > MAX_BATCH {
+        return Err(ApiError::bad_request("invalid_batch")
+            .with_field("messages", format!("between 1 and {MAX_BATCH} messages are required")));
+    }
+    let recipients: Vec<_> = batch.messages.iter().map(|m| m.recipient.clone()).collect();
+    let results = match publish(&pool, &payment, batch.messages, batch.mode, &settings).await? {
+        Outcome::Published(results) => results,
+        Outcome::Rejected(results) => {
+            return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(BatchResult { results })).into_response())
+        }
+    };
+
+    let published = results.iter().filter(|item| item.published.is_some()).count();
+    metrics::counter!("blindchannel_messages_published_total").increment(published as u64);
+    let mut notified = HashSet::new();
+    for (item, recipient) in results.iter().zip(recipients) {
+        // scheduled messages are announced once delivered
+        let delivered = item.published.as_ref().is_some_and(|p| !p.is_scheduled());
+        if delivered && notified.insert(recipient.name().to_owned()) {
+            tokio::spawn(push.clone().notify(recipient));
+        }
+    }
+    let status = match batch.mode {
+        BatchMode::Atomic => StatusCode::CREATED,
+        BatchMode::Partial => StatusCode::OK,
+    };
+    Ok((status, Json(BatchResult { results })).into_response())
+}
+
+/// What became of a batch, with the outcome of each message in request order.
+#[derive(Debug)]
+enum Outcome {
+    /// the valid messages were published
+    Published(Vec<BatchItem>),
+    /// an atomic batch with a rejected message, which published nothing
+    Rejected(Vec<BatchItem>),
+}
+
+async fn publish(
+    pool: &PgPool,
+    payment: &Payment,
+    messages: Vec<PublishMessage>,
+    mode: BatchMode,
+    settings: &MessageSettings,
+) -> Result<Outcome, ApiError> {
+    let rejections = check(pool, &messages, settings).await?;
+    let results: Vec<Option<BatchItem>> = rejections
+        .into_iter()
+        .map(|r| r.map(BatchItem::rejected))
+        .collect();
+    if mode == BatchMode::Atomic && results.iter().any(Option::is_some) {
+        return Ok(Outcome::Rejected(atomic_failure(results)));
+    }
+    insert(pool, payment, messages, results, mode, settings).await
+}
+
+/// Publishes the messages without a result yet in one transaction. Those
+/// rejected on insert, as their recipient, device or blob was removed since
+/// [`check`], are rolled back to a savepoint in partial mode. The payment
+/// is only spent if the transaction commits.
+async fn insert(
+    pool: &PgPool,
+    payment: &Payment,
+    messages: Vec<PublishMessage>,
+    mut results: Vec<Option<BatchItem>>,
+    mode: BatchMode,
+    settings: &MessageSettings,
+) -> Result<Outcome, ApiError> {
+    // lock recipients in the same order in every batch, so concurrent ones can't deadlock
+    let mut order: Vec<usize> = (0..results.len()).filter(|&i| results[i].is_none()).collect();
+    order.sort_by(|&a, &b| messages[a].recipient.name().cmp(messages[b].recipient.name()));
+    let mut messages: Vec<Option<PublishMessage>> = messages.into_iter().map(Some).collect();
+
+    let mut tx = pool.begin().await?;
+    payment.spend(&mut tx).await?;
+    for i in order {
+        let msg = messages[i].take().expect("each message is published once");
+        let inserted = match mode {
+            BatchMode::Atomic => insert_msg_in(&mut tx, msg, settings).await,
+            BatchMode::Partial => {
+                let mut savepoint = tx.begin().await?;
+                let inserted = insert_msg_in(&mut savepoint, msg, settings).await;
+                if inserted.is_ok() {
+                    savepoint.commit().await?;
+                }
+                inserted
+            }
+        };
+        results[i] = Some(match inserted {
+            Ok(published) => BatchItem {
+                status: StatusCode::CREATED.as_u16(),
+                published: Some(published),
+                code: None,
+                error: None,
+                errors: Vec::new(),
+            },
+            Err(e) => {
+                let Some(error) = rejection(&e) else {
+                    tracing::error!("error publishing message batch: {e}");
+                    return Err(ApiError::internal());
+                };
+                let item = BatchItem::rejected(error);
+                if mode == BatchMode::Atomic {
+                    let mut failed = vec![None; results.len()];
+                    failed[i] = Some(item);
+                    return Ok(Outcome::Rejected(atomic_failure(failed)));
+                }
+                item
+            }
+        });
+    }
+    tx.commit().await?;
+    Ok(Outcome::Published(
+        results
+            .into_iter()
+            .map(|r| r.expect("every message has a result"))
+            .collect(),
+    ))
+}
+
+/// Results of an atomic batch that published nothing. `results` holds the
+/// rejected messages, the others are reported as failed dependencies.
+fn atomic_failure(results: Vec<Option<BatchItem>>) -> Vec<BatchItem> {
+    results
+        .into_iter()
+        .map(|r| {
+            r.unwrap_or_else(|| {
+                BatchItem::rejected(
+                    ApiError::new(StatusCode::FAILED_DEPENDENCY, "batch_rejected")
+                        .with_detail("another message was rejected"),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Validates every message up front, returning why each one would be rejected.
+async fn check(
+    pool: &PgPool,
+    messages: &[PublishMessage],
+    settings: &MessageSettings,
+) -> sqlx::Result<Vec<Option<ApiError>>> {
+    let aliases: Vec<&str> = messages.iter().map(|m| m.recipient.name()).collect();
+    let devices: HashSet<(String, String)> = sqlx::query!(
+        "SELECT alias, kid FROM device_keys WHERE alias = ANY($1)",
+        &aliases as &[&str]
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|d| (d.alias, d.kid))
+    .collect();
+    // every alias has at least one device key
+    let known: HashSet<&str> = devices.iter().map(|(alias, _)| alias.as_str()).collect();
+    let hashes: Vec<Vec<u8>> = messages
+        .iter()
+        .flat_map(|m| m.attachments.iter().map(|h| h.as_bytes().to_vec()))
+        .collect();
+    let blobs: HashSet<Vec<u8>> = sqlx::query_scalar!("SELECT hash FROM blobs WHERE hash = ANY($1)", &hashes)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .collect();
+
+    Ok(messages
+        .iter()
+        .map(|m| {
+            let alias = m.recipient.name();
+            if let Err(e) = m.validate(settings) {
+                Some(e)
+            } else if !known.contains(alias) {
+                Some(ApiError::not_found("recipient_not_found").with_detail("unknown recipient"))
+            } else if m
+                .content
+                .keys()
+                .any(|kid| !devices.contains(&(alias.to_owned(), kid.as_str().to_owned())))
+            {
+                Some(ApiError::not_found("device_not_found").with_detail("unknown device key"))
+            } else if m.attachments.iter().any(|h| !blobs.contains(h.as_bytes())) {
+                Some(ApiError::unprocessable("attachment_not_found").with_detail("attachment not uploaded"))
+            } else {
+                None
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{message, register};
+
+    fn statuses(results: &[BatchItem]) -> Vec<u16> {
+        results.iter().map(|item| item.status).collect()
+    }
+
+    async fn count_messages(pool: &PgPool) -> i64 {
+        sqlx::query_scalar!(r#"SELECT count(*) as "count!" FROM messages"#)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    async fn recipient_of(pool: &PgPool, item: &BatchItem) -> String {
+        let id = item.published.as_ref().expect("published").id;
+        sqlx::query_scalar!(r#"SELECT recipient as "recipient!" FROM messages WHERE id = $1"#, id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    fn batch() -> Vec<PublishMessage> {
+        vec![
+            message("carol", &["k1"]),
+            message("bob", &["k1"]),
+            message("alice", &["k1"]),
+            message("alice", &["k2"]),
+        ]
+    }
+
+    #[sqlx::test]
+    async fn partial_batch_reports_each_message(pool: PgPool) {
+        register(&pool, "alice", &["k1"]).await;
+        register(&pool, "bob", &["k1"]).await;
+        let Outcome::Published(results) = publish(&pool, &Payment::Free, batch(), BatchMode::Partial, &Default::default())
+            .await
+            .unwrap()
+        else {
+            panic!("partial batches publish what they can");
+        };
+        assert_eq!(statuses(&results), [404, 201, 201, 404]);
+        assert_eq!(results[0].code, Some("recipient_not_found"));
+        assert_eq!(results[3].code, Some("device_not_found"));
+        // published in recipient order, reported in request order
+        assert_eq!(recipient_of(&pool, &results[1]).await, "bob");
+        assert_eq!(recipient_of(&pool, &results[2]).await, "alice");
+        assert_eq!(count_messages(&pool).await, 2);
+    }
+
+    #[sqlx::test]
+    async fn atomic_batch_with_a_bad_message_publishes_nothing(pool: PgPool) {
+        register(&pool, "alice", &["k1"]).await;
+        register(&pool, "bob", &["k1"]).await;
+        let outcome = publish(&pool, &Payment::Free, batch(), BatchMode::Atomic, &Default::default()).await.unwrap();
+        let Outcome::Rejected(results) = outcome else {
+            panic!("published an atomic batch with bad messages: {outcome:?}");
+        };
+        assert_eq!(statuses(&results), [404, 424, 424, 404]);
+        assert_eq!(count_messages(&pool).await, 0);
+
+        let outcome = publish(&pool, &Payment::Free, batch()[1..3].to_vec(), BatchMode::Atomic, &Default::default())
+            .await
+            .unwrap();
+        assert!(matches!(outcome, Outcome::Published(results) if statuses(&results) == [201, 201]));
+        assert_eq!(count_messages(&pool).await, 2);
+    }
+
+    #[sqlx::test]
+    async fn rejections_on_insert_roll_back(pool: PgPool) {
+        register(&pool, "alice", &["k1"]).await;
+        // as if the device was removed after the check
+        let messages = || vec![message("alice", &["k1"]), message("alice", &["k2"])];
+
+        let outcome = insert(&pool, &Payment::Free, messages(), vec![None, None], BatchMode::Atomic, &Default::default())
+            .await
+            .unwrap();
+        assert!(matches!(outcome, Outcome::Rejected(results) if statuses(&results) == [424, 404]));
+        assert_eq!(count_messages(&pool).await, 0);
+
+        let outcome = insert(&pool, &Payment::Free, messages(), vec![None, None], BatchMode::Partial, &Default::default())
+            .await
+            .unwrap();
+        assert!(matches!(outcome, Outcome::Published(results) if statuses(&results) == [201, 404]));
+        assert_eq!(count_messages(&pool).await, 1);
+        // the rolled back message took no `seq`
+        let last_seq = sqlx::query_scalar!("SELECT last_seq FROM keymap WHERE name = 'alice'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(last_seq, 1);
+    }
+}
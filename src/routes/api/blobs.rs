@@ -0,0 +1,181 @@
+use crate::blobs::{BlobError, BlobStore};
+use crate::domain::blob::BlobHash;
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use super::error::ApiError;
+use super::extract::{Json, Path, Query};
+
+/// Raw bytes of an encrypted blob, or of a chunk of it.
+#[derive(ToSchema)]
+#[schema(value_type = String, format = Binary)]
+pub struct BlobBytes(pub Vec<u8>);
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct NewUpload {
+    /// total size of the encrypted blob, in bytes
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Upload {
+    #[serde(rename = "uploadId")]
+    pub upload_id: Uuid,
+    /// max size of each chunk, in bytes
+    #[serde(rename = "maxChunkSize")]
+    pub max_chunk_size: usize,
+}
+
+/// Starts a chunked upload of an encrypted blob.
+#[utoipa::path(
+    post,
+    path = "/blobs/uploads",
+    request_body = NewUpload,
+    responses(
+        (status = 201, description = "Upload started", body = Upload),
+        (status = 413, description = "The blob exceeds the maximum size"),
+    ),
+)]
+#[tracing::instrument(skip(blobs), name = "starting blob upload")]
+pub async fn start_upload(
+    State(blobs): State<BlobStore>,
+    Json(upload): Json<NewUpload>,
+) -> Result<(StatusCode, Json<Upload>), ApiError> {
+    let upload_id = blobs.start_upload(upload.size).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(Upload {
+            upload_id,
+            max_chunk_size: blobs.max_chunk_size(),
+        }),
+    ))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ChunkParams {
+    /// position of the chunk in the blob, must equal the bytes received so far
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UploadProgress {
+    pub received: u64,
+}
+
+/// Appends a chunk to an upload.
+#[utoipa::path(
+    patch,
+    path = "/blobs/uploads/{uploadId}",
+    params(("uploadId" = Uuid, Path, description = "Id of the upload"), ChunkParams),
+    request_body(content = BlobBytes, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "Chunk stored", body = UploadProgress),
+        (status = 404, description = "No such upload"),
+        (status = 409, description = "The offset is not the number of bytes received so far"),
+        (status = 413, description = "The chunk or blob is too large"),
+    ),
+)]
+#[tracing::instrument(skip(blobs, body), name = "uploading blob chunk")]
+pub async fn upload_chunk(
+    State(blobs): State<BlobStore>,
+    Path(upload_id): Path<Uuid>,
+    Query(params): Query<ChunkParams>,
+    body: Body,
+) -> Result<Json<UploadProgress>, ApiError> {
+    let chunk = axum::body::to_bytes(body, blobs.max_chunk_size())
+        .await
+        .map_err(|_| BlobError::TooLarge)?;
+    let received = blobs.write_chunk(upload_id, params.offset, &chunk).await?;
+    Ok(Json(UploadProgress { received }))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct FinishUpload {
+    /// SHA-256 of the whole encrypted blob
+    pub hash: BlobHash,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Blob {
+    pub hash: BlobHash,
+    pub size: u64,
+}
+
+/// Completes an upload, checking the blob against its hash.
+#[utoipa::path(
+    post,
+    path = "/blobs/uploads/{uploadId}",
+    params(("uploadId" = Uuid, Path, description = "Id of the upload")),
+    request_body = FinishUpload,
+    responses(
+        (status = 201, description = "Blob stored", body = Blob),
+        (status = 404, description = "No such upload"),
+        (status = 409, description = "The upload is incomplete"),
+        (status = 422, description = "The hash does not match the uploaded data"),
+    ),
+)]
+#[tracing::instrument(skip(blobs), name = "finishing blob upload")]
+pub async fn finish_upload(
+    State(blobs): State<BlobStore>,
+    Path(upload_id): Path<Uuid>,
+    Json(finish): Json<FinishUpload>,
+) -> Result<(StatusCode, Json<Blob>), ApiError> {
+    let info = blobs.finish_upload(upload_id, finish.hash).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(Blob {
+            hash: info.hash,
+            size: info.size,
+        }),
+    ))
+}
+
+/// Downloads an encrypted blob.
+#[utoipa::path(
+    get,
+    path = "/blobs/{hash}",
+    params(("hash" = BlobHash, Path, description = "SHA-256 of the blob")),
+    responses(
+        (status = 200, description = "The encrypted blob", content_type = "application/octet-stream", body = BlobBytes),
+        (status = 404, description = "No such blob"),
+    ),
+)]
+#[tracing::instrument(skip(blobs), name = "fetching blob")]
+pub async fn fetch_blob(
+    State(blobs): State<BlobStore>,
+    Path(hash): Path<BlobHash>,
+) -> Result<Response, ApiError> {
+    let data = blobs.read(hash).await?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream"),
+            // content addressed, so it never changes
+            (header::CACHE_CONTROL, "public, max-age=31536000, immutable"),
+        ],
+        data,
+    )
+        .into_response())
+}
+
+impl From<BlobError> for ApiError {
+    fn from(e: BlobError) -> Self {
+        let error = match e {
+            BlobError::NotFound => ApiError::not_found("blob_not_found"),
+            BlobError::TooLarge => ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "blob_too_large"),
+            BlobError::OffsetMismatch { .. } => ApiError::conflict("offset_mismatch"),
+            BlobError::Incomplete { .. } => ApiError::conflict("upload_incomplete"),
+            BlobError::HashMismatch => ApiError::unprocessable("hash_mismatch"),
+            e => {
+                tracing::error!("blob store error: {e}");
+                return ApiError::internal();
+            }
+        };
+        error.with_detail(e.to_string())
+    }
+}
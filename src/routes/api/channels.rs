@@ -0,0 +1,391 @@
+use crate::domain::bytevec::ByteVec;
+use crate::domain::cursor::Cursor;
+use crate::domain::key::KeyName;
+use crate::domain::signing::{post_signing_input, SigningJwk};
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use super::auth::{require_owner, Bearer};
+use super::error::ApiError;
+use super::extract::{Json, Path, Query};
+
+const MAX_TITLE_LEN: usize = 200;
+const MAX_POST_LEN: usize = 64 * 1024;
+/// How far `signedAt` may be from the server clock.
+const MAX_SIGNATURE_AGE: chrono::TimeDelta = chrono::TimeDelta::minutes(5);
+const FEED_ENTRIES: i64 = 20;
+
+#[derive(Debug, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Path)]
+pub struct Params {
+    alias: KeyName,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ChannelInfo {
+    /// human readable channel title, defaults to the alias
+    pub title: Option<String>,
+    /// key verifying the channel's posts
+    #[serde(rename = "signingKey")]
+    pub signing_key: SigningJwk,
+}
+
+/// Creates the alias' channel, or replaces its title and signing key.
+#[utoipa::path(
+    put,
+    path = "/channels/{alias}",
+    params(Params),
+    request_body = ChannelInfo,
+    security(("ownerToken" = [])),
+    responses(
+        (status = 204, description = "Channel set up"),
+        (status = 400, description = "Title too long or signing key too weak"),
+        (status = 401, description = "Missing or malformed owner token"),
+        (status = 403, description = "Owner token does not match the alias"),
+    ),
+)]
+#[tracing::instrument(skip(pool, token, info), name = "setting up channel")]
+pub async fn put_channel(
+    State(pool): State<PgPool>,
+    Path(params): Path<Params>,
+    Bearer(token): Bearer,
+    Json(info): Json<ChannelInfo>,
+) -> Result<StatusCode, ApiError> {
+    let mut error = ApiError::bad_request("invalid_channel");
+    if info.title.as_ref().is_some_and(|t| t.len() > MAX_TITLE_LEN) {
+        error = error.with_field("title", format!("at most {MAX_TITLE_LEN} bytes are allowed"));
+    }
+    if let Err(e) = info.signing_key.validate() {
+        error = error.with_field("signingKey", e.to_string());
+    }
+    if !error.errors().is_empty() {
+        return Err(error);
+    }
+    require_owner(&pool, &params.alias, &token).await?;
+    upsert_channel(&pool, &params.alias, info).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct NewPost {
+    /// public plain text content
+    pub content: String,
+    /// RSASSA-PSS signature over the post signing input
+    pub signature: ByteVec,
+    /// time of signing, with millisecond precision
+    #[serde(rename = "signedAt")]
+    pub signed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Post {
+    pub id: Uuid,
+    pub content: String,
+    pub signature: ByteVec,
+    #[serde(rename = "signedAt")]
+    pub signed_at: DateTime<Utc>,
+    #[serde(rename = "publishedAt")]
+    pub published_at: DateTime<Utc>,
+}
+
+/// Publishes a post signed with the channel's signing key.
+#[utoipa::path(
+    post,
+    path = "/channels/{alias}/posts",
+    params(Params),
+    request_body = NewPost,
+    responses(
+        (status = 201, description = "Post published", body = Post),
+        (status = 400, description = "The signature is older than 5 minutes"),
+        (status = 403, description = "Invalid signature"),
+        (status = 404, description = "No such channel"),
+        (status = 409, description = "The post was already published"),
+        (status = 413, description = "The post is too large"),
+    ),
+)]
+#[tracing::instrument(skip(pool, post), name = "publishing channel post")]
+pub async fn publish_post(
+    State(pool): State<PgPool>,
+    Path(params): Path<Params>,
+    Json(post): Json<NewPost>,
+) -> Result<(StatusCode, Json<Post>), ApiError> {
+    if post.content.len() > MAX_POST_LEN {
+        return Err(ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "post_too_large")
+            .with_field("content", format!("at most {MAX_POST_LEN} bytes are allowed")));
+    }
+    if (Utc::now() - post.signed_at).abs() > MAX_SIGNATURE_AGE {
+        return Err(ApiError::bad_request("stale_signature")
+            .with_field("signedAt", "must be within 5 minutes of the server time"));
+    }
+    let key = get_signing_key(&pool, &params.alias)
+        .await?
+        .ok_or_else(|| ApiError::not_found("channel_not_found"))?;
+    let input = post_signing_input(&params.alias, post.signed_at, &post.content);
+    if !key.verify(&input, &post.signature) {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "invalid_signature"));
+    }
+    match insert_post(&pool, &params.alias, post).await {
+        Ok(post) => Ok((StatusCode::CREATED, Json(post))),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Err(ApiError::conflict("duplicate_post")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListPosts {
+    /// resume after this post
+    pub cursor: Option<Cursor>,
+    /// max posts to fetch
+    #[param(minimum = 1, maximum = 200)]
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PostPage {
+    /// posts, newest first
+    pub posts: Vec<Post>,
+    /// cursor for the next page, absent on the last page
+    #[serde(rename = "nextCursor", skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<Cursor>,
+}
+
+/// Lists the posts of a channel, newest first.
+#[utoipa::path(
+    get,
+    path = "/channels/{alias}/posts",
+    params(Params, ListPosts),
+    responses(
+        (status = 200, description = "A page of posts", body = PostPage),
+        (status = 404, description = "No such channel"),
+    ),
+)]
+#[tracing::instrument(skip(pool), name = "listing channel posts")]
+pub async fn list_posts(
+    State(pool): State<PgPool>,
+    Path(params): Path<Params>,
+    Query(list): Query<ListPosts>,
+) -> Result<Json<PostPage>, ApiError> {
+    let limit = match list.limit {
+        Some(l) => l.clamp(1, 200),
+        None => 20,
+    } as i64;
+    let result = async {
+        if get_channel(&pool, &params.alias).await?.is_none() {
+            return Ok(None);
+        }
+        get_posts(&pool, &params.alias, list.cursor, limit).await.map(Some)
+    };
+    let posts = result
+        .await?
+        .ok_or_else(|| ApiError::not_found("channel_not_found"))?;
+    let next_cursor = match posts.last() {
+        Some(p) if posts.len() as i64 == limit => Some(Cursor::new(p.published_at, p.id)),
+        _ => None,
+    };
+    Ok(Json(PostPage { posts, next_cursor }))
+}
+
+/// Latest posts of a channel as an Atom (RFC 4287) feed.
+#[utoipa::path(
+    get,
+    path = "/channels/{alias}/feed",
+    params(Params),
+    responses(
+        (status = 200, description = "Atom feed of the channel", content_type = "application/atom+xml", body = String),
+        (status = 404, description = "No such channel"),
+    ),
+)]
+#[tracing::instrument(skip(pool), name = "rendering channel feed")]
+pub async fn channel_feed(
+    State(pool): State<PgPool>,
+    Path(params): Path<Params>,
+) -> Result<Response, ApiError> {
+    let result = async {
+        let Some(channel) = get_channel(&pool, &params.alias).await? else {
+            return Ok(None);
+        };
+        let posts = get_posts(&pool, &params.alias, None, FEED_ENTRIES).await?;
+        Ok::<_, sqlx::Error>(Some((channel, posts)))
+    };
+    let (channel, posts) = result
+        .await?
+        .ok_or_else(|| ApiError::not_found("channel_not_found"))?;
+    let body = render_feed(&params.alias, &channel, &posts);
+    Ok(([(header::CONTENT_TYPE, "application/atom+xml; charset=utf-8")], body).into_response())
+}
+
+struct Channel {
+    title: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+async fn upsert_channel(pool: &PgPool, alias: &KeyName, info: ChannelInfo) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"INSERT INTO channels (alias, title, signing_key) VALUES ($1, $2, $3)
+        ON CONFLICT (alias) DO UPDATE SET title = EXCLUDED.title, signing_key = EXCLUDED.signing_key"#,
+        alias.name(),
+        info.title,
+        serde_json::to_value(info.signing_key).unwrap()
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn get_channel(pool: &PgPool, alias: &KeyName) -> sqlx::Result<Option<Channel>> {
+    sqlx::query_as!(
+        Channel,
+        "SELECT title, created_at FROM channels WHERE alias = $1",
+        alias.name()
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+async fn get_signing_key(pool: &PgPool, alias: &KeyName) -> sqlx::Result<Option<SigningJwk>> {
+    let key = sqlx::query_scalar!(
+        r#"SELECT signing_key as "key: sqlx::types::Json<SigningJwk>"
+        FROM channels WHERE alias = $1"#,
+        alias.name()
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(key.map(|k| k.0))
+}
+
+async fn insert_post(pool: &PgPool, alias: &KeyName, post: NewPost) -> sqlx::Result<Post> {
+    let row = sqlx::query!(
+        r#"INSERT INTO channel_posts (id, channel, content, signature, signed_at, published_at)
+        VALUES (gen_random_uuid(), $1, $2, $3, $4, now())
+        RETURNING id, published_at"#,
+        alias.name(),
+        post.content,
+        &post.signature[..],
+        post.signed_at
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(Post {
+        id: row.id,
+        content: post.content,
+        signature: post.signature,
+        signed_at: post.signed_at,
+        published_at: row.published_at,
+    })
+}
+
+async fn get_posts(
+    pool: &PgPool,
+    alias: &KeyName,
+    cursor: Option<Cursor>,
+    limit: i64,
+) -> sqlx::Result<Vec<Post>> {
+    let rows = sqlx::query!(
+        r#"SELECT id, content, signature, signed_at, published_at
+        FROM channel_posts
+        WHERE channel = $1
+            AND ($2::timestamptz IS NULL OR (published_at, id) < ($2, $3))
+        ORDER BY published_at DESC, id DESC
+        LIMIT $4"#,
+        alias.name(),
+        cursor.map(|c| c.at),
+        cursor.map(|c| c.id),
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|r| Post {
+            id: r.id,
+            content: r.content,
+            signature: r.signature.into(),
+            signed_at: r.signed_at,
+            published_at: r.published_at,
+        })
+        .collect())
+}
+
+fn render_feed(alias: &KeyName, channel: &Channel, posts: &[Post]) -> String {
+    use std::fmt::Write;
+    let updated = posts.first().map_or(channel.created_at, |p| p.published_at);
+    let title = channel.title.as_deref().unwrap_or(alias.name());
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="utf-8"?>"#);
+    xml.push_str("\n<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    let _ = writeln!(xml, "  <id>urn:blindchannel:channel:{alias}</id>");
+    let _ = writeln!(xml, "  <title>{}</title>", escape_xml(title));
+    let _ = writeln!(xml, "  <updated>{}</updated>", atom_date(updated));
+    let _ = writeln!(xml, "  <author><name>{alias}</name></author>");
+    for post in posts {
+        let entry_title: String = post.content.lines().next().unwrap_or("").chars().take(80).collect();
+        xml.push_str("  <entry>\n");
+        let _ = writeln!(xml, "    <id>urn:uuid:{}</id>", post.id);
+        let _ = writeln!(xml, "    <title>{}</title>", escape_xml(&entry_title));
+        let _ = writeln!(xml, "    <published>{}</published>", atom_date(post.published_at));
+        let _ = writeln!(xml, "    <updated>{}</updated>", atom_date(post.published_at));
+        let _ = writeln!(xml, "    <content type=\"text\">{}</content>", escape_xml(&post.content));
+        xml.push_str("  </entry>\n");
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+fn atom_date(date: DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Escapes markup and drops characters that XML 1.0 does not allow.
+fn escape_xml(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\t' | '\n' | '\r' => out.push(c),
+            c if c < ' ' || c == '\u{FFFE}' || c == '\u{FFFF}' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_markup_and_control_chars() {
+        assert_eq!(escape_xml("<a href='x'>&\"\u{0}"), "&lt;a href=&apos;x&apos;&gt;&amp;&quot;");
+        assert_eq!(escape_xml("line\nbreak"), "line\nbreak");
+    }
+    #[test]
+    fn feed_contains_entries() {
+        let alias = KeyName::parse("news".into()).unwrap();
+        let channel = Channel {
+            title: Some("News & Updates".into()),
+            created_at: Utc::now(),
+        };
+        let post = Post {
+            id: Uuid::new_v4(),
+            content: "first <post>\nbody".into(),
+            signature: ByteVec::from(vec![1, 2, 3]),
+            signed_at: Utc::now(),
+            published_at: Utc::now(),
+        };
+        let id = post.id;
+        let xml = render_feed(&alias, &channel, &[post]);
+        assert!(xml.contains("<title>News &amp; Updates</title>"));
+        assert!(xml.contains(&format!("<id>urn:uuid:{id}</id>")));
+        assert!(xml.contains("<title>first &lt;post&gt;</title>"));
+    }
+}
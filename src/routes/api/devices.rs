@@ -0,0 +1,315 @@
+use std::collections::BTreeMap;
+
+use crate::domain::bytevec::ByteVec;
+use crate::domain::key::{DeviceKey, Kid, KeyName, PublicJwk};
+use crate::domain::token::SecretToken;
+use crate::keycache::KeyCache;
+use axum::extract::State;
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use utoipa::{IntoParams, ToSchema};
+
+use super::auth::{require_owner, Bearer};
+use super::error::ApiError;
+use super::extract::{Json, Path};
+
+#[derive(Debug, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Path)]
+pub struct AliasParams {
+    alias: KeyName,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Path)]
+pub struct DeviceParams {
+    alias: KeyName,
+    kid: Kid,
+}
+
+/// How long a claimed owner token can be presented back, in seconds.
+const CLAIM_TTL: f64 = 600.0;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct OwnerClaim {
+    /// owner token encrypted with RSA-OAEP to each device key of the alias,
+    /// keyed by `kid`. It has to be decrypted and presented back within
+    /// 10 minutes to become the owner token.
+    #[serde(rename = "ownerToken")]
+    owner_token: BTreeMap<Kid, ByteVec>,
+}
+
+/// Starts claiming an owner token for an alias registered before owner
+/// tokens existed. Only a holder of one of its device keys can read it.
+#[utoipa::path(
+    post,
+    path = "/registry/{alias}/claim",
+    params(AliasParams),
+    responses(
+        (status = 201, description = "Owner token encrypted to the device keys", body = OwnerClaim),
+        (status = 404, description = "No such alias"),
+        (status = 409, description = "The alias already has an owner token"),
+    ),
+)]
+#[tracing::instrument(skip(pool), name = "claiming owner token")]
+pub async fn start_claim(
+    State(pool): State<PgPool>,
+    Path(params): Path<AliasParams>,
+) -> Result<(StatusCode, Json<OwnerClaim>), ApiError> {
+    let claimed = sqlx::query_scalar!(
+        r#"SELECT owner_token_hash IS NOT NULL as "claimed!" FROM keymap WHERE name = $1"#,
+        params.alias.name()
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| ApiError::not_found("alias_not_found"))?;
+    if claimed {
+        return Err(already_claimed());
+    }
+    let keys = sqlx::query!(
+        r#"SELECT kid, public_key as "public_key: sqlx::types::Json<PublicJwk>"
+        FROM device_keys WHERE alias = $1"#,
+        params.alias.name()
+    )
+    .fetch_all(&pool)
+    .await?;
+    let token = SecretToken::generate();
+    let mut owner_token = BTreeMap::new();
+    for key in keys {
+        let kid = Kid::parse(key.kid).map_err(|e| {
+            tracing::error!("stored kid is invalid: {e}");
+            ApiError::internal()
+        })?;
+        let ciphertext = key.public_key.encrypt(token.to_string().as_bytes()).map_err(|e| {
+            tracing::error!("error encrypting owner token to {kid}: {e}");
+            ApiError::internal()
+        })?;
+        owner_token.insert(kid, ciphertext.into());
+    }
+    sqlx::query!(
+        r#"INSERT INTO owner_claims (token_hash, alias, expires_at)
+        VALUES ($1, $2, now() + make_interval(secs => $3))"#,
+        token.digest(),
+        params.alias.name(),
+        CLAIM_TTL
+    )
+    .execute(&pool)
+    .await?;
+    Ok((StatusCode::CREATED, Json(OwnerClaim { owner_token })))
+}
+
+/// Makes a decrypted claim the owner token of its alias.
+#[utoipa::path(
+    put,
+    path = "/registry/{alias}/claim",
+    params(AliasParams),
+    security(("ownerToken" = [])),
+    responses(
+        (status = 204, description = "The token is now the owner token of the alias"),
+        (status = 401, description = "Missing or malformed token"),
+        (status = 403, description = "The token was not claimed for the alias, or expired"),
+        (status = 409, description = "The alias already has an owner token"),
+    ),
+)]
+#[tracing::instrument(skip(pool, token), name = "confirming owner token")]
+pub async fn finish_claim(
+    State(pool): State<PgPool>,
+    Path(params): Path<AliasParams>,
+    Bearer(token): Bearer,
+) -> Result<StatusCode, ApiError> {
+    let mut tx = pool.begin().await?;
+    let claim = sqlx::query!(
+        "DELETE FROM owner_claims WHERE token_hash = $1 AND alias = $2 AND expires_at > now()",
+        token.digest(),
+        params.alias.name()
+    )
+    .execute(&mut *tx)
+    .await?;
+    if claim.rows_affected() == 0 {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "invalid_claim"));
+    }
+    let claimed = sqlx::query!(
+        "UPDATE keymap SET owner_token_hash = $2 WHERE name = $1 AND owner_token_hash IS NULL",
+        params.alias.name(),
+        token.digest()
+    )
+    .execute(&mut *tx)
+    .await?;
+    if claimed.rows_affected() == 0 {
+        return Err(already_claimed());
+    }
+    sqlx::query!("DELETE FROM owner_claims WHERE alias = $1", params.alias.name())
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn already_claimed() -> ApiError {
+    ApiError::conflict("already_claimed").with_detail("the alias already has an owner token")
+}
+
+/// Adds a device key to an alias.
+#[utoipa::path(
+    post,
+    path = "/registry/{alias}/keys",
+    params(AliasParams),
+    request_body = DeviceKey,
+    security(("ownerToken" = [])),
+    responses(
+        (status = 201, description = "Device key added"),
+        (status = 401, description = "Missing or malformed owner token"),
+        (status = 403, description = "Owner token does not match the alias"),
+        (status = 409, description = "A key with this kid already exists"),
+    ),
+)]
+#[tracing::instrument(skip(pool, cache, token, key), fields(kid = %key.kid), name = "adding device key")]
+pub async fn add_device_key(
+    State(pool): State<PgPool>,
+    State(cache): State<KeyCache>,
+    Path(params): Path<AliasParams>,
+    Bearer(token): Bearer,
+    Json(key): Json<DeviceKey>,
+) -> Result<StatusCode, ApiError> {
+    require_owner(&pool, &params.alias, &token).await?;
+    match insert_device_key(&pool, &params.alias, key).await {
+        Ok(()) => {
+            // without waiting for the change notification
+            cache.invalidate(params.alias.name());
+            Ok(StatusCode::CREATED)
+        }
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Err(ApiError::conflict("kid_taken")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Removes a device key along with the copies of messages encrypted to it.
+/// The last key of an alias cannot be removed.
+#[utoipa::path(
+    delete,
+    path = "/registry/{alias}/keys/{kid}",
+    params(DeviceParams),
+    security(("ownerToken" = [])),
+    responses(
+        (status = 204, description = "Device key removed"),
+        (status = 401, description = "Missing or malformed owner token"),
+        (status = 403, description = "Owner token does not match the alias"),
+        (status = 404, description = "No such device key"),
+        (status = 409, description = "The key is the last one of the alias"),
+    ),
+)]
+#[tracing::instrument(skip(pool, cache, token), name = "removing device key")]
+pub async fn remove_device_key(
+    State(pool): State<PgPool>,
+    State(cache): State<KeyCache>,
+    Path(params): Path<DeviceParams>,
+    Bearer(token): Bearer,
+) -> Result<StatusCode, ApiError> {
+    require_owner(&pool, &params.alias, &token).await?;
+    match delete_device_key(&pool, &params.alias, &params.kid).await? {
+        DeleteOutcome::Deleted => {
+            // a revoked key mustn't be handed out a moment longer than necessary
+            cache.invalidate(params.alias.name());
+            Ok(StatusCode::NO_CONTENT)
+        }
+        DeleteOutcome::NotFound => Err(ApiError::not_found("device_not_found")),
+        DeleteOutcome::LastKey => Err(ApiError::conflict("last_device_key")
+            .with_detail("an alias keeps at least one device key")),
+    }
+}
+
+async fn insert_device_key(pool: &PgPool, alias: &KeyName, key: DeviceKey) -> sqlx::Result<()> {
+    sqlx::query!(
+        "INSERT INTO device_keys (alias, kid, public_key) VALUES ($1, $2, $3)",
+        alias.name(),
+        key.kid.as_str(),
+        serde_json::to_value(key.jwk).unwrap()
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+enum DeleteOutcome {
+    Deleted,
+    NotFound,
+    LastKey,
+}
+
+async fn delete_device_key(pool: &PgPool, alias: &KeyName, kid: &Kid) -> sqlx::Result<DeleteOutcome> {
+    let mut tx = pool.begin().await?;
+    // lock the alias' keys so two concurrent removals can't empty it
+    let kids = sqlx::query_scalar!(
+        "SELECT kid FROM device_keys WHERE alias = $1 FOR UPDATE",
+        alias.name()
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    if !kids.iter().any(|k| k == kid.as_str()) {
+        return Ok(DeleteOutcome::NotFound);
+    }
+    if kids.len() == 1 {
+        return Ok(DeleteOutcome::LastKey);
+    }
+    sqlx::query!(
+        "DELETE FROM device_keys WHERE alias = $1 AND kid = $2",
+        alias.name(),
+        kid.as_str()
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(DeleteOutcome::Deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::key::{Algorithm, KeyType, KeyUse, PublicExponent};
+    use crate::routes::api::auth::is_owner;
+    use rsa::traits::PublicKeyParts;
+    use rsa::{Oaep, RsaPrivateKey};
+    use sha2::Sha256;
+
+    #[sqlx::test]
+    async fn legacy_aliases_claim_an_owner_token(pool: PgPool) {
+        let private = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+        let jwk = PublicJwk {
+            e: PublicExponent,
+            n: private.n().to_bytes_be().into(),
+            alg: Algorithm::RsaOaep256,
+            kty: KeyType::Rsa,
+            key_use: KeyUse::Enc,
+        };
+        // registered before owner tokens existed
+        sqlx::query!("INSERT INTO keymap (name) VALUES ('legacy')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query!(
+            "INSERT INTO device_keys (alias, kid, public_key) VALUES ('legacy', 'default', $1)",
+            serde_json::to_value(jwk).unwrap()
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let name = KeyName::parse("legacy".into()).unwrap();
+        let alias = || Path(AliasParams { alias: name.clone() });
+
+        let (status, Json(claim)) = start_claim(State(pool.clone()), alias()).await.unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        let decrypted = private
+            .decrypt(Oaep::new::<Sha256>(), &claim.owner_token[&Kid::default()])
+            .unwrap();
+        let token: SecretToken = String::from_utf8(decrypted).unwrap().parse().unwrap();
+
+        let guessed = finish_claim(State(pool.clone()), alias(), Bearer(SecretToken::generate())).await;
+        assert_eq!(guessed.unwrap_err().status(), StatusCode::FORBIDDEN);
+        let status = finish_claim(State(pool.clone()), alias(), Bearer(token.clone())).await.unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(is_owner(&pool, &name, &token).await.unwrap());
+
+        let again = start_claim(State(pool.clone()), alias()).await;
+        assert!(matches!(again, Err(e) if e.status() == StatusCode::CONFLICT));
+    }
+}
@@ -0,0 +1,564 @@
+//! Dead drops: anonymous, short-lived mailboxes that claim no alias.
+//!
+//! A drop is addressed by a random token its creator hands to senders,
+//! who fetch the drop's public key and publish to it. Messages are read
+//! through a capability URL holding a separate read token. Both tokens are
+//! only returned on creation and redacted from request logs, and the drop
+//! is deleted with its messages once its ttl has passed.
+//!
+//! Creating and publishing to drops is paid for with proof of work or
+//! tokens like publishing to an alias, see [`super::pow`], and drops take a
+//! limited number of messages.
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use chrono::{DateTime, SubsecRound, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json as SqlJson;
+use sqlx::{PgConnection, PgPool};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::configuration::DropSettings;
+use crate::domain::blob::BlobHash;
+use crate::domain::key::{Kid, PublicJwk};
+use crate::domain::token::SecretToken;
+
+use super::error::ApiError;
+use super::extract::{Json, Path, Query};
+use super::messages::{blob_hashes, mark_fetched, rejection, Message, Published, MAX_ATTACHMENTS};
+use super::openapi::V1;
+use super::pow::{Action, StampHeader, Stampable, Stamped, TokenHeader, DROPS_RESOURCE};
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct CreateDrop {
+    /// key senders encrypt their messages to
+    #[serde(rename = "publicKey")]
+    pub public_key: PublicJwk,
+    /// seconds until the drop expires, defaults to `default_ttl_secs`
+    #[serde(rename = "ttlSecs")]
+    pub ttl_secs: Option<u64>,
+}
+impl Stampable for CreateDrop {
+    const ACTION: Action = Action::Drop;
+    fn resources(&self) -> Vec<&str> {
+        vec![DROPS_RESOURCE]
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct CreatedDrop {
+    /// secret addressing the drop, to hand to senders
+    pub token: String,
+    /// secret to read the drop's messages with. It is only returned once.
+    #[serde(rename = "readToken")]
+    pub read_token: String,
+    /// capability URL listing the drop's messages
+    #[serde(rename = "readUrl")]
+    pub read_url: String,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Creates a drop with a fresh pair of tokens.
+#[utoipa::path(
+    post,
+    path = "/drops",
+    params(StampHeader, TokenHeader),
+    request_body = CreateDrop,
+    responses(
+        (status = 201, description = "Drop created", body = CreatedDrop),
+        (status = 400, description = "The ttl is out of range"),
+        (status = 403, description = "Missing or invalid proof of work stamp or token"),
+        (status = 409, description = "The stamp or token was already spent"),
+        (status = 429, description = "Too many requests from this client"),
+    ),
+)]
+#[tracing::instrument(skip_all, name = "creating drop")]
+pub async fn create_drop(
+    State(pool): State<PgPool>,
+    State(settings): State<DropSettings>,
+    Stamped(drop, payment): Stamped<CreateDrop>,
+) -> Result<(StatusCode, Json<CreatedDrop>), ApiError> {
+    let ttl = drop.ttl_secs.unwrap_or(settings.default_ttl_secs);
+    if ttl == 0 || ttl > settings.max_ttl_secs {
+        let max = settings.max_ttl_secs;
+        return Err(ApiError::bad_request("invalid_drop")
+            .with_field("ttlSecs", format!("must be between 1 and {max} seconds")));
+    }
+    // postgres keeps microseconds, answer with what is stored
+    let expires_at = (Utc::now() + TimeDelta::seconds(ttl as i64)).trunc_subsecs(6);
+    let mut tx = pool.begin().await?;
+    payment.spend(&mut tx).await?;
+    let (token, read_token) = insert_drop(&mut tx, &drop.public_key, expires_at).await?;
+    tx.commit().await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedDrop {
+            token: token.to_string(),
+            read_url: format!("{V1}/drops/inbox/{read_token}"),
+            read_token: read_token.to_string(),
+            expires_at,
+        }),
+    ))
+}
+
+/// Stores a drop, returning its token and read token.
+async fn insert_drop(
+    conn: &mut PgConnection,
+    public_key: &PublicJwk,
+    expires_at: DateTime<Utc>,
+) -> sqlx::Result<(SecretToken, SecretToken)> {
+    let token = SecretToken::generate();
+    let read_token = SecretToken::generate();
+    sqlx::query!(
+        r#"INSERT INTO drops (id, token_hash, read_token_hash, public_key, expires_at)
+        VALUES (gen_random_uuid(), $1, $2, $3, $4)"#,
+        token.digest(),
+        read_token.digest(),
+        serde_json::to_value(public_key).unwrap(),
+        expires_at
+    )
+    .execute(conn)
+    .await?;
+    Ok((token, read_token))
+}
+
+fn drop_not_found() -> ApiError {
+    ApiError::not_found("drop_not_found")
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DropInfo {
+    #[serde(rename = "publicKey")]
+    pub public_key: PublicJwk,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Returns the key to encrypt messages to the drop with.
+#[utoipa::path(
+    get,
+    path = "/drops/{token}",
+    params(("token" = String, Path, description = "Token addressing the drop")),
+    responses(
+        (status = 200, description = "Key of the drop", body = DropInfo),
+        (status = 404, description = "No such drop, or it expired"),
+    ),
+)]
+#[tracing::instrument(skip_all, name = "getting drop")]
+pub async fn fetch_drop(
+    State(pool): State<PgPool>,
+    Path(token): Path<String>,
+) -> Result<Json<DropInfo>, ApiError> {
+    let token: SecretToken = token.parse().map_err(|_| drop_not_found())?;
+    let drop = sqlx::query!(
+        r#"SELECT public_key as "public_key: SqlJson<PublicJwk>", expires_at
+        FROM drops WHERE token_hash = $1 AND expires_at > now()"#,
+        token.digest()
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(drop_not_found)?;
+    Ok(Json(DropInfo {
+        public_key: drop.public_key.0,
+        expires_at: drop.expires_at,
+    }))
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct DropMessage {
+    /// content encrypted to the drop's key, encoded in base64
+    pub content: String,
+    /// hashes of previously uploaded encrypted blobs
+    #[serde(default)]
+    #[schema(max_items = 16)]
+    pub attachments: Vec<BlobHash>,
+}
+impl Stampable for DropMessage {
+    const ACTION: Action = Action::Drop;
+    fn resources(&self) -> Vec<&str> {
+        vec![DROPS_RESOURCE]
+    }
+}
+
+/// Publishes a message to a drop. Like messages to an alias, it comes with
+/// a sender token to query its status.
+#[utoipa::path(
+    post,
+    path = "/drops/{token}/messages",
+    params(
+        ("token" = String, Path, description = "Token addressing the drop"),
+        StampHeader,
+        TokenHeader,
+    ),
+    request_body = DropMessage,
+    responses(
+        (status = 201, description = "Message published", body = Published),
+        (status = 400, description = "Invalid message"),
+        (status = 403, description = "Missing or invalid proof of work stamp or token"),
+        (status = 404, description = "No such drop, or it expired"),
+        (status = 409, description = "The stamp or token was already spent, or the drop is full"),
+        (status = 422, description = "An attachment was not uploaded"),
+        (status = 429, description = "Too many requests from this client"),
+    ),
+)]
+#[tracing::instrument(skip_all, name = "publishing to drop")]
+pub async fn publish_to_drop(
+    State(pool): State<PgPool>,
+    State(settings): State<DropSettings>,
+    Path(token): Path<String>,
+    Stamped(msg, payment): Stamped<DropMessage>,
+) -> Result<(StatusCode, Json<Published>), ApiError> {
+    let token: SecretToken = token.parse().map_err(|_| drop_not_found())?;
+    let mut error = ApiError::bad_request("invalid_message");
+    if msg.content.is_empty() {
+        error = error.with_field("content", "must not be empty");
+    }
+    if msg.attachments.len() > MAX_ATTACHMENTS {
+        error = error.with_field("attachments", format!("at most {MAX_ATTACHMENTS} attachments are allowed"));
+    }
+    if !error.errors().is_empty() {
+        return Err(error);
+    }
+    // nothing is spent unless the message is published
+    let mut tx = pool.begin().await?;
+    payment.spend(&mut tx).await?;
+    match insert_drop_msg(&mut tx, &token, msg, settings.max_messages).await {
+        Ok(DropPublish::Published(published)) => {
+            tx.commit().await?;
+            Ok((StatusCode::CREATED, Json(published)))
+        }
+        Ok(DropPublish::NotFound) => Err(drop_not_found()),
+        Ok(DropPublish::Full) => Err(ApiError::conflict("drop_full")
+            .with_detail(format!("drops take at most {} messages", settings.max_messages))),
+        Err(e) => Err(rejection(&e).unwrap_or_else(|| {
+            tracing::error!("error publishing to drop: {e}");
+            ApiError::internal()
+        })),
+    }
+}
+
+#[derive(Debug)]
+enum DropPublish {
+    Published(Published),
+    /// the drop is unknown or expired
+    NotFound,
+    /// the drop took its `max_messages` already
+    Full,
+}
+
+/// Stores the message like one to an alias, with its single copy under the
+/// default kid. Nothing is stored unless it is published.
+async fn insert_drop_msg(
+    tx: &mut PgConnection,
+    token: &SecretToken,
+    msg: DropMessage,
+    max_messages: u32,
+) -> sqlx::Result<DropPublish> {
+    // the row lock is held until commit, keeping `seq` in commit order
+    let Some(drop) = sqlx::query!(
+        r#"UPDATE drops SET last_seq = last_seq + 1
+        WHERE token_hash = $1 AND expires_at > now() AND last_seq < $2
+        RETURNING id, last_seq, expires_at"#,
+        token.digest(),
+        i64::from(max_messages)
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        let exists = sqlx::query_scalar!(
+            r#"SELECT EXISTS (
+                SELECT 1 FROM drops WHERE token_hash = $1 AND expires_at > now()
+            ) as "exists!""#,
+            token.digest()
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        return Ok(if exists { DropPublish::Full } else { DropPublish::NotFound });
+    };
+    let sender_token = SecretToken::generate();
+    let kid = Kid::default();
+    let mut attachments: Vec<Vec<u8>> = msg.attachments.iter().map(|h| h.as_bytes().to_vec()).collect();
+    attachments.sort();
+    attachments.dedup();
+    let inserted = sqlx::query!(
+        r#"INSERT INTO messages (id, drop_id, seq, sent_at, expires_at)
+        VALUES (gen_random_uuid(), $1, $2, now(), $3) RETURNING id, sent_at"#,
+        drop.id,
+        drop.last_seq,
+        drop.expires_at
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"INSERT INTO message_receipts (message_id, sender_token_hash, expires_at)
+        VALUES ($1, $2, $3)"#,
+        inserted.id,
+        sender_token.digest(),
+        drop.expires_at
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO message_copies (message_id, kid, content) VALUES ($1, $2, $3)",
+        inserted.id,
+        kid.as_str(),
+        msg.content
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO message_blobs (message_id, blob_hash) SELECT $1, * FROM UNNEST($2::bytea[])",
+        inserted.id,
+        &attachments
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(DropPublish::Published(Published {
+        id: inserted.id,
+        sent_at: inserted.sent_at,
+        expires_at: Some(drop.expires_at),
+        sender_token: sender_token.to_string(),
+        deliver_after: None,
+        cancel_token: None,
+    }))
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReadDrop {
+    /// only fetch messages with a greater `seq`
+    pub after: Option<i64>,
+    /// max messages to fetch
+    pub limit: Option<u32>,
+}
+
+/// Lists the messages of a drop, in order. This is the capability URL
+/// handed out on creation.
+#[utoipa::path(
+    get,
+    path = "/drops/inbox/{readToken}",
+    params(
+        ("readToken" = String, Path, description = "Read token of the drop"),
+        ReadDrop,
+    ),
+    responses(
+        (status = 200, description = "Messages of the drop", body = Vec<Message>),
+        (status = 404, description = "No such drop, or it expired"),
+    ),
+)]
+#[tracing::instrument(skip_all, name = "reading drop")]
+pub async fn read_drop(
+    State(pool): State<PgPool>,
+    Path(read_token): Path<String>,
+    Query(query): Query<ReadDrop>,
+) -> Result<Json<Vec<Message>>, ApiError> {
+    let read_token: SecretToken = read_token.parse().map_err(|_| drop_not_found())?;
+    let limit = query.limit.map_or(10, |l| l.min(200));
+    get_drop_msgs(&pool, &read_token, query.after.unwrap_or(0), limit.into())
+        .await?
+        .map(Json)
+        .ok_or_else(drop_not_found)
+}
+
+/// Fetches messages after `after`, or `None` if the drop is unknown or expired.
+async fn get_drop_msgs(
+    pool: &PgPool,
+    read_token: &SecretToken,
+    after: i64,
+    limit: i64,
+) -> sqlx::Result<Option<Vec<Message>>> {
+    let Some(drop_id) = sqlx::query_scalar!(
+        "SELECT id FROM drops WHERE read_token_hash = $1 AND expires_at > now()",
+        read_token.digest()
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+    let msgs = sqlx::query!(
+        r#"
+        SELECT m.id, m.seq as "seq!", c.content, m.sent_at, array(
+            SELECT blob_hash FROM message_blobs b WHERE b.message_id = m.id
+        ) as "attachments!"
+        FROM messages m JOIN message_copies c ON c.message_id = m.id
+        WHERE m.drop_id = $1 AND m.seq > $2
+        ORDER BY m.seq LIMIT $3
+        "#,
+        drop_id,
+        after,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    let msgs = msgs
+        .into_iter()
+        .map(|r| {
+            Ok(Message {
+                id: r.id,
+                seq: r.seq,
+                content: r.content,
+                sent_at: r.sent_at,
+                attachments: blob_hashes(&r.attachments)?,
+            })
+        })
+        .collect::<sqlx::Result<Vec<_>>>()?;
+    // the read token is the drop's owner credential
+    mark_fetched(pool, &msgs).await?;
+    Ok(Some(msgs))
+}
+
+/// Deletes a drop and its messages before it expires.
+#[utoipa::path(
+    delete,
+    path = "/drops/inbox/{readToken}",
+    params(("readToken" = String, Path, description = "Read token of the drop")),
+    responses(
+        (status = 204, description = "Drop deleted"),
+        (status = 404, description = "No such drop"),
+    ),
+)]
+#[tracing::instrument(skip_all, name = "deleting drop")]
+pub async fn delete_drop(
+    State(pool): State<PgPool>,
+    Path(read_token): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let read_token: SecretToken = read_token.parse().map_err(|_| drop_not_found())?;
+    if !delete(&pool, &read_token).await? {
+        return Err(drop_not_found());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Deletes the drop with its messages, false if there is none.
+async fn delete(pool: &PgPool, read_token: &SecretToken) -> sqlx::Result<bool> {
+    let deleted = sqlx::query!(
+        "DELETE FROM drops WHERE read_token_hash = $1",
+        read_token.digest()
+    )
+    .execute(pool)
+    .await?;
+    Ok(deleted.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::api::pow::Payment;
+    use crate::testing::public_key;
+
+    async fn create(pool: &PgPool, ttl: TimeDelta) -> (SecretToken, SecretToken) {
+        let mut conn = pool.acquire().await.unwrap();
+        insert_drop(&mut conn, &public_key(), Utc::now() + ttl).await.unwrap()
+    }
+
+    async fn publish(pool: &PgPool, token: &SecretToken, content: &str, max_messages: u32) -> DropPublish {
+        let msg = DropMessage {
+            content: content.to_owned(),
+            attachments: Vec::new(),
+        };
+        let mut tx = pool.begin().await.unwrap();
+        let outcome = insert_drop_msg(&mut tx, token, msg, max_messages).await.unwrap();
+        tx.commit().await.unwrap();
+        outcome
+    }
+
+    async fn read(pool: &PgPool, read_token: &SecretToken, after: i64, limit: i64) -> Option<Vec<String>> {
+        let msgs = get_drop_msgs(pool, read_token, after, limit).await.unwrap()?;
+        Some(msgs.into_iter().map(|m| m.content).collect())
+    }
+
+    async fn count_messages(pool: &PgPool) -> i64 {
+        sqlx::query_scalar!(r#"SELECT count(*) as "count!" FROM messages"#)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn messages_are_read_in_order(pool: PgPool) {
+        let (token, read_token) = create(&pool, TimeDelta::hours(1)).await;
+        for content in ["a", "b", "c"] {
+            assert!(matches!(publish(&pool, &token, content, 10).await, DropPublish::Published(_)));
+        }
+        assert_eq!(read(&pool, &read_token, 0, 10).await.unwrap(), ["a", "b", "c"]);
+        assert_eq!(read(&pool, &read_token, 1, 1).await.unwrap(), ["b"]);
+        // senders can't read
+        assert_eq!(read(&pool, &token, 0, 10).await, None);
+        let unfetched = sqlx::query_scalar!(
+            r#"SELECT count(*) as "count!" FROM message_receipts WHERE fetched_at IS NULL"#
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(unfetched, 0);
+    }
+
+    #[sqlx::test]
+    async fn drops_take_at_most_max_messages(pool: PgPool) {
+        let (token, read_token) = create(&pool, TimeDelta::hours(1)).await;
+        assert!(matches!(publish(&pool, &token, "a", 2).await, DropPublish::Published(_)));
+        assert!(matches!(publish(&pool, &token, "b", 2).await, DropPublish::Published(_)));
+        assert!(matches!(publish(&pool, &token, "c", 2).await, DropPublish::Full));
+        assert_eq!(read(&pool, &read_token, 0, 10).await.unwrap(), ["a", "b"]);
+    }
+
+    #[sqlx::test]
+    async fn expired_drops_are_gone(pool: PgPool) {
+        let (token, read_token) = create(&pool, TimeDelta::seconds(-1)).await;
+        assert!(matches!(publish(&pool, &token, "a", 10).await, DropPublish::NotFound));
+        assert!(matches!(
+            publish(&pool, &SecretToken::generate(), "a", 10).await,
+            DropPublish::NotFound
+        ));
+        assert_eq!(read(&pool, &read_token, 0, 10).await, None);
+        assert_eq!(count_messages(&pool).await, 0);
+    }
+
+    #[sqlx::test]
+    async fn deleting_drops_deletes_their_messages(pool: PgPool) {
+        let (token, read_token) = create(&pool, TimeDelta::hours(1)).await;
+        publish(&pool, &token, "a", 10).await;
+        assert!(!delete(&pool, &token).await.unwrap(), "deleted with the sender's token");
+        assert!(delete(&pool, &read_token).await.unwrap());
+        assert_eq!(count_messages(&pool).await, 0);
+        assert!(!delete(&pool, &read_token).await.unwrap());
+        assert!(matches!(publish(&pool, &token, "b", 10).await, DropPublish::NotFound));
+    }
+
+    #[sqlx::test]
+    async fn rejected_messages_keep_their_payment(pool: PgPool) {
+        let payment = || Payment::Tokens {
+            nonces: vec![vec![1; 32]],
+            key_id: vec![0; 32],
+        };
+        let msg = DropMessage {
+            content: "a".to_owned(),
+            attachments: Vec::new(),
+        };
+        let sent = |token: SecretToken| {
+            publish_to_drop(
+                State(pool.clone()),
+                State(DropSettings::default()),
+                Path(token.to_string()),
+                Stamped(msg.clone(), payment()),
+            )
+        };
+        let spent = || async {
+            sqlx::query_scalar!(r#"SELECT count(*) as "count!" FROM spent_tokens"#)
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+        };
+
+        let unknown = sent(SecretToken::generate()).await;
+        assert!(matches!(unknown, Err(e) if e.status() == StatusCode::NOT_FOUND));
+        assert_eq!(spent().await, 0);
+        let (token, _) = create(&pool, TimeDelta::hours(1)).await;
+        assert!(sent(token.clone()).await.is_ok());
+        assert_eq!(spent().await, 1);
+        let again = sent(token).await;
+        assert!(matches!(again, Err(e) if e.code() == "token_spent"));
+    }
+}
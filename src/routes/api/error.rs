@@ -0,0 +1,180 @@
+//! Errors answered as RFC 7807 `application/problem+json` documents.
+//!
+//! Besides the standard members, every problem has a machine-readable
+//! `code`, and validation failures list the offending fields in `errors`.
+
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// A request field that failed validation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct FieldError {
+    /// path of the field in the request, like `publicKey.n` or `messages[2].recipient`
+    pub field: String,
+    pub message: String,
+}
+
+/// Response extension of errors a retry with the same `Idempotency-Key`
+/// may get past, such as a missing or spent stamp, so they aren't replayed.
+#[derive(Debug, Clone, Copy)]
+pub struct Retryable;
+
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    detail: Option<String>,
+    errors: Vec<FieldError>,
+    retryable: bool,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str) -> Self {
+        Self {
+            status,
+            code,
+            detail: None,
+            errors: Vec::new(),
+            retryable: false,
+        }
+    }
+    /// Human readable explanation of this occurrence of the problem.
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+    pub fn with_field(mut self, field: impl Into<String>, message: impl Into<String>) -> Self {
+        self.errors.push(FieldError {
+            field: field.into(),
+            message: message.into(),
+        });
+        self
+    }
+    /// Marks the error [`Retryable`].
+    pub fn retryable(mut self) -> Self {
+        self.retryable = true;
+        self
+    }
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+    pub fn code(&self) -> &'static str {
+        self.code
+    }
+    pub fn detail(&self) -> Option<&str> {
+        self.detail.as_deref()
+    }
+    pub fn errors(&self) -> &[FieldError] {
+        &self.errors
+    }
+
+    pub fn bad_request(code: &'static str) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code)
+    }
+    pub fn not_found(code: &'static str) -> Self {
+        Self::new(StatusCode::NOT_FOUND, code)
+    }
+    pub fn conflict(code: &'static str) -> Self {
+        Self::new(StatusCode::CONFLICT, code)
+    }
+    pub fn unprocessable(code: &'static str) -> Self {
+        Self::new(StatusCode::UNPROCESSABLE_ENTITY, code)
+    }
+    /// The cause is logged, never shown to the client.
+    pub fn internal() -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error")
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        tracing::error!("database error: {e}");
+        Self::internal()
+    }
+}
+
+/// RFC 7807 problem document describing an error.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct Problem<'a> {
+    #[serde(rename = "type")]
+    #[schema(example = "about:blank")]
+    kind: &'static str,
+    /// reason phrase of the status code
+    title: &'static str,
+    status: u16,
+    /// machine-readable error code, such as `alias_taken` or `invalid_body`
+    code: &'static str,
+    /// human readable explanation of this occurrence of the problem
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+    /// invalid request fields, for validation errors
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    errors: &'a [FieldError],
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let problem = Problem {
+            // problems are told apart by `code`, so the title is the status phrase
+            kind: "about:blank",
+            title: self.status.canonical_reason().unwrap_or("Error"),
+            status: self.status.as_u16(),
+            code: self.code,
+            detail: self.detail.as_deref(),
+            errors: &self.errors,
+        };
+        let body = serde_json::to_vec(&problem).expect("problems serialize");
+        let mut response = (
+            self.status,
+            [(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON))],
+            body,
+        )
+            .into_response();
+        if self.retryable {
+            response.extensions_mut().insert(Retryable);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn renders_problem_json() {
+        let response = ApiError::unprocessable("invalid_body")
+            .with_detail("request body is invalid")
+            .with_field("publicKey.e", "public exponent must be 65537")
+            .into_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], PROBLEM_JSON);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            problem,
+            serde_json::json!({
+                "type": "about:blank",
+                "title": "Unprocessable Entity",
+                "status": 422,
+                "code": "invalid_body",
+                "detail": "request body is invalid",
+                "errors": [{"field": "publicKey.e", "message": "public exponent must be 65537"}],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn omits_empty_members() {
+        let response = ApiError::internal().into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(problem.get("detail").is_none());
+        assert!(problem.get("errors").is_none());
+        assert_eq!(problem["code"], "internal_error");
+    }
+}
@@ -0,0 +1,296 @@
+//! Drop-in replacements for axum's `Json`, `Path` and `Query` extractors
+//! that reject malformed input with a problem listing the invalid fields,
+//! instead of axum's plain-text rejections.
+
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::extract::{FromRequest, FromRequestParts, RawPathParams, Request};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::de::value::MapDeserializer;
+use serde::de::{self, DeserializeOwned, Visitor};
+use serde::Serialize;
+
+use super::error::ApiError;
+
+/// JSON request body, or JSON response.
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned, S: Send + Sync> FromRequest<S> for Json<T> {
+    type Rejection = ApiError;
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let body = json_body(req, state).await?;
+        parse_json(&body).map(Json)
+    }
+}
+
+/// Raw body of a JSON request, for extractors needing more than its value.
+pub(super) async fn json_body<S: Send + Sync>(req: Request, state: &S) -> Result<Bytes, ApiError> {
+    if !is_json(req.headers()) {
+        return Err(ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type")
+            .with_detail("expected `Content-Type: application/json`"));
+    }
+    Bytes::from_request(req, state).await.map_err(|e| {
+        let code = match e.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => "body_too_large",
+            _ => "unreadable_body",
+        };
+        ApiError::new(e.status(), code).with_detail(e.body_text())
+    })
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    essence == "application/json" || (essence.starts_with("application/") && essence.ends_with("+json"))
+}
+
+pub(super) fn parse_json<T: DeserializeOwned>(body: &[u8]) -> Result<T, ApiError> {
+    let mut deserializer = serde_json::Deserializer::from_slice(body);
+    let value = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+        let path = e.path().to_string();
+        let inner = e.into_inner();
+        let message = without_position(&inner);
+        if inner.is_data() {
+            field_error(ApiError::unprocessable("invalid_body"), &path, message)
+        } else {
+            ApiError::bad_request("malformed_json").with_detail(message)
+        }
+    })?;
+    deserializer
+        .end()
+        .map_err(|e| ApiError::bad_request("malformed_json").with_detail(without_position(&e)))?;
+    Ok(value)
+}
+
+/// serde_json appends the position to its messages, which says nothing
+/// once the error is attached to a field.
+fn without_position(e: &serde_json::Error) -> String {
+    let message = e.to_string();
+    match message.rfind(" at line ") {
+        Some(i) if e.line() > 0 => message[..i].to_owned(),
+        _ => message,
+    }
+}
+
+/// Attaches `message` to the field at `path`. Missing fields are reported
+/// by their parent, so their name is taken from the message.
+fn field_error(error: ApiError, path: &str, message: String) -> ApiError {
+    let parent = if path == "." { "" } else { path };
+    let missing = message
+        .strip_prefix("missing field `")
+        .and_then(|m| m.strip_suffix('`'));
+    let field = match missing {
+        Some(name) if parent.is_empty() => name.to_owned(),
+        Some(name) => format!("{parent}.{name}"),
+        None => parent.to_owned(),
+    };
+    error.with_detail("request is invalid").with_field(field, message)
+}
+
+/// Path parameters. Single parameters deserialize to a value, several to a struct.
+pub struct Path<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned, S: Send + Sync> FromRequestParts<S> for Path<T> {
+    type Rejection = ApiError;
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let params = RawPathParams::from_request_parts(parts, state)
+            .await
+            .map_err(|e| ApiError::bad_request("invalid_path").with_detail(e.body_text()))?;
+        let params: Vec<(String, String)> = params
+            .iter()
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect();
+        parse_path(params).map(Path)
+    }
+}
+
+fn parse_path<T: DeserializeOwned>(params: Vec<(String, String)>) -> Result<T, ApiError> {
+    let single = match &params[..] {
+        [(key, _)] => Some(key.clone()),
+        _ => None,
+    };
+    serde_path_to_error::deserialize(PathParams(params)).map_err(|e| {
+        let path = match single {
+            // a single value has no path of its own
+            Some(key) if e.path().iter().next().is_none() => key,
+            _ => e.path().to_string(),
+        };
+        field_error(ApiError::bad_request("invalid_path"), &path, e.into_inner().to_string())
+    })
+}
+
+/// Deserializer over the percent-decoded path parameters of a route.
+struct PathParams(Vec<(String, String)>);
+
+impl<'de> de::Deserializer<'de> for PathParams {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match <[(String, String); 1]>::try_from(self.0) {
+            Ok([(_, value)]) => visitor.visit_string(value),
+            Err(params) => PathParams(params).deserialize_map(visitor),
+        }
+    }
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(MapDeserializer::new(self.0.into_iter()))
+    }
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_map(visitor)
+    }
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct seq tuple tuple_struct enum
+        identifier ignored_any
+    }
+}
+
+/// Query string parameters.
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned, S: Send + Sync> FromRequestParts<S> for Query<T> {
+    type Rejection = ApiError;
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parse_query(parts.uri.query().unwrap_or_default()).map(Query)
+    }
+}
+
+fn parse_query<T: DeserializeOwned>(query: &str) -> Result<T, ApiError> {
+    let deserializer = serde_urlencoded::Deserializer::new(form_urlencoded::parse(query.as_bytes()));
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        let path = e.path().to_string();
+        field_error(ApiError::bad_request("invalid_query"), &path, e.into_inner().to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::api::error::FieldError;
+    use serde::Deserialize;
+    use uuid::Uuid;
+
+    use crate::domain::key::{DeviceKey, Kid, KeyName};
+
+    fn field(field: &str, message: &str) -> FieldError {
+        FieldError {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Register {
+        #[allow(dead_code)]
+        name: KeyName,
+        #[serde(rename = "publicKey")]
+        #[allow(dead_code)]
+        public_key: DeviceKey,
+    }
+
+    #[test]
+    fn json_reports_invalid_fields() {
+        let n = include_str!("../../domain/n");
+        let body = format!(r#"{{"name": "a b", "publicKey": {{"e": "AQAB", "n": "{n}", "alg": "RSA-OAEP-256", "kty": "RSA"}}}}"#);
+        let e = parse_json::<Register>(body.as_bytes()).unwrap_err();
+        assert_eq!(e.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(e.errors(), [field("name", "keyname contains invalid character ` `")]);
+
+        let body = format!(r#"{{"name": "abc", "publicKey": {{"e": "AQAC", "n": "{n}", "alg": "RSA-OAEP-256", "kty": "RSA"}}}}"#);
+        let e = parse_json::<Register>(body.as_bytes()).unwrap_err();
+        assert_eq!(e.errors(), [field("publicKey.e", "public exponent must be 65537")]);
+
+        let e = parse_json::<Register>(br#"{"name": "abc"}"#).unwrap_err();
+        assert_eq!(e.errors(), [field("publicKey", "missing field `publicKey`")]);
+    }
+
+    #[test]
+    fn json_syntax_errors_are_bad_requests() {
+        let e = parse_json::<Register>(b"{\"name\": ").unwrap_err();
+        assert_eq!(e.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(e.code(), "malformed_json");
+        let e = parse_json::<serde_json::Value>(b"{} x").unwrap_err();
+        assert_eq!(e.code(), "malformed_json");
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct DeviceParams {
+        alias: KeyName,
+        kid: Kid,
+    }
+
+    #[test]
+    fn path_params_deserialize() {
+        let id = Uuid::new_v4();
+        let parsed: Uuid = parse_path(vec![("id".into(), id.to_string())]).unwrap();
+        assert_eq!(parsed, id);
+        let params: DeviceParams =
+            parse_path(vec![("alias".into(), "abc".into()), ("kid".into(), "phone".into())]).unwrap();
+        assert_eq!((params.alias.name(), params.kid.as_str()), ("abc", "phone"));
+    }
+
+    #[test]
+    fn path_reports_invalid_params() {
+        let e = parse_path::<KeyName>(vec![("alias".into(), "ab".into())]).unwrap_err();
+        assert_eq!(e.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            e.errors(),
+            [field("alias", "keyname too short, minimum length is 3 characters")]
+        );
+        let e = parse_path::<DeviceParams>(vec![("alias".into(), "abc".into()), ("kid".into(), "a b".into())])
+            .unwrap_err();
+        assert_eq!(e.errors(), [field("kid", "kid contains invalid character ` `")]);
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Page {
+        #[allow(dead_code)]
+        recipient: KeyName,
+        #[allow(dead_code)]
+        limit: Option<u32>,
+    }
+
+    #[test]
+    fn query_reports_invalid_params() {
+        assert!(parse_query::<Page>("recipient=abc&limit=5").is_ok());
+        let e = parse_query::<Page>("recipient=abc&limit=x").unwrap_err();
+        assert_eq!(e.errors()[0].field, "limit");
+        let e = parse_query::<Page>("limit=5").unwrap_err();
+        assert_eq!(e.errors(), [field("recipient", "missing field `recipient`")]);
+    }
+
+    #[test]
+    fn json_content_types() {
+        let headers = |v: &'static str| HeaderMap::from_iter([(header::CONTENT_TYPE, v.parse().unwrap())]);
+        assert!(is_json(&headers("application/json")));
+        assert!(is_json(&headers("application/json; charset=utf-8")));
+        assert!(is_json(&headers("application/merge-patch+json")));
+        assert!(!is_json(&headers("text/plain")));
+        assert!(!is_json(&HeaderMap::new()));
+    }
+}
@@ -0,0 +1,433 @@
+//! `Idempotency-Key` support for POST routes.
+//!
+//! The first request with a key claims it and runs; its response is stored
+//! for the configured window and replayed to retries. Retries with another
+//! body or query are answered with 422, and retries arriving while the
+//! first request still runs with 409.
+//!
+//! Keys are scoped to the path and authorization they are sent with, and
+//! only a hash of them is stored. Responses are stored encrypted under a
+//! key derived from the idempotency key, so they replay verbatim, secrets
+//! such as owner and sender tokens included, while the database can't
+//! tell them.
+
+use axum::body::{to_bytes, Body, Bytes};
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::RequestExt;
+use aes_gcm::aead::Aead;
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+use crate::configuration::IdempotencySettings;
+
+use super::error::{ApiError, Retryable};
+
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+/// Set on responses replayed from an earlier request.
+pub const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+const MAX_KEY_LEN: usize = 255;
+/// Largest response body buffered for replay. Requests are buffered up to
+/// the body limit of their route.
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+const NONCE_LEN: usize = 12;
+
+/// Keys are opaque to the server, typically a UUID. They are limited
+/// to visible ASCII so they can be stored and logged safely.
+fn valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LEN && key.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Hash identifying what a request asks for, beyond its path.
+fn fingerprint(query: Option<&str>, authorization: Option<&[u8]>, body: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for part in [query.unwrap_or_default().as_bytes(), authorization.unwrap_or_default()] {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    hasher.update(body);
+    hasher.finalize().to_vec()
+}
+
+pub async fn idempotent(
+    State(pool): State<PgPool>,
+    State(settings): State<IdempotencySettings>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if request.method() != Method::POST {
+        return next.run(request).await;
+    }
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY) else {
+        return next.run(request).await;
+    };
+    let key = match key.to_str() {
+        Ok(key) if valid_key(key) => key.to_owned(),
+        _ => {
+            return ApiError::bad_request("invalid_idempotency_key")
+                .with_detail(format!("keys are 1 to {MAX_KEY_LEN} visible ASCII characters"))
+                .into_response()
+        }
+    };
+    let (parts, body) = request.with_limited_body().into_parts();
+    let Ok(body) = to_bytes(body, usize::MAX).await else {
+        return ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "body_too_large").into_response();
+    };
+    let authorization = parts.headers.get(header::AUTHORIZATION).map(HeaderValue::as_bytes);
+    let scope = Scope::new(&key, parts.uri.path(), authorization);
+    let hash = fingerprint(parts.uri.query(), authorization, &body);
+
+    match claim(&pool, &scope, &hash, &settings).await {
+        Ok(None) => {}
+        Ok(Some(stored)) if stored.request_hash != hash => {
+            return ApiError::unprocessable("idempotency_key_reused")
+                .with_detail("idempotency key reused with another request")
+                .into_response();
+        }
+        Ok(Some(stored)) => return stored.replay(&scope),
+        Err(e) => return ApiError::from(e).into_response(),
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    if !is_final(&response) {
+        if let Err(e) = release(&pool, &scope).await {
+            tracing::error!("database error: {e}");
+        }
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, MAX_BODY_SIZE).await {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("error buffering response: {e}");
+            if let Err(e) = release(&pool, &scope).await {
+                tracing::error!("database error: {e}");
+            }
+            return ApiError::internal().into_response();
+        }
+    };
+    let content_type = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    if let Err(e) = store(&pool, &scope, parts.status, content_type, &scope.seal(&body)).await {
+        tracing::error!("database error: {e}");
+    }
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Whether retries get the response replayed. Server errors, timeouts,
+/// rate limited requests and [`Retryable`] errors are not final, a retry
+/// should run again.
+fn is_final(response: &Response) -> bool {
+    let status = response.status();
+    !status.is_server_error()
+        && status != StatusCode::REQUEST_TIMEOUT
+        && status != StatusCode::TOO_MANY_REQUESTS
+        && response.extensions().get::<Retryable>().is_none()
+}
+
+/// Secrets derived from a key, together with the path and authorization
+/// it is sent with: the hash it is stored under, and the cipher its
+/// response is stored encrypted with.
+struct Scope {
+    key_hash: Vec<u8>,
+    cipher: Aes256Gcm,
+}
+
+impl Scope {
+    fn new(key: &str, path: &str, authorization: Option<&[u8]>) -> Self {
+        let mut ikm = Vec::new();
+        for part in [key.as_bytes(), path.as_bytes(), authorization.unwrap_or_default()] {
+            ikm.extend_from_slice(&(part.len() as u64).to_be_bytes());
+            ikm.extend_from_slice(part);
+        }
+        let hkdf = Hkdf::<Sha256>::new(None, &ikm);
+        let mut key_hash = [0u8; 32];
+        hkdf.expand(b"idempotency key hash", &mut key_hash)
+            .expect("valid hkdf length");
+        let mut response_key = [0u8; 32];
+        hkdf.expand(b"idempotency response key", &mut response_key)
+            .expect("valid hkdf length");
+        Self {
+            key_hash: key_hash.to_vec(),
+            cipher: Aes256Gcm::new_from_slice(&response_key).expect("valid aes key length"),
+        }
+    }
+
+    /// Encrypts a response body, prefixed with its random nonce.
+    fn seal(&self, body: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), body)
+            .expect("response fits a message");
+        [nonce.as_slice(), &ciphertext].concat()
+    }
+
+    fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+    }
+}
+
+struct Stored {
+    request_hash: Vec<u8>,
+    status_code: Option<i16>,
+    content_type: Option<String>,
+    body: Option<Vec<u8>>,
+}
+
+impl Stored {
+    fn replay(self, scope: &Scope) -> Response {
+        let Some(status) = self
+            .status_code
+            .and_then(|s| StatusCode::from_u16(s as u16).ok())
+        else {
+            return ApiError::conflict("request_in_progress")
+                .with_detail("a request with this idempotency key is in progress")
+                .into_response();
+        };
+        let Some(body) = scope.open(&self.body.unwrap_or_default()) else {
+            tracing::error!("stored response failed to decrypt");
+            return ApiError::internal().into_response();
+        };
+        let mut response = Response::new(Body::from(Bytes::from(body)));
+        *response.status_mut() = status;
+        if let Some(content_type) = self.content_type.and_then(|c| HeaderValue::try_from(c).ok()) {
+            response.headers_mut().insert(header::CONTENT_TYPE, content_type);
+        }
+        response
+            .headers_mut()
+            .insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+        response
+    }
+}
+
+/// Claims `key` for a new request, taking over entries older than the window.
+/// Returns the entry of an earlier request if the key is taken.
+async fn claim(
+    pool: &PgPool,
+    scope: &Scope,
+    hash: &[u8],
+    settings: &IdempotencySettings,
+) -> sqlx::Result<Option<Stored>> {
+    let window = settings.window().as_secs_f64();
+    let claimed = sqlx::query_scalar!(
+        r#"INSERT INTO idempotency_keys (key_hash, request_hash) VALUES ($1, $2)
+        ON CONFLICT (key_hash) DO UPDATE
+        SET request_hash = excluded.request_hash, created_at = now(),
+            status_code = NULL, content_type = NULL, body = NULL
+        WHERE idempotency_keys.created_at < now() - make_interval(secs => $3)
+        RETURNING true as "claimed!""#,
+        scope.key_hash,
+        hash,
+        window
+    )
+    .fetch_optional(pool)
+    .await?;
+    if claimed.is_some() {
+        return Ok(None);
+    }
+    sqlx::query_as!(
+        Stored,
+        r#"SELECT request_hash, status_code, content_type, body
+        FROM idempotency_keys WHERE key_hash = $1"#,
+        scope.key_hash
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+async fn store(
+    pool: &PgPool,
+    scope: &Scope,
+    status: StatusCode,
+    content_type: Option<&str>,
+    body: &[u8],
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"UPDATE idempotency_keys SET status_code = $2, content_type = $3, body = $4
+        WHERE key_hash = $1"#,
+        scope.key_hash,
+        status.as_u16() as i16,
+        content_type,
+        body
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn release(pool: &PgPool, scope: &Scope) -> sqlx::Result<()> {
+    sqlx::query!(
+        "DELETE FROM idempotency_keys WHERE key_hash = $1",
+        scope.key_hash
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::{DefaultBodyLimit, FromRef};
+    use axum::routing::post;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    #[derive(Clone)]
+    struct TestState {
+        pool: PgPool,
+    }
+    impl FromRef<TestState> for PgPool {
+        fn from_ref(state: &TestState) -> Self {
+            state.pool.clone()
+        }
+    }
+    impl FromRef<TestState> for IdempotencySettings {
+        fn from_ref(_: &TestState) -> Self {
+            Self::default()
+        }
+    }
+
+    #[test]
+    fn key_validation() {
+        assert!(valid_key("9b2f6c1e-5d0a-4c7e-8f3b-1a2b3c4d5e6f"));
+        assert!(!valid_key(""));
+        assert!(!valid_key("with space"));
+        assert!(!valid_key(&"k".repeat(MAX_KEY_LEN + 1)));
+    }
+
+    #[test]
+    fn fingerprint_covers_request() {
+        let base = fingerprint(Some("a=1"), Some(b"Bearer x"), b"{}");
+        assert_eq!(base, fingerprint(Some("a=1"), Some(b"Bearer x"), b"{}"));
+        assert_ne!(base, fingerprint(Some("a=2"), Some(b"Bearer x"), b"{}"));
+        assert_ne!(base, fingerprint(Some("a=1"), None, b"{}"));
+        assert_ne!(base, fingerprint(Some("a=1"), Some(b"Bearer x"), b"{ }"));
+        // parts are length prefixed, so bytes can't move between them
+        assert_ne!(fingerprint(Some("ab"), Some(b"c"), b""), fingerprint(Some("a"), Some(b"bc"), b""));
+    }
+
+    #[test]
+    fn retryable_responses_are_not_final() {
+        assert!(is_final(&StatusCode::CREATED.into_response()));
+        assert!(is_final(&ApiError::unprocessable("invalid_body").into_response()));
+        assert!(!is_final(&StatusCode::INTERNAL_SERVER_ERROR.into_response()));
+        assert!(!is_final(&StatusCode::REQUEST_TIMEOUT.into_response()));
+        assert!(!is_final(&StatusCode::TOO_MANY_REQUESTS.into_response()));
+        assert!(!is_final(&ApiError::conflict("stamp_spent").retryable().into_response()));
+    }
+
+    #[test]
+    fn responses_are_sealed_to_their_scope() {
+        let scope = Scope::new("k", "/publish", Some(b"Bearer x"));
+        let sealed = scope.seal(b"{\"senderToken\":\"s\"}");
+        assert_eq!(scope.open(&sealed).unwrap(), b"{\"senderToken\":\"s\"}");
+        for other in [
+            Scope::new("k2", "/publish", Some(b"Bearer x")),
+            Scope::new("k", "/register", Some(b"Bearer x")),
+            Scope::new("k", "/publish", None),
+        ] {
+            assert_ne!(other.key_hash, scope.key_hash);
+            assert_eq!(other.open(&sealed), None);
+        }
+        // parts are length prefixed, so bytes can't move between them
+        assert_ne!(Scope::new("ab", "/c", None).key_hash, Scope::new("a", "b/c", None).key_hash);
+    }
+
+    #[sqlx::test]
+    async fn bodies_are_buffered_up_to_the_route_limit(pool: PgPool) {
+        let app = Router::new()
+            .route("/", post(|body: Bytes| async move { body }))
+            .layer(axum::middleware::from_fn_with_state(TestState { pool: pool.clone() }, idempotent))
+            .layer(DefaultBodyLimit::max(8));
+        let request = |body: &'static str| {
+            Request::post("/")
+                .header(IDEMPOTENCY_KEY, "k")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let stored = || async {
+            sqlx::query_scalar!(r#"SELECT count(*) as "count!" FROM idempotency_keys"#)
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request("far too long")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(stored().await, 0);
+        let response = app.oneshot(request("short")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(stored().await, 1);
+    }
+
+    #[sqlx::test]
+    async fn responses_replay_verbatim(pool: PgPool) {
+        let app = Router::new()
+            .route("/", post(|| async { r#"{"ownerToken":"secret"}"# }))
+            .layer(axum::middleware::from_fn_with_state(TestState { pool: pool.clone() }, idempotent));
+        let request = |authorization: &'static str| {
+            Request::post("/")
+                .header(IDEMPOTENCY_KEY, "k")
+                .header(header::AUTHORIZATION, authorization)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let body = |response: Response| async {
+            to_bytes(response.into_body(), usize::MAX).await.unwrap()
+        };
+
+        let first = app.clone().oneshot(request("Bearer a")).await.unwrap();
+        assert!(!first.headers().contains_key(IDEMPOTENT_REPLAYED));
+        let replayed = app.clone().oneshot(request("Bearer a")).await.unwrap();
+        assert!(replayed.headers().contains_key(IDEMPOTENT_REPLAYED));
+        assert_eq!(body(replayed).await, body(first).await);
+        // the same key sent with other credentials is another request
+        let other = app.oneshot(request("Bearer b")).await.unwrap();
+        assert!(!other.headers().contains_key(IDEMPOTENT_REPLAYED));
+
+        let stored = sqlx::query_scalar!("SELECT body FROM idempotency_keys")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 2);
+        for body in stored.into_iter().flatten() {
+            assert!(!body.windows(6).any(|w| w == b"secret"), "response stored in the clear");
+        }
+    }
+
+    #[sqlx::test]
+    async fn timeouts_release_their_key(pool: PgPool) {
+        let slow = Router::new().route(
+            "/",
+            post(|| async { tokio::time::sleep(std::time::Duration::from_secs(5)).await }),
+        );
+        let app = crate::hardening::with_timeout(slow, std::time::Duration::from_millis(10))
+            .layer(axum::middleware::from_fn_with_state(TestState { pool: pool.clone() }, idempotent));
+        let request = Request::post("/")
+            .header(IDEMPOTENCY_KEY, "k")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+        let stored = sqlx::query_scalar!(r#"SELECT count(*) as "count!" FROM idempotency_keys"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(stored, 0);
+    }
+}
@@ -0,0 +1,286 @@
+use std::convert::Infallible;
+use std::time::Duration;
+
+use axum::extract::ws::rejection::WebSocketUpgradeRejection;
+use axum::extract::ws::{close_code, CloseFrame, Message as WsMessage, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::Response;
+use serde::Deserialize;
+use sqlx::PgPool;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use utoipa::IntoParams;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::domain::key::{Kid, KeyName};
+use crate::domain::token::SecretToken;
+use crate::events::{MailboxEvents, Subscription};
+
+use super::auth::{is_owner, require_owner, Bearer};
+use super::error::ApiError;
+use super::extract::Query;
+use super::messages::{get_sent_msgs, mark_fetched, Message};
+
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// Connections that haven't answered a ping for this long are dropped.
+const PONG_TIMEOUT: Duration = Duration::from_secs(75);
+const BATCH_SIZE: i64 = 100;
+
+/// Follows the mailbox of a device from a cursor: first the messages
+/// already stored after it, then new ones as they are published.
+struct Follower {
+    pool: PgPool,
+    subscription: Subscription,
+    recipient: KeyName,
+    kid: Kid,
+    cursor: i64,
+    /// whether messages may be waiting after the cursor
+    pending: bool,
+}
+
+impl Follower {
+    /// Subscribes before anything is queried, so nothing published
+    /// in between is missed.
+    async fn new(pool: PgPool, events: &MailboxEvents, recipient: KeyName, kid: Kid, cursor: i64) -> Self {
+        Self {
+            pool,
+            subscription: events.watch(recipient.clone()).await,
+            recipient,
+            kid,
+            cursor,
+            pending: true,
+        }
+    }
+
+    /// Waits for the next messages after the cursor and advances past them.
+    /// Cancelling it doesn't lose messages.
+    async fn next(&mut self) -> sqlx::Result<Vec<Message>> {
+        loop {
+            if self.pending {
+                let batch =
+                    get_sent_msgs(&self.pool, &self.recipient, &self.kid, self.cursor, BATCH_SIZE).await?;
+                // followers are only made for the owner
+                mark_fetched(&self.pool, &batch).await?;
+                self.pending = batch.len() as i64 == BATCH_SIZE;
+                if let Some(last) = batch.last() {
+                    self.cursor = last.seq;
+                    return Ok(batch);
+                }
+            }
+            self.subscription.changed().await;
+            self.pending = true;
+        }
+    }
+}
+
+/// First frame a client sends after connecting.
+/// The token isn't passed in the URL, so it never ends up in access logs.
+#[derive(Deserialize)]
+struct Hello {
+    alias: KeyName,
+    /// device whose copies are streamed
+    #[serde(default)]
+    kid: Kid,
+    /// owner token of the alias
+    token: String,
+    /// resume after the message with this `seq`
+    #[serde(default)]
+    cursor: i64,
+}
+
+/// Streams the messages of an alias as JSON text frames, starting after
+/// the cursor given in the first frame and then live as they are published.
+///
+/// The first frame must be a JSON text frame `{"alias", "kid", "token",
+/// "cursor"}` sent within 10 seconds, `token` being the owner token of the alias.
+#[utoipa::path(
+    get,
+    path = "/mailbox/ws",
+    responses(
+        (status = 101, description = "Switching to the WebSocket protocol"),
+        (status = 400, description = "Not a WebSocket upgrade request"),
+    ),
+)]
+#[tracing::instrument(skip_all, name = "mailbox websocket")]
+pub async fn mailbox_ws(
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+    State(pool): State<PgPool>,
+    State(events): State<MailboxEvents>,
+    State(shutdown): State<CancellationToken>,
+) -> Result<Response, ApiError> {
+    let ws = ws.map_err(|e| ApiError::new(e.status(), "websocket_required").with_detail(e.body_text()))?;
+    Ok(ws.on_upgrade(move |socket| session(socket, pool, events, shutdown)))
+}
+
+async fn session(mut socket: WebSocket, pool: PgPool, events: MailboxEvents, shutdown: CancellationToken) {
+    let hello = match authenticate(&mut socket, &pool).await {
+        Ok(hello) => hello,
+        Err(reason) => {
+            close(&mut socket, close_code::POLICY, reason).await;
+            return;
+        }
+    };
+    let mut follower = Follower::new(pool, &events, hello.alias, hello.kid, hello.cursor).await;
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    let mut last_pong = Instant::now();
+    loop {
+        tokio::select! {
+            batch = follower.next() => {
+                let batch = match batch {
+                    Ok(batch) => batch,
+                    Err(e) => {
+                        tracing::error!("error getting messages: {e}");
+                        close(&mut socket, close_code::ERROR, "internal error").await;
+                        return;
+                    }
+                };
+                metrics::counter!("blindchannel_messages_fetched_total", "via" => "websocket")
+                    .increment(batch.len() as u64);
+                for msg in &batch {
+                    let frame = serde_json::to_string(msg).unwrap();
+                    if socket.send(WsMessage::Text(frame)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            _ = shutdown.cancelled() => {
+                close(&mut socket, close_code::AWAY, "server shutting down").await;
+                return;
+            }
+            _ = ping.tick() => {
+                if last_pong.elapsed() > PONG_TIMEOUT {
+                    close(&mut socket, close_code::AWAY, "keepalive timeout").await;
+                    return;
+                }
+                if socket.send(WsMessage::Ping(Vec::new())).await.is_err() {
+                    return;
+                }
+            }
+            frame = socket.recv() => match frame {
+                Some(Ok(WsMessage::Pong(_))) => last_pong = Instant::now(),
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+async fn authenticate(socket: &mut WebSocket, pool: &PgPool) -> Result<Hello, &'static str> {
+    let frame = match tokio::time::timeout(AUTH_TIMEOUT, socket.recv()).await {
+        Ok(Some(Ok(WsMessage::Text(frame)))) => frame,
+        Ok(_) => return Err("expected hello frame"),
+        Err(_) => return Err("hello frame timeout"),
+    };
+    let hello: Hello = serde_json::from_str(&frame).map_err(|_| "invalid hello frame")?;
+    let token: SecretToken = hello.token.parse().map_err(|_| "invalid token")?;
+    match is_owner(pool, &hello.alias, &token).await {
+        Ok(true) => Ok(hello),
+        Ok(false) => Err("invalid token"),
+        Err(e) => {
+            tracing::error!("database error: {e}");
+            Err("internal error")
+        }
+    }
+}
+
+async fn close(socket: &mut WebSocket, code: u16, reason: &'static str) {
+    let frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    let _ = socket.send(WsMessage::Close(Some(frame))).await;
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StreamParams {
+    alias: KeyName,
+    /// device whose copies are streamed
+    #[serde(default)]
+    #[param(required = false)]
+    kid: Kid,
+    /// resume after the message with this `seq`, overridden by `Last-Event-ID`
+    cursor: Option<i64>,
+}
+
+/// Streams the messages of an alias as Server-Sent Events whose `id` is the
+/// message `seq`, so reconnecting clients resume through `Last-Event-ID`.
+#[utoipa::path(
+    get,
+    path = "/mailbox/events",
+    params(
+        StreamParams,
+        ("Last-Event-ID" = Option<i64>, Header, description = "Resume after the message with this `seq`"),
+    ),
+    security(("ownerToken" = [])),
+    responses(
+        (
+            status = 200,
+            description = "`message` events whose data is a message",
+            content_type = "text/event-stream",
+            body = Message,
+        ),
+        (status = 400, description = "Invalid `Last-Event-ID`"),
+        (status = 401, description = "Missing or malformed owner token"),
+        (status = 403, description = "Owner token does not match the alias"),
+    ),
+)]
+#[tracing::instrument(skip(pool, events, shutdown, token, headers), name = "mailbox event stream")]
+pub async fn mailbox_events(
+    State(pool): State<PgPool>,
+    State(events): State<MailboxEvents>,
+    State(shutdown): State<CancellationToken>,
+    Bearer(token): Bearer,
+    headers: HeaderMap,
+    Query(params): Query<StreamParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let cursor = match headers.get("last-event-id") {
+        Some(id) => id
+            .to_str()
+            .ok()
+            .and_then(|id| id.trim().parse().ok())
+            .ok_or_else(|| {
+                ApiError::bad_request("invalid_last_event_id")
+                    .with_detail("`Last-Event-ID` must be the `seq` of a message")
+            })?,
+        None => params.cursor.unwrap_or(0),
+    };
+    require_owner(&pool, &params.alias, &token).await?;
+    let mut follower = Follower::new(pool, &events, params.alias, params.kid, cursor).await;
+    let (tx, rx) = tokio::sync::mpsc::channel::<Message>(BATCH_SIZE as usize);
+    tokio::spawn(async move {
+        loop {
+            let batch = tokio::select! {
+                batch = follower.next() => batch,
+                // stop following once the client is gone, ending the stream when shutting down
+                _ = tx.closed() => return,
+                _ = shutdown.cancelled() => return,
+            };
+            let batch = match batch {
+                Ok(batch) => batch,
+                Err(e) => {
+                    tracing::error!("error getting messages: {e}");
+                    return;
+                }
+            };
+            metrics::counter!("blindchannel_messages_fetched_total", "via" => "sse").increment(batch.len() as u64);
+            for msg in batch {
+                if tx.send(msg).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    let stream = ReceiverStream::new(rx).map(|msg| {
+        Ok(Event::default()
+            .id(msg.seq.to_string())
+            .event("message")
+            .json_data(&msg)
+            .unwrap())
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
@@ -1,101 +1,673 @@
 use axum::extract::State;
 use axum::http::StatusCode;
-use axum::Json;
-use axum::extract::Query;
-use chrono::{DateTime, Utc};
-use sqlx::PgPool;
+use chrono::{DateTime, SubsecRound, Utc};
+use sqlx::{PgConnection, PgPool};
 use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
 
-use crate::domain::key::KeyName;
+use crate::configuration::MessageSettings;
+use crate::domain::blob::BlobHash;
+use crate::events::{channel_name, MailboxEvents};
+use crate::push::PushService;
+use crate::domain::key::{Kid, KeyName};
+use crate::domain::token::SecretToken;
+use super::auth::{require_owner, Bearer};
+use super::error::ApiError;
+use super::extract::{Json, Path, Query};
+use super::pow::{Action, StampHeader, Stampable, Stamped, TokenHeader};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) const MAX_ATTACHMENTS: usize = 16;
+/// Longest a long-polling request may wait, in seconds.
+const MAX_WAIT: u64 = 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct PublishMessage {
-    /// base64 encoded string
-    pub content: String,
+    /// base64 encoded ciphertext for each recipient device, keyed by `kid`
+    pub content: BTreeMap<Kid, String>,
     /// recipient name
     pub recipient: KeyName,
+    /// hashes of previously uploaded encrypted blobs
+    #[serde(default)]
+    #[schema(max_items = 16)]
+    pub attachments: Vec<BlobHash>,
+    /// keep the message hidden from the recipient until this time
+    #[serde(default, rename = "deliverAfter")]
+    pub deliver_after: Option<DateTime<Utc>>,
+}
+impl PublishMessage {
+    /// Checks the message has content, not too many attachments,
+    /// and isn't scheduled too far ahead.
+    pub fn validate(&self, settings: &MessageSettings) -> Result<(), ApiError> {
+        let mut error = ApiError::bad_request("invalid_message");
+        if self.content.is_empty() {
+            error = error.with_field("content", "at least one device copy is required");
+        }
+        if self.attachments.len() > MAX_ATTACHMENTS {
+            error = error.with_field("attachments", format!("at most {MAX_ATTACHMENTS} attachments are allowed"));
+        }
+        if self
+            .deliver_after
+            .is_some_and(|at| at > Utc::now() + settings.max_schedule())
+        {
+            let days = settings.max_schedule_days;
+            error = error.with_field("deliverAfter", format!("at most {days} days ahead are allowed"));
+        }
+        if error.errors().is_empty() {
+            Ok(())
+        } else {
+            Err(error)
+        }
+    }
+}
+impl Stampable for PublishMessage {
+    const ACTION: Action = Action::Publish;
+    fn resources(&self) -> Vec<&str> {
+        vec![self.recipient.name()]
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Published {
+    pub id: Uuid,
+    #[serde(rename = "sentAt")]
+    pub sent_at: DateTime<Utc>,
+    /// absent if messages don't expire
+    #[serde(rename = "expiresAt", skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// secret to query the message status with. It is only returned once.
+    #[serde(rename = "senderToken")]
+    pub sender_token: String,
+    /// time a scheduled message becomes visible to the recipient
+    #[serde(rename = "deliverAfter", skip_serializing_if = "Option::is_none")]
+    pub deliver_after: Option<DateTime<Utc>>,
+    /// secret to cancel a scheduled message before its delivery.
+    /// It is only returned once.
+    #[serde(rename = "cancelToken", skip_serializing_if = "Option::is_none")]
+    pub cancel_token: Option<String>,
+}
+impl Published {
+    pub fn is_scheduled(&self) -> bool {
+        self.deliver_after.is_some()
+    }
 }
 
-#[tracing::instrument(skip(pool, msg), name = "publishing new message")]
+/// Publishes a message to an alias, encrypted to each of its device keys.
+#[utoipa::path(
+    post,
+    path = "/publish",
+    params(StampHeader, TokenHeader),
+    request_body = PublishMessage,
+    responses(
+        (status = 201, description = "Message published", body = Published),
+        (status = 400, description = "Invalid message"),
+        (status = 403, description = "Missing or invalid proof of work stamp or token"),
+        (status = 404, description = "No such recipient or device"),
+        (status = 409, description = "The stamp or token was already spent"),
+        (status = 422, description = "An attachment was not uploaded"),
+    ),
+)]
+#[tracing::instrument(skip(pool, settings, push, msg), name = "publishing new message")]
 pub async fn publish_message(
     State(pool): State<PgPool>,
-    Json(msg): Json<PublishMessage>,
-) -> StatusCode {
-    match insert_msg(&pool, msg).await {
-        Ok(()) => StatusCode::CREATED,
-        Err(sqlx::Error::Database(err)) if err.is_foreign_key_violation() => {
-            StatusCode::NOT_FOUND
-        }
-        Err(e) => {
+    State(settings): State<MessageSettings>,
+    State(push): State<PushService>,
+    Stamped(msg, payment): Stamped<PublishMessage>,
+) -> Result<(StatusCode, Json<Published>), ApiError> {
+    msg.validate(&settings)?;
+    let recipient = msg.recipient.clone();
+    let mut tx = pool.begin().await?;
+    payment.spend(&mut tx).await?;
+    let published = insert_msg_in(&mut tx, msg, &settings).await.map_err(|e| {
+        rejection(&e).unwrap_or_else(|| {
             tracing::error!("error publishing message: {e}");
-            StatusCode::INTERNAL_SERVER_ERROR
+            ApiError::internal()
+        })
+    })?;
+    tx.commit().await?;
+    metrics::counter!("blindchannel_messages_published_total").increment(1);
+    // scheduled messages are announced once delivered
+    if !published.is_scheduled() {
+        tokio::spawn(push.notify(recipient));
+    }
+    Ok((StatusCode::CREATED, Json(published)))
+}
+
+/// Errors caused by the published message rather than the server: an
+/// unknown recipient or device is 404, an attachment never uploaded 422.
+pub(crate) fn rejection(err: &sqlx::Error) -> Option<ApiError> {
+    match err {
+        sqlx::Error::RowNotFound => Some(ApiError::not_found("recipient_not_found")),
+        sqlx::Error::Database(err) if err.constraint() == Some("message_blobs_blob_hash_fkey") => {
+            Some(ApiError::unprocessable("attachment_not_found"))
         }
+        sqlx::Error::Database(err) if err.constraint() == Some("message_copies_recipient_kid_fkey") => {
+            Some(ApiError::not_found("device_not_found"))
+        }
+        sqlx::Error::Database(err) if err.is_foreign_key_violation() => {
+            Some(ApiError::not_found("recipient_not_found"))
+        }
+        _ => None,
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct GetMessages {
     /// recipient name
     pub recipient: KeyName,
+    /// device whose copies are fetched
+    #[serde(default)]
+    #[param(required = false)]
+    pub kid: Kid,
+    /// only fetch messages with a greater `seq`
+    pub after: Option<i64>,
     /// max messages to fetch
+    #[param(maximum = 200)]
     pub limit: Option<u32>,
+    /// seconds to wait for a message when there is none, capped at 60
+    pub wait: Option<u64>,
 }
 
-#[tracing::instrument(skip(pool), name = "get published messages")]
+/// Fetches messages of an alias in mailbox order, optionally long-polling
+/// until one arrives. Senders only see their messages as fetched once the
+/// owner of the alias fetched them, with its owner token.
+#[utoipa::path(
+    get,
+    path = "/messages",
+    params(GetMessages),
+    security((), ("ownerToken" = [])),
+    responses(
+        (status = 200, description = "Messages after the cursor", body = Vec<Message>),
+        (status = 403, description = "Owner token does not match the alias"),
+    ),
+)]
+#[tracing::instrument(skip(pool, events, shutdown, owner), name = "get published messages")]
 pub async fn get_messages(
     State(pool): State<PgPool>,
+    State(events): State<MailboxEvents>,
+    State(shutdown): State<CancellationToken>,
+    owner: Option<Bearer>,
     Query(get_msg): Query<GetMessages>,
-) -> Result<Json<Vec<Message>>, StatusCode> {
-    match get_sent_msgs(&pool, get_msg).await {
-        Ok(msgs) => Ok(Json(msgs)),
-        Err(sqlx::Error::RowNotFound) => {
-            Err(StatusCode::NOT_FOUND)
+) -> Result<Json<Vec<Message>>, ApiError> {
+    if let Some(Bearer(token)) = &owner {
+        require_owner(&pool, &get_msg.recipient, token).await?;
+    }
+    let limit = match get_msg.limit {
+        Some(l) => l.min(200),
+        None => 10,
+    };
+    let after = get_msg.after.unwrap_or(0);
+    let wait = Duration::from_secs(get_msg.wait.unwrap_or(0).min(MAX_WAIT));
+    let fetch = || get_sent_msgs(&pool, &get_msg.recipient, &get_msg.kid, after, limit.into());
+    let result = async {
+        if wait.is_zero() {
+            return fetch().await;
         }
-        Err(e) => {
-            tracing::error!("error getting messages: {e}");
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        // watch before querying, so a message published in between still wakes us
+        let mut subscription = events.watch(get_msg.recipient.clone()).await;
+        let deadline = Instant::now() + wait;
+        loop {
+            let msgs = fetch().await?;
+            if !msgs.is_empty() {
+                return Ok(msgs);
+            }
+            tokio::select! {
+                changed = tokio::time::timeout_at(deadline, subscription.changed()) => {
+                    if changed.is_err() {
+                        return Ok(msgs);
+                    }
+                }
+                // answer now rather than hold up the shutdown
+                _ = shutdown.cancelled() => return Ok(msgs),
+            }
         }
+    };
+    let msgs = result.await?;
+    if owner.is_some() {
+        mark_fetched(&pool, &msgs).await?;
     }
+    metrics::counter!("blindchannel_messages_fetched_total", "via" => "poll").increment(msgs.len() as u64);
+    Ok(Json(msgs))
 }
 
-async fn insert_msg(pool: &PgPool, msg: PublishMessage) -> sqlx::Result<()> {
+/// Deletes a message, given the owner token of its recipient.
+/// Messages of other aliases are reported as not found.
+#[utoipa::path(
+    delete,
+    path = "/messages/{id}",
+    params(("id" = Uuid, Path, description = "Message id")),
+    security(("ownerToken" = [])),
+    responses(
+        (status = 204, description = "Message deleted"),
+        (status = 401, description = "Missing or malformed owner token"),
+        (status = 404, description = "No such message for this owner"),
+    ),
+)]
+#[tracing::instrument(skip(pool, token), name = "deleting message")]
+pub async fn delete_message(
+    State(pool): State<PgPool>,
+    Path(id): Path<Uuid>,
+    Bearer(token): Bearer,
+) -> Result<StatusCode, ApiError> {
+    if delete_msg(&pool, id, &token).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::not_found("message_not_found"))
+    }
+}
+
+async fn delete_msg(pool: &PgPool, id: Uuid, token: &SecretToken) -> sqlx::Result<bool> {
+    let mut tx = pool.begin().await?;
+    let deleted = sqlx::query!(
+        r#"DELETE FROM messages m USING keymap k
+        WHERE m.id = $1 AND k.name = m.recipient AND k.owner_token_hash = $2
+            AND m.seq IS NOT NULL"#,
+        id,
+        token.digest()
+    )
+    .execute(&mut *tx)
+    .await?;
+    if deleted.rows_affected() == 0 {
+        return Ok(false);
+    }
     sqlx::query!(
-        r#"INSERT INTO MESSAGES (id, recipient, sent_at, content)
-        VALUES (gen_random_uuid(), $1, now(), $2)"#,
+        "UPDATE message_receipts SET deleted_at = now() WHERE message_id = $1",
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Inserts the message with its per-device copies, attachments and the
+/// sender's receipt, then delivers it unless it is scheduled for later.
+/// An unknown recipient, `kid` or blob is reported as a foreign key violation.
+pub(crate) async fn insert_msg_in(
+    tx: &mut PgConnection,
+    msg: PublishMessage,
+    settings: &MessageSettings,
+) -> sqlx::Result<Published> {
+    let now = Utc::now();
+    // postgres keeps microseconds, answer with what is stored
+    let deliver_after = msg
+        .deliver_after
+        .filter(|at| *at > now)
+        .map(|at| at.trunc_subsecs(6));
+    let expires_at = settings
+        .ttl()
+        .map(|ttl| (deliver_after.unwrap_or(now) + ttl).trunc_subsecs(6));
+    let sender_token = SecretToken::generate();
+    let cancel_token = deliver_after.map(|_| SecretToken::generate());
+    let (kids, contents): (Vec<String>, Vec<String>) =
+        msg.content.into_iter().map(|(k, c)| (k.into(), c)).unzip();
+    let mut attachments: Vec<Vec<u8>> = msg.attachments.iter().map(|h| h.as_bytes().to_vec()).collect();
+    attachments.sort();
+    attachments.dedup();
+    let inserted = sqlx::query!(
+        r#"INSERT INTO messages (id, recipient, sent_at, expires_at, deliver_after)
+        VALUES (gen_random_uuid(), $1, now(), $2, $3) RETURNING id, sent_at"#,
         msg.recipient.name(),
-        msg.content
+        expires_at,
+        deliver_after
     )
-    .execute(pool)
+    .fetch_one(&mut *tx)
     .await?;
-    Ok(())
+    let id = inserted.id;
+    sqlx::query!(
+        r#"INSERT INTO message_receipts
+        (message_id, sender_token_hash, expires_at, deliver_after, cancel_token_hash)
+        VALUES ($1, $2, $3, $4, $5)"#,
+        id,
+        sender_token.digest(),
+        expires_at,
+        deliver_after,
+        cancel_token.as_ref().map(SecretToken::digest)
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"INSERT INTO message_copies (message_id, recipient, kid, content)
+        SELECT $1, $2, kid, content FROM UNNEST($3::text[], $4::text[]) AS c(kid, content)"#,
+        id,
+        msg.recipient.name(),
+        &kids,
+        &contents
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO message_blobs (message_id, blob_hash) SELECT $1, * FROM UNNEST($2::bytea[])",
+        id,
+        &attachments
+    )
+    .execute(&mut *tx)
+    .await?;
+    if deliver_after.is_none() {
+        deliver(&mut *tx, id, &msg.recipient).await?;
+    }
+    Ok(Published {
+        id,
+        sent_at: inserted.sent_at,
+        expires_at,
+        sender_token: sender_token.to_string(),
+        deliver_after,
+        cancel_token: cancel_token.map(|t| t.to_string()),
+    })
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Makes a message visible in the recipient's mailbox: assigns its `seq`,
+/// queues a webhook delivery if the recipient registered one, and wakes
+/// listeners once the transaction commits.
+pub(crate) async fn deliver(tx: &mut PgConnection, id: Uuid, recipient: &KeyName) -> sqlx::Result<i64> {
+    // the row lock is held until commit, keeping `seq` in commit order
+    let seq = sqlx::query_scalar!(
+        "UPDATE keymap SET last_seq = last_seq + 1 WHERE name = $1 RETURNING last_seq",
+        recipient.name()
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    sqlx::query!("UPDATE messages SET seq = $2 WHERE id = $1", id, seq)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        r#"INSERT INTO webhook_outbox (id, alias, message_id)
+        SELECT gen_random_uuid(), alias, $1 FROM webhooks WHERE alias = $2"#,
+        id,
+        recipient.name()
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "SELECT pg_notify($1, $2)",
+        channel_name(recipient),
+        recipient.name()
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(seq)
+}
+
+/// Cancels a scheduled message before its delivery, given the cancel token
+/// returned on publishing. Wrong tokens are reported as not found, and
+/// messages already delivered as a conflict.
+#[utoipa::path(
+    post,
+    path = "/messages/{id}/cancel",
+    params(("id" = Uuid, Path, description = "Message id")),
+    security(("cancelToken" = [])),
+    responses(
+        (status = 204, description = "Message cancelled, or it already was"),
+        (status = 401, description = "Missing or malformed cancel token"),
+        (status = 404, description = "No scheduled message with this cancel token"),
+        (status = 409, description = "The message was already delivered"),
+    ),
+)]
+#[tracing::instrument(skip(pool, token), name = "cancelling scheduled message")]
+pub async fn cancel_message(
+    State(pool): State<PgPool>,
+    Path(id): Path<Uuid>,
+    Bearer(token): Bearer,
+) -> Result<StatusCode, ApiError> {
+    match cancel_msg(&pool, id, &token).await? {
+        CancelOutcome::Cancelled => Ok(StatusCode::NO_CONTENT),
+        CancelOutcome::NotFound => Err(ApiError::not_found("message_not_found")),
+        CancelOutcome::Delivered => Err(ApiError::conflict("already_delivered")),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum CancelOutcome {
+    Cancelled,
+    NotFound,
+    Delivered,
+}
+
+pub(crate) async fn cancel_msg(pool: &PgPool, id: Uuid, token: &SecretToken) -> sqlx::Result<CancelOutcome> {
+    let mut tx = pool.begin().await?;
+    let receipt = sqlx::query!(
+        r#"SELECT cancelled_at FROM message_receipts
+        WHERE message_id = $1 AND cancel_token_hash = $2 FOR UPDATE"#,
+        id,
+        token.digest()
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    match receipt {
+        None => return Ok(CancelOutcome::NotFound),
+        Some(r) if r.cancelled_at.is_some() => return Ok(CancelOutcome::Cancelled),
+        Some(_) => {}
+    }
+    // waits for a concurrent delivery, which then leaves nothing to delete
+    let deleted = sqlx::query!("DELETE FROM messages WHERE id = $1 AND seq IS NULL", id)
+        .execute(&mut *tx)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Ok(CancelOutcome::Delivered);
+    }
+    sqlx::query!(
+        "UPDATE message_receipts SET cancelled_at = now() WHERE message_id = $1",
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(CancelOutcome::Cancelled)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    /// hidden from the recipient until its deliver-after time
+    Scheduled,
+    /// not fetched by any device of the recipient yet
+    Pending,
+    Fetched,
+    /// deleted by the recipient
+    Deleted,
+    /// expired before being deleted
+    Expired,
+    /// cancelled by the sender before its delivery
+    Cancelled,
+}
+
+/// What the sender may learn about a message.
+#[derive(Debug, Clone, Default)]
+pub struct Receipt {
+    pub expires_at: Option<DateTime<Utc>>,
+    pub deliver_after: Option<DateTime<Utc>>,
+    pub fetched_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub cancelled_at: Option<DateTime<Utc>>,
+}
+
+impl Receipt {
+    /// Cancellation, deletion and expiry are final, and take precedence
+    /// over the message having been fetched.
+    pub fn status(&self, now: DateTime<Utc>) -> DeliveryStatus {
+        if self.cancelled_at.is_some() {
+            DeliveryStatus::Cancelled
+        } else if self.deleted_at.is_some() {
+            DeliveryStatus::Deleted
+        } else if self.expires_at.is_some_and(|at| at <= now) {
+            DeliveryStatus::Expired
+        } else if self.deliver_after.is_some_and(|at| at > now) {
+            DeliveryStatus::Scheduled
+        } else if self.fetched_at.is_some() {
+            DeliveryStatus::Fetched
+        } else {
+            DeliveryStatus::Pending
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct MessageStatus {
+    pub id: Uuid,
+    pub status: DeliveryStatus,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(rename = "deliverAfter")]
+    pub deliver_after: Option<DateTime<Utc>>,
+    /// first time a device of the recipient fetched the message
+    #[serde(rename = "fetchedAt")]
+    pub fetched_at: Option<DateTime<Utc>>,
+    #[serde(rename = "deletedAt")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    #[serde(rename = "cancelledAt")]
+    pub cancelled_at: Option<DateTime<Utc>>,
+}
+
+/// Reports the status of a message to its sender, given the sender token
+/// returned on publishing. Wrong tokens are reported as not found.
+#[utoipa::path(
+    get,
+    path = "/messages/{id}/status",
+    params(("id" = Uuid, Path, description = "Message id")),
+    security(("senderToken" = [])),
+    responses(
+        (status = 200, description = "Status of the message", body = MessageStatus),
+        (status = 401, description = "Missing or malformed sender token"),
+        (status = 404, description = "No message with this sender token"),
+    ),
+)]
+#[tracing::instrument(skip(pool, token), name = "getting message status")]
+pub async fn message_status(
+    State(pool): State<PgPool>,
+    Path(id): Path<Uuid>,
+    Bearer(token): Bearer,
+) -> Result<Json<MessageStatus>, ApiError> {
+    let receipt = sqlx::query_as!(
+        Receipt,
+        r#"SELECT expires_at, deliver_after, fetched_at, deleted_at, cancelled_at
+        FROM message_receipts WHERE message_id = $1 AND sender_token_hash = $2"#,
+        id,
+        token.digest()
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| ApiError::not_found("message_not_found"))?;
+    Ok(Json(MessageStatus {
+        id,
+        status: receipt.status(Utc::now()),
+        expires_at: receipt.expires_at,
+        deliver_after: receipt.deliver_after,
+        fetched_at: receipt.fetched_at,
+        deleted_at: receipt.deleted_at,
+        cancelled_at: receipt.cancelled_at,
+    }))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Message {
+    pub id: Uuid,
+    /// position in the recipient's mailbox, used as cursor
+    pub seq: i64,
     /// encrypted content encoded in base64
     pub content: String,
     #[serde(rename = "sentAt")]
     pub sent_at: DateTime<Utc>,
+    /// hashes of attached encrypted blobs
+    pub attachments: Vec<BlobHash>,
 }
-async fn get_sent_msgs(pool: &PgPool, get_msg: GetMessages) -> sqlx::Result<Vec<Message>> {
-    let limit = match get_msg.limit {
-        Some(l) => l.min(200),
-        None => 10,
-    };
+
+/// Fetches the copies for device `kid` of messages after `after`, in mailbox order.
+/// Scheduled messages have no `seq` until they are delivered, so they stay hidden.
+pub(crate) async fn get_sent_msgs(
+    pool: &PgPool,
+    recipient: &KeyName,
+    kid: &Kid,
+    after: i64,
+    limit: i64,
+) -> sqlx::Result<Vec<Message>> {
     let msgs = sqlx::query!(
         r#"
-        SELECT content, sent_at FROM messages WHERE recipient = $1 ORDER BY sent_at LIMIT $2
+        SELECT m.id, m.seq as "seq!", c.content, m.sent_at, array(
+            SELECT blob_hash FROM message_blobs b WHERE b.message_id = m.id
+        ) as "attachments!"
+        FROM messages m JOIN message_copies c ON c.message_id = m.id
+        WHERE m.recipient = $1 AND c.kid = $2 AND m.seq > $3
+            AND (m.expires_at IS NULL OR m.expires_at > now())
+        ORDER BY m.seq LIMIT $4
         "#,
-        get_msg.recipient.name(),
-        limit as i32
+        recipient.name(),
+        kid.as_str(),
+        after,
+        limit
     )
     .fetch_all(pool)
     .await?;
-    Ok(msgs
-        .into_iter()
-        .map(|r| Message {
-            content: r.content,
-            sent_at: r.sent_at,
+    msgs.into_iter()
+        .map(|r| {
+            Ok(Message {
+                id: r.id,
+                seq: r.seq,
+                content: r.content,
+                sent_at: r.sent_at,
+                attachments: blob_hashes(&r.attachments)?,
+            })
         })
-        .collect())
+        .collect()
+}
+
+/// Records the first fetch of messages on their senders' receipts. Only
+/// reads by the recipient count, anyone may read the ciphertext.
+pub(crate) async fn mark_fetched(pool: &PgPool, msgs: &[Message]) -> sqlx::Result<()> {
+    if msgs.is_empty() {
+        return Ok(());
+    }
+    let ids: Vec<Uuid> = msgs.iter().map(|m| m.id).collect();
+    sqlx::query!(
+        "UPDATE message_receipts SET fetched_at = now() WHERE message_id = ANY($1) AND fetched_at IS NULL",
+        &ids
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Decodes attachment hashes as stored in `message_blobs`.
+pub(crate) fn blob_hashes(hashes: &[Vec<u8>]) -> sqlx::Result<Vec<BlobHash>> {
+    hashes
+        .iter()
+        .map(|h| BlobHash::try_from(&h[..]))
+        .collect::<Result<_, _>>()
+        .map_err(|e| sqlx::Error::Decode(e.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delivery_status_precedence() {
+        let now = Utc::now();
+        let later = Some(now + chrono::TimeDelta::days(1));
+        let earlier = Some(now - chrono::TimeDelta::days(1));
+        let status = |expires_at, deliver_after, fetched_at, deleted_at, cancelled_at| {
+            Receipt {
+                expires_at,
+                deliver_after,
+                fetched_at,
+                deleted_at,
+                cancelled_at,
+            }
+            .status(now)
+        };
+        use DeliveryStatus::*;
+        assert_eq!(status(later, None, None, None, None), Pending);
+        assert_eq!(status(None, None, None, None, None), Pending);
+        assert_eq!(status(later, None, earlier, None, None), Fetched);
+        assert_eq!(status(earlier, None, earlier, None, None), Expired);
+        assert_eq!(status(earlier, None, earlier, earlier, None), Deleted);
+        assert_eq!(status(later, None, None, earlier, None), Deleted);
+        assert_eq!(status(later, later, None, None, None), Scheduled);
+        assert_eq!(status(later, earlier, None, None, None), Pending);
+        assert_eq!(status(later, later, None, None, earlier), Cancelled);
+    }
 }
@@ -1,18 +1,138 @@
 pub mod messages;
 pub mod alias;
+pub mod auth;
+pub mod batch;
+pub mod blobs;
+pub mod channels;
+pub mod devices;
+pub mod drops;
+pub mod error;
+pub mod extract;
+pub mod idempotency;
+pub mod mailbox;
+pub mod openapi;
+pub mod pow;
+pub mod push;
 pub mod register;
+pub mod tokens;
+pub mod webhooks;
 
+use axum::extract::DefaultBodyLimit;
+use axum::middleware;
 use axum::routing::post;
-use axum::routing::get;
+use axum::routing::{delete, get, patch, put};
 use axum::Router;
-use sqlx::PgPool;
+use tower::util::option_layer;
 
+use crate::configuration::ApplicationSettings;
+use crate::hardening::with_timeout;
+use crate::ratelimit::{ClientIp, Quota, RateLimitLayer, RateLimiter};
+use crate::startup::AppState;
 
-pub fn router() -> Router<PgPool> {
-    Router::new()
-        .route("/search/:alias", get(alias::search_alias))
+use self::error::ApiError;
+
+
+pub fn router(state: &AppState, settings: &ApplicationSettings) -> Router<AppState> {
+    let limits = &settings.limits;
+    let rate_limits = &settings.rate_limits;
+    let idempotent = middleware::from_fn_with_state(state.clone(), idempotency::idempotent);
+    // `Idempotency-Key` requests are buffered up to the body limit of their
+    // route, set outside, and release their key when answered with 408 by
+    // the timeout inside, so a retry runs again
+    let bounded = |router: Router<AppState>, body_limit: usize| {
+        with_timeout(router, limits.request_timeout())
+            .layer(idempotent.clone())
+            .layer(DefaultBodyLimit::max(body_limit))
+    };
+    let client_ip = ClientIp::new(&rate_limits.trusted_proxies);
+    let per_client = |quota: Option<Quota>| {
+        option_layer(quota.map(|quota| RateLimitLayer::per_client(RateLimiter::new(quota), client_ip.clone())))
+    };
+    let recipients = rate_limits.publish_per_recipient.map(RateLimiter::new);
+    let per_recipient = |max_body| {
+        option_layer(
+            recipients
+                .clone()
+                .map(|limiter| RateLimitLayer::per_recipient(limiter, max_body)),
+        )
+    };
+    let publish_rate = per_client(rate_limits.publish);
+    #[allow(deprecated)]
+    let requests = Router::new()
+        .route("/search", get(alias::search).layer(per_client(rate_limits.search)))
+        .route(
+            "/search/:alias",
+            get(alias::search_alias).layer(per_client(rate_limits.search)),
+        )
         .route("/registry/:alias", get(alias::fetch_alias))
-        .route("/register", post(register::register))
-        .route("/publish", post(messages::publish_message))
+        .route("/registry/:alias/keys", post(devices::add_device_key))
+        .route("/registry/:alias/keys/:kid", delete(devices::remove_device_key))
+        .route(
+            "/registry/:alias/claim",
+            post(devices::start_claim)
+                .layer(per_client(rate_limits.register))
+                .put(devices::finish_claim),
+        )
+        .route("/registry/:alias/pow", put(pow::put_difficulty))
+        .route("/pow/challenge", get(pow::challenge))
+        .route("/tokens/key", get(tokens::token_key))
+        .route(
+            "/tokens/issue",
+            post(tokens::issue_tokens).layer(per_client(rate_limits.issue)),
+        )
+        .route(
+            "/register",
+            post(register::register).layer(per_client(rate_limits.register)),
+        )
+        .route("/messages/:id", delete(messages::delete_message))
+        .route("/messages/:id/status", get(messages::message_status))
+        .route("/messages/:id/cancel", post(messages::cancel_message))
+        .route("/drops", post(drops::create_drop).layer(publish_rate.clone()))
+        .route("/drops/inbox/:read_token", get(drops::read_drop).delete(drops::delete_drop))
+        .route("/drops/:token", get(drops::fetch_drop))
+        .route("/channels/:alias", put(channels::put_channel))
+        .route(
+            "/channels/:alias/posts",
+            get(channels::list_posts).post(channels::publish_post),
+        )
+        .route("/channels/:alias/feed", get(channels::channel_feed))
+        .route("/blobs/uploads", post(blobs::start_upload))
+        .route(
+            "/blobs/uploads/:id",
+            patch(blobs::upload_chunk).post(blobs::finish_upload),
+        )
+        .route("/blobs/:hash", get(blobs::fetch_blob))
+        .route(
+            "/webhooks/:alias",
+            put(webhooks::put_webhook).delete(webhooks::delete_webhook),
+        )
+        .route("/webhooks/:alias/deliveries", get(webhooks::list_deliveries))
+        .route("/push/key", get(push::vapid_key))
+        .route(
+            "/push/:alias/subscriptions",
+            post(push::add_subscription).delete(push::remove_subscription),
+        );
+    let publishing = Router::new()
+        .route(
+            "/publish",
+            post(messages::publish_message).layer((
+                publish_rate.clone(),
+                per_recipient(limits.publish_body_limit_bytes),
+            )),
+        )
+        .route("/drops/:token/messages", post(drops::publish_to_drop).layer(publish_rate.clone()));
+    let batches = Router::new().route(
+        "/publish/batch",
+        post(batch::publish_batch).layer((publish_rate, per_recipient(limits.batch_body_limit_bytes))),
+    );
+    // long-polls and streams outlive any request timeout
+    let streams = Router::new()
         .route("/messages", get(messages::get_messages))
+        .route("/mailbox/ws", get(mailbox::mailbox_ws))
+        .route("/mailbox/events", get(mailbox::mailbox_events));
+    bounded(requests, limits.body_limit_bytes)
+        .merge(bounded(publishing, limits.publish_body_limit_bytes))
+        .merge(bounded(batches, limits.batch_body_limit_bytes))
+        .merge(streams.layer(DefaultBodyLimit::max(limits.body_limit_bytes)))
+        .fallback(|| async { ApiError::not_found("route_not_found") })
 }
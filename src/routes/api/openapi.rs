@@ -0,0 +1,180 @@
+//! OpenAPI document of the API, generated from the handler and type
+//! annotations and served at `/api/openapi.json`.
+
+use utoipa::openapi::path::{Operation, ParameterBuilder, ParameterIn};
+use utoipa::openapi::schema::{ObjectBuilder, Type};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::{ContentBuilder, Ref, Required, ResponseBuilder};
+use utoipa::{Modify, OpenApi};
+
+use super::error::{Problem, PROBLEM_JSON};
+use super::extract::Json;
+use super::{alias, batch, blobs, channels, devices, drops, mailbox, messages, pow, push, register, tokens, webhooks};
+
+/// Prefix of the current version of the API.
+pub const V1: &str = "/api/v1";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "BlindChannel REST API",
+        description = "Errors are answered as RFC 7807 `application/problem+json` documents, \
+            told apart by their `code`."
+    ),
+    nest((path = V1, api = ApiV1)),
+    modifiers(&SecuritySchemes, &CommonResponses),
+)]
+pub struct ApiDoc;
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        alias::search,
+        alias::search_alias,
+        alias::fetch_alias,
+        devices::add_device_key,
+        devices::remove_device_key,
+        devices::start_claim,
+        devices::finish_claim,
+        pow::put_difficulty,
+        pow::challenge,
+        tokens::token_key,
+        tokens::issue_tokens,
+        register::register,
+        messages::publish_message,
+        batch::publish_batch,
+        messages::get_messages,
+        messages::delete_message,
+        messages::message_status,
+        messages::cancel_message,
+        drops::create_drop,
+        drops::fetch_drop,
+        drops::publish_to_drop,
+        drops::read_drop,
+        drops::delete_drop,
+        mailbox::mailbox_ws,
+        mailbox::mailbox_events,
+        channels::put_channel,
+        channels::list_posts,
+        channels::publish_post,
+        channels::channel_feed,
+        blobs::start_upload,
+        blobs::upload_chunk,
+        blobs::finish_upload,
+        blobs::fetch_blob,
+        webhooks::put_webhook,
+        webhooks::delete_webhook,
+        webhooks::list_deliveries,
+        push::vapid_key,
+        push::add_subscription,
+        push::remove_subscription,
+    ),
+    components(schemas(Problem))
+)]
+struct ApiV1;
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        for (name, description) in [
+            ("ownerToken", "Owner token returned when registering the alias"),
+            ("senderToken", "Sender token returned when publishing the message"),
+            ("cancelToken", "Cancel token returned when scheduling the message"),
+        ] {
+            let scheme = HttpBuilder::new()
+                .scheme(HttpAuthScheme::Bearer)
+                .description(Some(description))
+                .build();
+            components.add_security_scheme(name, SecurityScheme::Http(scheme));
+        }
+    }
+}
+
+/// Adds what the API does for every operation, rather than repeating it
+/// on each handler: error bodies are problems, any operation may fail with
+/// 500, and POST operations take an `Idempotency-Key`.
+struct CommonResponses;
+
+impl Modify for CommonResponses {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        for item in openapi.paths.paths.values_mut() {
+            if let Some(op) = item.post.as_mut() {
+                add_idempotency_key(op);
+            }
+            let ops = [&mut item.get, &mut item.put, &mut item.post, &mut item.delete, &mut item.patch];
+            for op in ops.into_iter().flatten() {
+                add_problems(op);
+            }
+        }
+    }
+}
+
+fn add_problems(op: &mut Operation) {
+    let responses = &mut op.responses.responses;
+    responses
+        .entry("500".into())
+        .or_insert_with(|| ResponseBuilder::new().description("Internal server error").into());
+    for (status, response) in responses.iter_mut() {
+        let utoipa::openapi::RefOr::T(response) = response else {
+            continue;
+        };
+        if (status.starts_with('4') || status.starts_with('5')) && response.content.is_empty() {
+            let problem = ContentBuilder::new()
+                .schema(Some(Ref::from_schema_name("Problem")))
+                .build();
+            response.content.insert(PROBLEM_JSON.into(), problem);
+        }
+    }
+}
+
+fn add_idempotency_key(op: &mut Operation) {
+    let param = ParameterBuilder::new()
+        .name("Idempotency-Key")
+        .parameter_in(ParameterIn::Header)
+        .required(Required::False)
+        .schema(Some(ObjectBuilder::new().schema_type(Type::String).max_length(Some(255))))
+        .description(Some(
+            "Client chosen key, such as a UUID, making retries of the request safe. The response \
+            to the first request is replayed with an `Idempotent-Replayed: true` header. Reusing \
+            the key with another request is answered with 422, and retrying while the first \
+            request still runs with 409.",
+        ))
+        .build();
+    op.parameters.get_or_insert_with(Vec::new).push(param);
+}
+
+/// Serves the OpenAPI document.
+pub async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spec_documents_versioned_routes() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let paths = spec["paths"].as_object().unwrap();
+        assert!(paths.keys().all(|p| p.starts_with(V1)));
+        assert!(paths.contains_key("/api/v1/registry/{alias}"));
+
+        let register = &spec["components"]["schemas"]["RegisterInfo"];
+        assert!(register["properties"].get("name").is_some());
+        let exponent = &spec["components"]["schemas"]["PublicExponent"]["enum"];
+        assert_eq!(exponent, &serde_json::json!(["AQAB", "AQABAA=="]));
+    }
+
+    #[test]
+    fn errors_are_problems() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let register = &spec["paths"]["/api/v1/register"]["post"];
+        let conflict = &register["responses"]["409"]["content"][PROBLEM_JSON]["schema"];
+        assert_eq!(conflict["$ref"], "#/components/schemas/Problem");
+        assert!(register["responses"].get("500").is_some());
+        let params: Vec<_> = register["parameters"].as_array().unwrap().iter().map(|p| &p["name"]).collect();
+        assert_eq!(params, ["X-Hashcash", "Idempotency-Key"]);
+    }
+}
@@ -0,0 +1,452 @@
+//! Proof of work asked of anonymous senders and registrants, see
+//! [`crate::pow`]. Clients fetch a challenge for the alias, solve it and
+//! send the stamp in an `X-Hashcash` header. Senders, to aliases or drops,
+//! may instead spend anonymous tokens, see [`super::tokens`], in an
+//! `X-Private-Token` header.
+
+use std::collections::HashMap;
+
+use axum::async_trait;
+use axum::extract::{FromRef, FromRequest, Request, State};
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::domain::key::KeyName;
+use crate::pow::{Hashcash, Spent};
+use crate::tokens::{Token, TokenIssuer};
+
+use super::auth::{require_owner, Bearer};
+use super::error::ApiError;
+use super::extract::{json_body, parse_json, Json, Path, Query};
+
+pub const HASHCASH: &str = "x-hashcash";
+pub const PRIVATE_TOKEN: &str = "x-private-token";
+/// What stamps paying for tokens are made for, in place of an alias.
+pub const TOKENS_RESOURCE: &str = "tokens";
+/// What stamps for drops are made for, in place of an alias.
+pub const DROPS_RESOURCE: &str = "drops";
+
+/// What a stamp is made for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Publish,
+    Register,
+    /// getting anonymous tokens
+    Issue,
+    /// creating or publishing to a drop
+    Drop,
+}
+
+/// Request bodies paid for with a stamp for each resource they name:
+/// the aliases published to or registered, [`TOKENS_RESOURCE`] or
+/// [`DROPS_RESOURCE`].
+pub trait Stampable: DeserializeOwned {
+    const ACTION: Action;
+    fn resources(&self) -> Vec<&str>;
+}
+
+/// Values of a header holding a comma separated list.
+fn header_list(req: &Request, name: &str) -> Vec<String> {
+    req.headers()
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|s| s.trim().to_owned())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// JSON request body accompanied by valid stamps, unless the server and
+/// the aliases ask for none. Published messages, to aliases or drops, and
+/// new drops may come with valid tokens instead, one per message, unless
+/// an alias asks for more than the server's difficulty.
+///
+/// Nothing is spent yet: handlers spend the [`Payment`] once the request
+/// is validated, in the transaction storing what it pays for.
+pub struct Stamped<T>(pub T, pub Payment);
+
+/// Stamps or tokens a request came with, spent by [`Payment::spend`].
+#[derive(Debug, Default)]
+pub enum Payment {
+    /// nothing was asked for
+    #[default]
+    Free,
+    Stamps(Vec<Spent>),
+    Tokens { nonces: Vec<Vec<u8>>, key_id: Vec<u8> },
+}
+
+impl Payment {
+    /// Marks the stamps or tokens spent, all or none, within the caller's
+    /// transaction, which must be rolled back if one already was.
+    pub async fn spend(&self, tx: &mut PgConnection) -> Result<(), ApiError> {
+        match self {
+            Self::Free => Ok(()),
+            Self::Stamps(spent) => {
+                if spend(tx, spent).await? {
+                    Ok(())
+                } else {
+                    Err(ApiError::conflict("stamp_spent")
+                        .with_detail("each challenge can be used once")
+                        .retryable())
+                }
+            }
+            Self::Tokens { nonces, key_id } => {
+                if redeem(tx, nonces, key_id).await? {
+                    Ok(())
+                } else {
+                    Err(ApiError::conflict("token_spent")
+                        .with_detail("each token can be spent once")
+                        .retryable())
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Stamped<T>
+where
+    T: Stampable + Send,
+    S: Send + Sync,
+    Hashcash: FromRef<S>,
+    TokenIssuer: FromRef<S>,
+    PgPool: FromRef<S>,
+{
+    type Rejection = ApiError;
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let stamps = header_list(&req, HASHCASH);
+        let tokens = header_list(&req, PRIVATE_TOKEN);
+        let body = json_body(req, state).await?;
+        let value: T = parse_json(&body)?;
+
+        let pool = PgPool::from_ref(state);
+        let resources = value.resources();
+        let hashcash = Hashcash::from_ref(state);
+        let issuer = TokenIssuer::from_ref(state);
+        let required = difficulties(&pool, &hashcash, &issuer, T::ACTION, &resources).await?;
+        if matches!(T::ACTION, Action::Publish | Action::Drop) && !tokens.is_empty() {
+            // tokens cost the server's difficulty, aliases asking for more want stamps
+            if required.iter().any(|&bits| bits > hashcash.publish_difficulty(None)) {
+                return Err(ApiError::new(StatusCode::FORBIDDEN, "proof_of_work_required")
+                    .with_detail("the alias asks for more proof of work than tokens stand for, send stamps")
+                    .retryable());
+            }
+            let nonces = verify_tokens(&issuer, &tokens, resources.len())?;
+            let key_id = issuer.key_id().to_vec();
+            return Ok(Stamped(value, Payment::Tokens { nonces, key_id }));
+        }
+        if required.iter().all(|&bits| bits == 0) {
+            return Ok(Stamped(value, Payment::Free));
+        }
+        if stamps.is_empty() {
+            return Err(ApiError::new(StatusCode::FORBIDDEN, "proof_of_work_required")
+                .with_detail("solve a challenge from `/pow/challenge` and send it in `X-Hashcash`")
+                .retryable());
+        }
+        if stamps.len() != resources.len() {
+            return Err(ApiError::new(StatusCode::FORBIDDEN, "invalid_stamp")
+                .with_detail(format!("expected {} stamps, one per message in order", resources.len()))
+                .retryable());
+        }
+        let mut spent = Vec::with_capacity(stamps.len());
+        for ((stamp, resource), bits) in stamps.iter().zip(&resources).zip(required) {
+            let stamp = hashcash.verify(stamp, resource, &body, bits).map_err(|e| {
+                ApiError::new(StatusCode::FORBIDDEN, "invalid_stamp")
+                    .with_detail(e.to_string())
+                    .retryable()
+            })?;
+            spent.push(stamp);
+        }
+        Ok(Stamped(value, Payment::Stamps(spent)))
+    }
+}
+
+/// Zero bits asked for each resource. Unknown aliases get the server's
+/// difficulty, and are rejected by the handler. Drops can't ask for more
+/// than the server's difficulty.
+async fn difficulties(
+    pool: &PgPool,
+    hashcash: &Hashcash,
+    issuer: &TokenIssuer,
+    action: Action,
+    resources: &[&str],
+) -> sqlx::Result<Vec<u8>> {
+    match action {
+        Action::Register => return Ok(vec![hashcash.register_difficulty(); resources.len()]),
+        Action::Issue => return Ok(vec![issuer.issue_difficulty(); resources.len()]),
+        Action::Drop => return Ok(vec![hashcash.publish_difficulty(None); resources.len()]),
+        Action::Publish => {}
+    }
+    let names: Vec<String> = resources.iter().map(|&r| r.to_owned()).collect();
+    let own: HashMap<String, Option<i16>> = sqlx::query!(
+        "SELECT name, pow_difficulty FROM keymap WHERE name = ANY($1)",
+        &names
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| (row.name, row.pow_difficulty))
+    .collect();
+    Ok(names
+        .iter()
+        .map(|name| {
+            let own = own.get(name).copied().flatten().and_then(|bits| u8::try_from(bits).ok());
+            hashcash.publish_difficulty(own)
+        })
+        .collect())
+}
+
+/// Marks the nonces spent. False if one already was.
+async fn spend(tx: &mut PgConnection, spent: &[Spent]) -> sqlx::Result<bool> {
+    let nonces: Vec<Vec<u8>> = spent.iter().map(|s| s.nonce.clone()).collect();
+    let expiries: Vec<DateTime<Utc>> = spent.iter().map(|s| s.expires_at).collect();
+    let inserted = sqlx::query!(
+        r#"INSERT INTO spent_stamps (nonce, expires_at)
+        SELECT * FROM UNNEST($1::bytea[], $2::timestamptz[])
+        ON CONFLICT DO NOTHING"#,
+        &nonces,
+        &expiries
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(inserted.rows_affected() == spent.len() as u64)
+}
+
+/// Nonces of the tokens, after checking there is a valid one for each of
+/// `count` messages.
+fn verify_tokens(issuer: &TokenIssuer, tokens: &[String], count: usize) -> Result<Vec<Vec<u8>>, ApiError> {
+    if tokens.len() != count {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "invalid_token")
+            .with_detail(format!("expected {count} tokens, one per message"))
+            .retryable());
+    }
+    let tokens = tokens
+        .iter()
+        .map(|t| issuer.verify(t))
+        .collect::<Result<Vec<Token>, _>>()
+        .map_err(|e| {
+            ApiError::new(StatusCode::FORBIDDEN, "invalid_token")
+                .with_detail(e.to_string())
+                .retryable()
+        })?;
+    Ok(tokens.iter().map(|t| t.nonce.to_vec()).collect())
+}
+
+/// Marks the token nonces spent. False if one already was.
+async fn redeem(tx: &mut PgConnection, nonces: &[Vec<u8>], key_id: &[u8]) -> sqlx::Result<bool> {
+    let inserted = sqlx::query!(
+        r#"INSERT INTO spent_tokens (nonce, key_id)
+        SELECT nonce, $2 FROM UNNEST($1::bytea[]) AS nonce
+        ON CONFLICT DO NOTHING"#,
+        nonces,
+        key_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(inserted.rows_affected() == nonces.len() as u64)
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ChallengeParams {
+    /// alias to publish to, or to register. Not needed for tokens and drops.
+    alias: Option<KeyName>,
+    /// what the stamp is for
+    #[serde(default)]
+    action: Action,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct PowChallenge {
+    /// nonce for stamps made for the alias
+    nonce: String,
+    /// zero bits the SHA-256 of the stamp must start with
+    bits: u8,
+    /// the stamp must be sent before this time
+    #[serde(rename = "expiresAt")]
+    expires_at: DateTime<Utc>,
+}
+
+/// `X-Private-Token` header of messages paid for with tokens.
+#[derive(IntoParams)]
+#[into_params(names("X-Private-Token"), parameter_in = Header)]
+#[allow(dead_code)]
+pub struct TokenHeader(
+    /// token from `/tokens/issue`, spent in place of a proof of work stamp. Batches take one
+    /// token per message, separated by commas. Aliases asking for more proof of work than
+    /// the server refuse tokens.
+    Option<String>,
+);
+
+/// `X-Hashcash` header of requests requiring proof of work.
+#[derive(IntoParams)]
+#[into_params(names("X-Hashcash"), parameter_in = Header)]
+#[allow(dead_code)]
+pub struct StampHeader(
+    /// stamp `1:<bits>:<nonce>:<resource>:<contentHash>:<counter>` made with a challenge for
+    /// the resource, the alias published to or registered, `tokens` when getting tokens or
+    /// `drops` when creating or publishing to a drop, where `contentHash` is the base64url
+    /// encoded SHA-256 of the request body and the SHA-256 of the whole stamp starts with
+    /// `bits` zero bits. Batches take one stamp per message, in order and separated by commas.
+    Option<String>,
+);
+
+/// Hands out a nonce to make a proof of work stamp with.
+#[utoipa::path(
+    get,
+    path = "/pow/challenge",
+    params(ChallengeParams),
+    responses(
+        (status = 200, description = "Challenge for the alias, tokens or drops", body = PowChallenge),
+        (status = 400, description = "No alias to publish to or register"),
+    ),
+)]
+#[tracing::instrument(skip(pool, hashcash, issuer), name = "issuing proof of work challenge")]
+pub async fn challenge(
+    State(pool): State<PgPool>,
+    State(hashcash): State<Hashcash>,
+    State(issuer): State<TokenIssuer>,
+    Query(params): Query<ChallengeParams>,
+) -> Result<Json<PowChallenge>, ApiError> {
+    let resource = match (params.action, &params.alias) {
+        (Action::Issue, _) => TOKENS_RESOURCE,
+        (Action::Drop, _) => DROPS_RESOURCE,
+        (_, Some(alias)) => alias.name(),
+        (_, None) => {
+            return Err(ApiError::bad_request("invalid_query")
+                .with_field("alias", "required to publish or register"))
+        }
+    };
+    let bits = difficulties(&pool, &hashcash, &issuer, params.action, &[resource]).await?[0];
+    let challenge = hashcash.challenge(resource);
+    Ok(Json(PowChallenge {
+        nonce: challenge.nonce,
+        bits,
+        expires_at: challenge.expires_at,
+    }))
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Path)]
+pub struct Params {
+    alias: KeyName,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct PowDifficulty {
+    /// zero bits asked of senders, or null for the server's difficulty.
+    /// Lower ones than the server's have no effect, higher ones refuse tokens.
+    difficulty: Option<u8>,
+}
+
+/// Sets the proof of work an alias asks of its senders.
+#[utoipa::path(
+    put,
+    path = "/registry/{alias}/pow",
+    params(Params),
+    request_body = PowDifficulty,
+    security(("ownerToken" = [])),
+    responses(
+        (status = 204, description = "Difficulty set"),
+        (status = 401, description = "Missing or malformed owner token"),
+        (status = 403, description = "Owner token does not match the alias"),
+        (status = 422, description = "The difficulty is above the server's maximum"),
+    ),
+)]
+#[tracing::instrument(skip(pool, hashcash, token), name = "setting proof of work difficulty")]
+pub async fn put_difficulty(
+    State(pool): State<PgPool>,
+    State(hashcash): State<Hashcash>,
+    Path(params): Path<Params>,
+    Bearer(token): Bearer,
+    Json(body): Json<PowDifficulty>,
+) -> Result<StatusCode, ApiError> {
+    require_owner(&pool, &params.alias, &token).await?;
+    if body.difficulty.is_some_and(|bits| bits > hashcash.max_difficulty()) {
+        return Err(ApiError::unprocessable("invalid_difficulty")
+            .with_field("difficulty", format!("at most {} bits", hashcash.max_difficulty())));
+    }
+    sqlx::query!(
+        "UPDATE keymap SET pow_difficulty = $2 WHERE name = $1",
+        params.alias.name(),
+        body.difficulty.map(i16::from)
+    )
+    .execute(&pool)
+    .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::header;
+    use rsa::RsaPrivateKey;
+
+    use crate::configuration::{PowSettings, TokenSettings};
+    use crate::routes::api::messages::PublishMessage;
+    use crate::testing::{message, register};
+
+    #[derive(Clone)]
+    struct TestState {
+        pool: PgPool,
+        hashcash: Hashcash,
+        issuer: TokenIssuer,
+    }
+    impl FromRef<TestState> for PgPool {
+        fn from_ref(state: &TestState) -> Self {
+            state.pool.clone()
+        }
+    }
+    impl FromRef<TestState> for Hashcash {
+        fn from_ref(state: &TestState) -> Self {
+            state.hashcash.clone()
+        }
+    }
+    impl FromRef<TestState> for TokenIssuer {
+        fn from_ref(state: &TestState) -> Self {
+            state.issuer.clone()
+        }
+    }
+
+    #[sqlx::test]
+    async fn aliases_asking_for_more_refuse_tokens(pool: PgPool) {
+        register(&pool, "alice", &["k1"]).await;
+        register(&pool, "bob", &["k1"]).await;
+        sqlx::query!("UPDATE keymap SET pow_difficulty = 24 WHERE name = 'bob'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let state = TestState {
+            pool,
+            hashcash: Hashcash::new(&PowSettings::default()),
+            issuer: TokenIssuer::with_key(key, &TokenSettings::default()),
+        };
+        let publish = |recipient| {
+            let body = serde_json::to_vec(&message(recipient, &["k1"])).unwrap();
+            Request::post("/")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(PRIVATE_TOKEN, "forged")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let Err(refused) = Stamped::<PublishMessage>::from_request(publish("bob"), &state).await else {
+            panic!("tokens redeemed for an alias asking for more");
+        };
+        assert_eq!(refused.code(), "proof_of_work_required");
+        // tokens are checked for others
+        let Err(forged) = Stamped::<PublishMessage>::from_request(publish("alice"), &state).await else {
+            panic!("forged token redeemed");
+        };
+        assert_eq!(forged.code(), "invalid_token");
+    }
+}
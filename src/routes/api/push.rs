@@ -0,0 +1,149 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::domain::bytevec::ByteVec;
+use crate::domain::key::KeyName;
+use crate::push::{PushService, SubscriptionKeys};
+use crate::webhooks::validate_url;
+
+use super::auth::{require_owner, Bearer};
+use super::error::ApiError;
+use super::extract::{Json, Path, Query};
+
+#[derive(Debug, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Path)]
+pub struct Params {
+    alias: KeyName,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct VapidKey {
+    /// base64url encoded key to pass as `applicationServerKey` when subscribing
+    #[serde(rename = "publicKey")]
+    public_key: String,
+}
+
+fn push_disabled() -> ApiError {
+    ApiError::not_found("push_disabled").with_detail("Web Push is not configured on this server")
+}
+
+/// Returns the server's VAPID public key, or 404 when Web Push is disabled.
+#[utoipa::path(
+    get,
+    path = "/push/key",
+    responses(
+        (status = 200, description = "VAPID public key", body = VapidKey),
+        (status = 404, description = "Web Push is disabled"),
+    ),
+)]
+pub async fn vapid_key(State(push): State<PushService>) -> Result<Json<VapidKey>, ApiError> {
+    let vapid = push.vapid().ok_or_else(push_disabled)?;
+    Ok(Json(VapidKey {
+        public_key: vapid.public_key(),
+    }))
+}
+
+/// A `PushSubscription` as serialized by the browser.
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct PushSubscription {
+    endpoint: String,
+    keys: PushSubscriptionKeys,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct PushSubscriptionKeys {
+    p256dh: ByteVec,
+    auth: ByteVec,
+}
+
+/// Registers a push subscription for the alias, or renews the keys of a known endpoint.
+#[utoipa::path(
+    post,
+    path = "/push/{alias}/subscriptions",
+    params(Params),
+    request_body = PushSubscription,
+    security(("ownerToken" = [])),
+    responses(
+        (status = 201, description = "Subscription stored"),
+        (status = 401, description = "Missing or malformed owner token"),
+        (status = 403, description = "Owner token does not match the alias"),
+        (status = 404, description = "Web Push is disabled"),
+        (status = 422, description = "Invalid endpoint or keys"),
+    ),
+)]
+#[tracing::instrument(skip(pool, push, token, sub), name = "adding push subscription")]
+pub async fn add_subscription(
+    State(pool): State<PgPool>,
+    State(push): State<PushService>,
+    Path(params): Path<Params>,
+    Bearer(token): Bearer,
+    Json(sub): Json<PushSubscription>,
+) -> Result<StatusCode, ApiError> {
+    if push.vapid().is_none() {
+        return Err(push_disabled());
+    }
+    require_owner(&pool, &params.alias, &token).await?;
+    let endpoint = validate_url(&sub.endpoint, push.allow_insecure())
+        .map_err(|e| ApiError::unprocessable("invalid_subscription").with_field("endpoint", e))?;
+    let keys = SubscriptionKeys::parse(&sub.keys.p256dh, &sub.keys.auth)
+        .map_err(|e| ApiError::unprocessable("invalid_subscription").with_field("keys", e))?;
+    sqlx::query!(
+        r#"INSERT INTO push_subscriptions (alias, endpoint, p256dh, auth) VALUES ($1, $2, $3, $4)
+        ON CONFLICT (alias, endpoint) DO UPDATE SET p256dh = excluded.p256dh, auth = excluded.auth"#,
+        params.alias.name(),
+        endpoint.as_str(),
+        keys.p256dh_bytes(),
+        &keys.auth[..]
+    )
+    .execute(&pool)
+    .await?;
+    Ok(StatusCode::CREATED)
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SubscriptionQuery {
+    /// endpoint of the subscription to remove
+    endpoint: String,
+}
+
+/// Removes a push subscription of the alias.
+#[utoipa::path(
+    delete,
+    path = "/push/{alias}/subscriptions",
+    params(Params, SubscriptionQuery),
+    security(("ownerToken" = [])),
+    responses(
+        (status = 204, description = "Subscription removed"),
+        (status = 401, description = "Missing or malformed owner token"),
+        (status = 403, description = "Owner token does not match the alias"),
+        (status = 404, description = "No such subscription"),
+    ),
+)]
+#[tracing::instrument(skip(pool, token), name = "removing push subscription")]
+pub async fn remove_subscription(
+    State(pool): State<PgPool>,
+    Path(params): Path<Params>,
+    Query(query): Query<SubscriptionQuery>,
+    Bearer(token): Bearer,
+) -> Result<StatusCode, ApiError> {
+    require_owner(&pool, &params.alias, &token).await?;
+    // compare endpoints the way they were stored
+    let endpoint = reqwest::Url::parse(&query.endpoint)
+        .map(String::from)
+        .unwrap_or(query.endpoint);
+    let deleted = sqlx::query!(
+        "DELETE FROM push_subscriptions WHERE alias = $1 AND endpoint = $2",
+        params.alias.name(),
+        endpoint
+    )
+    .execute(&pool)
+    .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(ApiError::not_found("subscription_not_found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,43 +1,94 @@
-use crate::domain::key::{PublicJwk, KeyName};
+use crate::domain::key::{DeviceKey, KeyName};
+use crate::domain::token::SecretToken;
 use axum::extract::State;
 use axum::http::StatusCode;
-use axum::response::IntoResponse;
-use axum::response::Response;
-use axum::Json;
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool};
 use tracing::instrument;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+use super::error::ApiError;
+use super::extract::Json;
+use super::pow::{Action, StampHeader, Stampable, Stamped};
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct RegisterInfo {
     name: KeyName,
+    /// first device key of the alias
     #[serde(rename = "publicKey")]
-    public_key: PublicJwk,
+    public_key: DeviceKey,
+}
+
+impl Stampable for RegisterInfo {
+    const ACTION: Action = Action::Register;
+    fn resources(&self) -> Vec<&str> {
+        vec![self.name.name()]
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Registered {
+    /// secret required to manage the alias' device keys.
+    /// It is only returned once.
+    #[serde(rename = "ownerToken")]
+    owner_token: String,
 }
 
+/// Registers an alias with its first device key.
+#[utoipa::path(
+    post,
+    path = "/register",
+    params(StampHeader),
+    request_body = RegisterInfo,
+    responses(
+        (status = 201, description = "Alias registered", body = Registered),
+        (status = 403, description = "Missing or invalid proof of work stamp"),
+        (status = 409, description = "The alias is already registered, or the stamp was already spent"),
+        (status = 422, description = "Invalid name or key"),
+    ),
+)]
 #[instrument(skip(pool, info), fields(name = %info.name))]
-pub async fn register(State(pool): State<PgPool>, Json(info): Json<RegisterInfo>) -> Response {
-    match register_key(&pool, info).await {
-        Ok(()) => StatusCode::CREATED.into_response(),
-        Err(e) => match e.into_database_error() {
-            Some(e) if e.is_unique_violation() => StatusCode::CONFLICT.into_response(),
-            Some(e) => Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(axum::body::Body::new(e.to_string()))
-                .unwrap(),
-            _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-        },
+pub async fn register(
+    State(pool): State<PgPool>,
+    Stamped(info, payment): Stamped<RegisterInfo>,
+) -> Result<(StatusCode, Json<Registered>), ApiError> {
+    let token = SecretToken::generate();
+    let mut tx = pool.begin().await?;
+    payment.spend(&mut tx).await?;
+    match register_key(&mut tx, info, &token).await {
+        Ok(()) => {
+            tx.commit().await?;
+            metrics::counter!("blindchannel_aliases_registered_total").increment(1);
+            Ok((
+                StatusCode::CREATED,
+                Json(Registered {
+                    owner_token: token.to_string(),
+                }),
+            ))
+        }
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            Err(ApiError::conflict("alias_taken").with_detail("the name is already registered"))
+        }
+        Err(e) => Err(e.into()),
     }
 }
 
-#[instrument(skip(pool, info) name="registering new key")]
-async fn register_key(pool: &PgPool, info: RegisterInfo) -> sqlx::Result<()> {
+#[instrument(skip(tx, info, token) name="registering new key")]
+async fn register_key(tx: &mut PgConnection, info: RegisterInfo, token: &SecretToken) -> sqlx::Result<()> {
+    sqlx::query!(
+        "INSERT INTO keymap (name, owner_token_hash) VALUES ($1, $2)",
+        info.name.name(),
+        token.digest()
+    )
+    .execute(&mut *tx)
+    .await?;
     sqlx::query!(
-        "INSERT INTO keymap (name, public_key) VALUES ($1, $2)",
+        "INSERT INTO device_keys (alias, kid, public_key) VALUES ($1, $2, $3)",
         info.name.name(),
-        serde_json::to_value(info.public_key).unwrap()
+        info.public_key.kid.as_str(),
+        serde_json::to_value(info.public_key.jwk).unwrap()
     )
-    .execute(pool)
+    .execute(&mut *tx)
     .await?;
     Ok(())
 }
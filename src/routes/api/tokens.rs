@@ -0,0 +1,132 @@
+//! Issuance of anonymous tokens, see [`crate::tokens`]. A batch costs one
+//! proof of work stamp made for `tokens`, and every token pays for one
+//! published message in place of a stamp.
+//!
+//! Nothing about an issuance is logged or stored, so redeemed tokens can't
+//! be linked to it.
+
+use axum::extract::State;
+use rsa::traits::PublicKeyParts;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use utoipa::ToSchema;
+
+use crate::domain::bytevec::ByteVec;
+use crate::tokens::TokenIssuer;
+
+use super::error::ApiError;
+use super::extract::Json;
+use super::pow::{Action, StampHeader, Stampable, Stamped, TOKENS_RESOURCE};
+
+/// RSA key tokens are blinded for and signed with.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TokenKey {
+    /// SHA-256 of the modulus, part of what is signed for each token
+    #[serde(rename = "keyId")]
+    key_id: ByteVec,
+    /// modulus
+    n: ByteVec,
+    /// public exponent
+    e: ByteVec,
+    /// most tokens handed out at once
+    #[serde(rename = "maxBatch")]
+    max_batch: usize,
+}
+
+/// Returns the key tokens are issued with.
+#[utoipa::path(
+    get,
+    path = "/tokens/key",
+    responses((status = 200, description = "Token issuer key", body = TokenKey)),
+)]
+pub async fn token_key(State(issuer): State<TokenIssuer>) -> Json<TokenKey> {
+    let public = issuer.public_key();
+    Json(TokenKey {
+        key_id: issuer.key_id().to_vec().into(),
+        n: public.n().to_bytes_be().into(),
+        e: public.e().to_bytes_be().into(),
+        max_batch: issuer.max_batch(),
+    })
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct IssueTokens {
+    /// for each token, `H(m) * r^e mod n` as many bytes as the modulus, where `m` is
+    /// `"blindchannel token v1" || keyId || nonce` for a random 32 byte nonce, `H` is MGF1
+    /// with SHA-256 expanding `m` to one byte less than the modulus, and `r` is random
+    #[serde(rename = "blindedMessages")]
+    blinded_messages: Vec<ByteVec>,
+}
+impl Stampable for IssueTokens {
+    const ACTION: Action = Action::Issue;
+    fn resources(&self) -> Vec<&str> {
+        vec![TOKENS_RESOURCE]
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct IssuedTokens {
+    /// signature of each blinded message, in order. Multiplied by `r^-1 mod n`, it is the
+    /// signature of `m`, and the token is `nonce || signature`.
+    #[serde(rename = "blindSignatures")]
+    blind_signatures: Vec<ByteVec>,
+}
+
+/// Signs blinded tokens, paid for with proof of work.
+#[utoipa::path(
+    post,
+    path = "/tokens/issue",
+    params(StampHeader),
+    request_body = IssueTokens,
+    responses(
+        (status = 200, description = "Tokens signed", body = IssuedTokens),
+        (status = 400, description = "No or too many tokens asked for"),
+        (status = 403, description = "Missing or invalid proof of work stamp"),
+        (status = 409, description = "The stamp was already spent"),
+        (status = 422, description = "A blinded message is not a number below the modulus"),
+    ),
+)]
+// deliberately not traced, see the module docs
+pub async fn issue_tokens(
+    State(pool): State<PgPool>,
+    State(issuer): State<TokenIssuer>,
+    Stamped(request, payment): Stamped<IssueTokens>,
+) -> Result<Json<IssuedTokens>, ApiError> {
+    let count = request.blinded_messages.len();
+    if count == 0 || count > issuer.max_batch() {
+        return Err(ApiError::bad_request("invalid_batch")
+            .with_field("blindedMessages", format!("between 1 and {} tokens can be issued", issuer.max_batch())));
+    }
+    // private key operations would hold up other requests on the async workers
+    let signer = issuer.clone();
+    let signed = tokio::task::spawn_blocking(move || {
+        request
+            .blinded_messages
+            .iter()
+            .map(|blinded| signer.blind_sign(blinded))
+            .collect::<Vec<_>>()
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("token signing task failed: {e}");
+        ApiError::internal()
+    })?;
+    let mut error = ApiError::unprocessable("invalid_blinded_message");
+    let mut blind_signatures = Vec::with_capacity(count);
+    for (i, signature) in signed.into_iter().enumerate() {
+        match signature {
+            Ok(signature) => blind_signatures.push(signature.into()),
+            Err(_) => {
+                let len = issuer.public_key().size();
+                error = error.with_field(format!("blindedMessages[{i}]"), format!("must be {len} bytes below the modulus"));
+            }
+        }
+    }
+    if !error.errors().is_empty() {
+        return Err(error);
+    }
+    let mut tx = pool.begin().await?;
+    payment.spend(&mut tx).await?;
+    tx.commit().await?;
+    Ok(Json(IssuedTokens { blind_signatures }))
+}
@@ -0,0 +1,169 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::configuration::WebhookSettings;
+use crate::domain::key::KeyName;
+use crate::domain::token::SecretToken;
+use crate::webhooks::validate_url;
+
+use super::auth::{require_owner, Bearer};
+use super::error::ApiError;
+use super::extract::{Json, Path, Query};
+
+#[derive(Debug, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Path)]
+pub struct Params {
+    alias: KeyName,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct NewWebhook {
+    /// URL new messages are POSTed to
+    url: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct WebhookCreated {
+    /// key of the HMAC signing every delivery. It is only returned once.
+    secret: String,
+}
+
+/// Sets the webhook of an alias, replacing any previous one
+/// along with its pending deliveries.
+#[utoipa::path(
+    put,
+    path = "/webhooks/{alias}",
+    params(Params),
+    request_body = NewWebhook,
+    security(("ownerToken" = [])),
+    responses(
+        (status = 201, description = "Webhook set", body = WebhookCreated),
+        (status = 401, description = "Missing or malformed owner token"),
+        (status = 403, description = "Owner token does not match the alias"),
+        (status = 422, description = "The URL is not an https URL of a public host"),
+    ),
+)]
+#[tracing::instrument(skip(pool, settings, token, hook), name = "setting webhook")]
+pub async fn put_webhook(
+    State(pool): State<PgPool>,
+    State(settings): State<WebhookSettings>,
+    Path(params): Path<Params>,
+    Bearer(token): Bearer,
+    Json(hook): Json<NewWebhook>,
+) -> Result<(StatusCode, Json<WebhookCreated>), ApiError> {
+    require_owner(&pool, &params.alias, &token).await?;
+    let url = validate_url(&hook.url, settings.allow_insecure)
+        .map_err(|e| ApiError::unprocessable("invalid_webhook").with_field("url", e))?;
+    let secret = SecretToken::generate().to_string();
+    replace_webhook(&pool, &params.alias, url.as_str(), secret.as_bytes()).await?;
+    Ok((StatusCode::CREATED, Json(WebhookCreated { secret })))
+}
+
+async fn replace_webhook(pool: &PgPool, alias: &KeyName, url: &str, secret: &[u8]) -> sqlx::Result<()> {
+    let mut tx = pool.begin().await?;
+    // pending deliveries were meant for the old url and secret
+    sqlx::query!("DELETE FROM webhooks WHERE alias = $1", alias.name())
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        "INSERT INTO webhooks (alias, url, secret) VALUES ($1, $2, $3)",
+        alias.name(),
+        url,
+        secret
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
+
+/// Removes the webhook of an alias and drops its pending deliveries.
+#[utoipa::path(
+    delete,
+    path = "/webhooks/{alias}",
+    params(Params),
+    security(("ownerToken" = [])),
+    responses(
+        (status = 204, description = "Webhook removed"),
+        (status = 401, description = "Missing or malformed owner token"),
+        (status = 403, description = "Owner token does not match the alias"),
+        (status = 404, description = "The alias has no webhook"),
+    ),
+)]
+#[tracing::instrument(skip(pool, token), name = "removing webhook")]
+pub async fn delete_webhook(
+    State(pool): State<PgPool>,
+    Path(params): Path<Params>,
+    Bearer(token): Bearer,
+) -> Result<StatusCode, ApiError> {
+    require_owner(&pool, &params.alias, &token).await?;
+    let deleted = sqlx::query!("DELETE FROM webhooks WHERE alias = $1", params.alias.name())
+        .execute(&pool)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(ApiError::not_found("webhook_not_found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListDeliveries {
+    /// max entries to fetch
+    #[param(maximum = 200)]
+    limit: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Delivery {
+    /// identifies the delivery across retries, sent as `X-Blindchannel-Delivery`
+    #[serde(rename = "deliveryId")]
+    delivery_id: Uuid,
+    #[serde(rename = "messageId")]
+    message_id: Uuid,
+    attempt: i32,
+    /// status the webhook answered, if it answered
+    #[serde(rename = "statusCode")]
+    status_code: Option<i32>,
+    error: Option<String>,
+    #[serde(rename = "attemptedAt")]
+    attempted_at: DateTime<Utc>,
+}
+
+/// Lists the latest delivery attempts of an alias' webhooks, newest first.
+#[utoipa::path(
+    get,
+    path = "/webhooks/{alias}/deliveries",
+    params(Params, ListDeliveries),
+    security(("ownerToken" = [])),
+    responses(
+        (status = 200, description = "Delivery attempts", body = Vec<Delivery>),
+        (status = 401, description = "Missing or malformed owner token"),
+        (status = 403, description = "Owner token does not match the alias"),
+    ),
+)]
+#[tracing::instrument(skip(pool, token), name = "listing webhook deliveries")]
+pub async fn list_deliveries(
+    State(pool): State<PgPool>,
+    Path(params): Path<Params>,
+    Query(query): Query<ListDeliveries>,
+    Bearer(token): Bearer,
+) -> Result<Json<Vec<Delivery>>, ApiError> {
+    require_owner(&pool, &params.alias, &token).await?;
+    let limit = query.limit.unwrap_or(50).min(200);
+    let deliveries = sqlx::query_as!(
+        Delivery,
+        r#"SELECT delivery_id, message_id, attempt, status_code, error, attempted_at
+        FROM webhook_deliveries WHERE alias = $1
+        ORDER BY attempted_at DESC, id DESC LIMIT $2"#,
+        params.alias.name(),
+        i64::from(limit)
+    )
+    .fetch_all(&pool)
+    .await?;
+    Ok(Json(deliveries))
+}
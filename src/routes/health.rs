@@ -0,0 +1,141 @@
+//! Endpoints for load balancers, service managers, deploy scripts and
+//! monitoring, outside the versioned API:
+//! - `/health` answers as long as the process serves requests,
+//! - `/ready` also checks the database is reachable and migrated as far as
+//!   this build expects, answering 503 otherwise,
+//! - `/version` tells which build is running,
+//! - `/metrics` serves Prometheus metrics, to holders of the metrics token
+//!   or, without one, to anyone locally.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use serde::Serialize;
+use sqlx::migrate::Migrator;
+use sqlx::PgPool;
+
+use crate::routes::api::error::ApiError;
+use crate::routes::api::extract::Json;
+use crate::startup::AppState;
+use crate::telemetry::Metrics;
+
+/// Migrations this build was compiled against.
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// How long the database may take to answer before the server is deemed
+/// not ready, well under what load balancers wait for.
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Serialize)]
+pub struct Status {
+    status: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Version {
+    version: &'static str,
+    #[serde(rename = "gitHash")]
+    git_hash: &'static str,
+    features: Vec<&'static str>,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        .route("/version", get(version))
+        .route("/metrics", get(metrics))
+}
+
+async fn health() -> Json<Status> {
+    Json(Status { status: "ok" })
+}
+
+async fn ready(State(pool): State<PgPool>) -> Result<Json<Status>, ApiError> {
+    let pending = tokio::time::timeout(READY_TIMEOUT, pending_migrations(&pool))
+        .await
+        .map_err(|_| not_ready("the database did not answer in time"))?
+        .map_err(|e| {
+            tracing::warn!("readiness check failed: {e}");
+            not_ready("the database is unreachable")
+        })?;
+    if !pending.is_empty() {
+        let versions: Vec<_> = pending.iter().map(i64::to_string).collect();
+        return Err(not_ready(format!("migrations pending: {}", versions.join(", "))));
+    }
+    Ok(Json(Status { status: "ready" }))
+}
+
+async fn version() -> Json<Version> {
+    Json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("GIT_HASH"),
+        features: env!("ENABLED_FEATURES").split(',').filter(|f| !f.is_empty()).collect(),
+    })
+}
+
+async fn metrics(
+    State(metrics): State<Metrics>,
+    State(pool): State<PgPool>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ApiError> {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    if !metrics.authorizes(token) {
+        return Err(ApiError::new(StatusCode::UNAUTHORIZED, "invalid_token")
+            .with_detail("expected `Authorization: Bearer <token>` with the metrics token"));
+    }
+    // gauges needing the database are updated by `telemetry::record_gauges`
+    record_pool(&pool);
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], metrics.render()))
+}
+
+fn record_pool(pool: &PgPool) {
+    let size = pool.size() as f64;
+    let idle = pool.num_idle() as f64;
+    metrics::gauge!("blindchannel_db_pool_connections", "state" => "idle").set(idle);
+    metrics::gauge!("blindchannel_db_pool_connections", "state" => "in_use").set(size - idle);
+    metrics::gauge!("blindchannel_db_pool_max_connections").set(pool.options().get_max_connections() as f64);
+}
+
+fn not_ready(detail: impl Into<String>) -> ApiError {
+    ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "not_ready").with_detail(detail)
+}
+
+async fn pending_migrations(pool: &PgPool) -> sqlx::Result<Vec<i64>> {
+    let applied = sqlx::query_scalar!("SELECT version FROM _sqlx_migrations WHERE success")
+        .fetch_all(pool)
+        .await?;
+    let expected: Vec<_> = MIGRATOR
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration())
+        .map(|m| m.version)
+        .collect();
+    Ok(pending(&expected, &applied))
+}
+
+/// Versions in `expected` not yet applied. Applied versions this build
+/// doesn't know are fine, they come from a newer build being rolled back.
+fn pending(expected: &[i64], applied: &[i64]) -> Vec<i64> {
+    let applied: HashSet<_> = applied.iter().collect();
+    expected.iter().copied().filter(|v| !applied.contains(v)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_pending_migrations() {
+        assert_eq!(pending(&[1, 2, 3], &[1, 2, 3]), Vec::<i64>::new());
+        assert_eq!(pending(&[1, 2, 3], &[1]), [2, 3]);
+        assert_eq!(pending(&[1, 2], &[1, 2, 4]), Vec::<i64>::new());
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use sqlx::PgPool;
+
+use crate::domain::key::KeyName;
+use crate::routes::api::messages::deliver;
+use crate::startup::AppState;
+use crate::telemetry;
+
+/// Most scheduled messages delivered in one transaction.
+const BATCH_SIZE: i64 = 100;
+
+/// Periodically delivers scheduled messages whose time has come, then
+/// notifies their recipients' push subscriptions. Returns once shutting
+/// down, after finishing the current batch.
+pub async fn run(state: AppState, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = state.shutdown.cancelled() => return,
+        }
+        let start = std::time::Instant::now();
+        loop {
+            match deliver_due(&state.pool).await {
+                Ok((n, recipients)) => {
+                    metrics::counter!("blindchannel_scheduled_delivered_total").increment(n as u64);
+                    for recipient in recipients {
+                        tokio::spawn(state.push.clone().notify(recipient));
+                    }
+                    if n < BATCH_SIZE as usize || state.shutdown.is_cancelled() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    telemetry::record_job_error("scheduler");
+                    tracing::error!("error delivering scheduled messages: {e}");
+                    break;
+                }
+            }
+        }
+        telemetry::record_job("scheduler", start.elapsed());
+    }
+}
+
+/// Delivers up to [`BATCH_SIZE`] due messages, returning how many were
+/// delivered and to whom. Rows are locked so that concurrent instances and
+/// cancellations skip or wait for them.
+#[tracing::instrument(skip(pool), name = "delivering scheduled messages")]
+async fn deliver_due(pool: &PgPool) -> sqlx::Result<(usize, HashSet<KeyName>)> {
+    let mut tx = pool.begin().await?;
+    // ordered by recipient like batches, so mailbox locks are taken in the same order
+    let due = sqlx::query!(
+        r#"SELECT id, recipient as "recipient!" FROM messages
+        WHERE seq IS NULL AND deliver_after <= now()
+        ORDER BY recipient, deliver_after, id
+        LIMIT $1 FOR UPDATE SKIP LOCKED"#,
+        BATCH_SIZE
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut recipients = HashSet::new();
+    for msg in &due {
+        let Ok(recipient) = KeyName::parse(msg.recipient.clone()) else {
+            tracing::error!("invalid recipient {} of message {}", msg.recipient, msg.id);
+            continue;
+        };
+        deliver(&mut tx, msg.id, &recipient).await?;
+        recipients.insert(recipient);
+    }
+    tx.commit().await?;
+    Ok((due.len(), recipients))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeDelta, Utc};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::domain::token::SecretToken;
+    use crate::routes::api::messages::{cancel_msg, insert_msg_in, CancelOutcome, Published};
+    use crate::testing::{message, register};
+
+    async fn publish(pool: &PgPool, scheduled: bool) -> Published {
+        let mut msg = message("alice", &["k1"]);
+        msg.deliver_after = scheduled.then(|| Utc::now() + TimeDelta::hours(1));
+        let mut tx = pool.begin().await.unwrap();
+        let published = insert_msg_in(&mut tx, msg, &Default::default()).await.unwrap();
+        tx.commit().await.unwrap();
+        published
+    }
+
+    async fn make_due(pool: &PgPool, id: Uuid) {
+        sqlx::query!("UPDATE messages SET deliver_after = now() WHERE id = $1", id)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    async fn seq(pool: &PgPool, id: Uuid) -> Option<i64> {
+        sqlx::query_scalar!("SELECT seq FROM messages WHERE id = $1", id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    fn cancel_token(published: &Published) -> SecretToken {
+        published.cancel_token.as_deref().unwrap().parse().unwrap()
+    }
+
+    #[sqlx::test]
+    async fn delivers_due_messages_after_immediate_ones(pool: PgPool) {
+        register(&pool, "alice", &["k1"]).await;
+        let first = publish(&pool, false).await;
+        let scheduled = publish(&pool, true).await;
+        let second = publish(&pool, false).await;
+
+        assert_eq!(deliver_due(&pool).await.unwrap().0, 0, "delivered ahead of time");
+        assert_eq!(seq(&pool, scheduled.id).await, None);
+
+        make_due(&pool, scheduled.id).await;
+        let (n, recipients) = deliver_due(&pool).await.unwrap();
+        assert_eq!(n, 1);
+        assert_eq!(recipients, HashSet::from([KeyName::parse("alice".to_owned()).unwrap()]));
+        assert_eq!(seq(&pool, first.id).await, Some(1));
+        assert_eq!(seq(&pool, second.id).await, Some(2));
+        assert_eq!(seq(&pool, scheduled.id).await, Some(3));
+        assert_eq!(deliver_due(&pool).await.unwrap().0, 0, "delivered twice");
+    }
+
+    #[sqlx::test]
+    async fn cancelled_messages_are_not_delivered(pool: PgPool) {
+        register(&pool, "alice", &["k1"]).await;
+        let scheduled = publish(&pool, true).await;
+        let wrong = SecretToken::generate();
+        assert_eq!(cancel_msg(&pool, scheduled.id, &wrong).await.unwrap(), CancelOutcome::NotFound);
+
+        let token = cancel_token(&scheduled);
+        assert_eq!(cancel_msg(&pool, scheduled.id, &token).await.unwrap(), CancelOutcome::Cancelled);
+        assert_eq!(cancel_msg(&pool, scheduled.id, &token).await.unwrap(), CancelOutcome::Cancelled);
+        make_due(&pool, scheduled.id).await;
+        assert_eq!(deliver_due(&pool).await.unwrap().0, 0);
+        let last_seq = sqlx::query_scalar!("SELECT last_seq FROM keymap WHERE name = 'alice'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(last_seq, 0);
+    }
+
+    #[sqlx::test]
+    async fn delivered_messages_cannot_be_cancelled(pool: PgPool) {
+        register(&pool, "alice", &["k1"]).await;
+        let scheduled = publish(&pool, true).await;
+        make_due(&pool, scheduled.id).await;
+        assert_eq!(deliver_due(&pool).await.unwrap().0, 1);
+
+        let token = cancel_token(&scheduled);
+        assert_eq!(cancel_msg(&pool, scheduled.id, &token).await.unwrap(), CancelOutcome::Delivered);
+        assert_eq!(seq(&pool, scheduled.id).await, Some(1));
+    }
+}
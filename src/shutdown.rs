@@ -0,0 +1,36 @@
+//! Graceful shutdown. Once SIGTERM or SIGINT arrives the shutdown token is
+//! cancelled: listeners stop accepting connections, and streams,
+//! long-polls and background jobs end at their next opportunity. Requests
+//! still running are waited for up to a deadline.
+
+use std::time::Duration;
+
+use axum_server::Handle;
+use tokio::signal::unix::{signal as unix_signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+
+/// Resolves once the process is asked to stop.
+pub async fn signal() {
+    let mut terminate = unix_signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => tracing::info!("received SIGTERM, shutting down"),
+        _ = tokio::signal::ctrl_c() => tracing::info!("received SIGINT, shutting down"),
+    }
+}
+
+/// A handle for a listener, which stops accepting connections once `token`
+/// is cancelled and closes those still open after `deadline`.
+pub fn drain_on(token: &CancellationToken, deadline: Duration) -> Handle {
+    let handle = Handle::new();
+    let draining = handle.clone();
+    let token = token.clone();
+    tokio::spawn(async move {
+        token.cancelled().await;
+        draining.graceful_shutdown(Some(deadline));
+        tokio::time::sleep(deadline).await;
+        if draining.connection_count() > 0 {
+            tracing::warn!("requests still running after {deadline:?}, dropping them");
+        }
+    });
+    handle
+}
@@ -1,21 +1,210 @@
+use std::net::SocketAddr;
+
+use axum::extract::FromRef;
+use axum::middleware;
+use axum::routing::get;
 use axum::Router;
-use tokio::net::ToSocketAddrs;
+use axum_server::tls_rustls::RustlsConfig;
+use sqlx::PgPool;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+use crate::blobs::BlobStore;
+use crate::configuration::{
+    ApplicationSettings, DropSettings, IdempotencySettings, MessageSettings, SearchSettings, Settings,
+    WebhookSettings,
+};
+use crate::events::MailboxEvents;
+use crate::keycache::{self, KeyCache};
+use crate::pow::Hashcash;
+use crate::telemetry::{self, Metrics};
+use crate::tokens::TokenIssuer;
+use crate::push::PushService;
+use crate::{hardening, reaper, routes, scheduler, shutdown, tls, webhooks};
 
-use crate::routes;
+/// State shared by every handler.
+#[derive(Clone)]
+pub struct AppState {
+    pub pool: PgPool,
+    pub blobs: BlobStore,
+    pub messages: MessageSettings,
+    pub events: MailboxEvents,
+    pub webhooks: WebhookSettings,
+    pub push: PushService,
+    pub idempotency: IdempotencySettings,
+    pub drops: DropSettings,
+    pub pow: Hashcash,
+    pub tokens: TokenIssuer,
+    pub metrics: Metrics,
+    pub keys: KeyCache,
+    pub search: SearchSettings,
+    /// cancelled once the server shuts down
+    pub shutdown: CancellationToken,
+}
 
-pub fn application(pool: sqlx::PgPool) -> Router {
-    Router::new()
-        .nest("/api", routes::api::router())
-        .with_state(pool)
-        .layer(tower_http::trace::TraceLayer::new_for_http())
+impl AppState {
+    pub fn new(pool: PgPool, settings: &Settings, events: MailboxEvents, shutdown: CancellationToken) -> Self {
+        Self {
+            blobs: BlobStore::new(pool.clone(), settings.blobs.clone()),
+            messages: settings.messages.clone(),
+            webhooks: settings.webhooks.clone(),
+            push: PushService::new(pool.clone(), &settings.push),
+            idempotency: settings.idempotency.clone(),
+            drops: settings.drops.clone(),
+            pow: Hashcash::new(&settings.pow),
+            tokens: TokenIssuer::new(&settings.tokens).expect("failed to load the token issuer key"),
+            keys: KeyCache::new(&settings.registry),
+            search: settings.search.clone(),
+            metrics: Metrics::install(&settings.metrics).expect("failed to set up metrics"),
+            events,
+            shutdown,
+            pool,
+        }
+    }
+}
+impl FromRef<AppState> for PgPool {
+    fn from_ref(state: &AppState) -> Self {
+        state.pool.clone()
+    }
+}
+impl FromRef<AppState> for BlobStore {
+    fn from_ref(state: &AppState) -> Self {
+        state.blobs.clone()
+    }
+}
+impl FromRef<AppState> for MailboxEvents {
+    fn from_ref(state: &AppState) -> Self {
+        state.events.clone()
+    }
+}
+impl FromRef<AppState> for MessageSettings {
+    fn from_ref(state: &AppState) -> Self {
+        state.messages.clone()
+    }
+}
+impl FromRef<AppState> for PushService {
+    fn from_ref(state: &AppState) -> Self {
+        state.push.clone()
+    }
+}
+impl FromRef<AppState> for IdempotencySettings {
+    fn from_ref(state: &AppState) -> Self {
+        state.idempotency.clone()
+    }
+}
+impl FromRef<AppState> for WebhookSettings {
+    fn from_ref(state: &AppState) -> Self {
+        state.webhooks.clone()
+    }
+}
+impl FromRef<AppState> for DropSettings {
+    fn from_ref(state: &AppState) -> Self {
+        state.drops.clone()
+    }
+}
+impl FromRef<AppState> for Hashcash {
+    fn from_ref(state: &AppState) -> Self {
+        state.pow.clone()
+    }
+}
+impl FromRef<AppState> for TokenIssuer {
+    fn from_ref(state: &AppState) -> Self {
+        state.tokens.clone()
+    }
+}
+impl FromRef<AppState> for Metrics {
+    fn from_ref(state: &AppState) -> Self {
+        state.metrics.clone()
+    }
+}
+impl FromRef<AppState> for KeyCache {
+    fn from_ref(state: &AppState) -> Self {
+        state.keys.clone()
+    }
+}
+impl FromRef<AppState> for SearchSettings {
+    fn from_ref(state: &AppState) -> Self {
+        state.search.clone()
+    }
+}
+impl FromRef<AppState> for CancellationToken {
+    fn from_ref(state: &AppState) -> Self {
+        state.shutdown.clone()
+    }
+}
+
+pub fn application(state: AppState, settings: &ApplicationSettings) -> Router {
+    let api = routes::api::router(&state, settings);
+    let app = Router::new()
+        .nest(routes::api::openapi::V1, api)
+        .route("/api/openapi.json", get(routes::api::openapi::openapi_json))
+        .with_state(state.clone());
+    hardening::protect(app, &settings.limits)
+        // not shed, an overloaded server is still alive and ready
+        .merge(routes::health::router().with_state(state))
+        .layer(middleware::from_fn(telemetry::track_requests))
+        .layer(tower_http::trace::TraceLayer::new_for_http().make_span_with(telemetry::request_span))
         .fallback_service(routes::ui::ui_server())
 }
 
-pub async fn run(addr: impl ToSocketAddrs, pool: sqlx::PgPool) {
-    let app = application(pool);
-    let listener = tokio::net::TcpListener::bind(addr)
-        .await
-        .expect("failed to bind to address");
+/// Serves until SIGTERM or SIGINT, then drains in-flight requests and
+/// background jobs for up to the drain timeout each, and closes the pool.
+pub async fn run(pool: PgPool, settings: Settings) {
+    let shutdown = CancellationToken::new();
+    let (events, listener) = MailboxEvents::new(pool.clone());
+    let events_listener = tokio::spawn(listener.run());
+    let state = AppState::new(pool.clone(), &settings, events, shutdown.clone());
+    let jobs = TaskTracker::new();
+    jobs.spawn(reaper::run(state.clone(), settings.reaper.interval()));
+    jobs.spawn(scheduler::run(state.clone(), settings.messages.schedule_interval()));
+    jobs.spawn(webhooks::run(pool.clone(), settings.webhooks.clone(), shutdown.clone()));
+    jobs.spawn(keycache::listen(state.keys.clone(), pool.clone(), shutdown.clone()));
+    jobs.spawn(telemetry::record_gauges(pool.clone(), settings.metrics.gauge_interval(), shutdown.clone()));
+
+    let app = application(state, &settings.application);
+    let listener = bind(&settings.application.host, settings.application.port);
+    let drain_timeout = settings.application.shutdown.drain_timeout();
+    let handle = shutdown::drain_on(&shutdown, drain_timeout);
+    let signalled = shutdown.clone();
+    tokio::spawn(async move {
+        shutdown::signal().await;
+        signalled.cancel();
+    });
+    // client addresses key the rate limits
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    let served = match &settings.application.tls {
+        None => axum_server::from_tcp(listener).handle(handle).serve(service).await,
+        Some(tls_settings) => {
+            let config = tls::load(tls_settings).await.expect("failed to load the TLS certificate");
+            let config = RustlsConfig::from_config(config);
+            jobs.spawn(tls::reload(config.clone(), tls_settings.clone(), shutdown.clone()));
+            if let Some(port) = tls_settings.redirect_port {
+                let redirects = bind(&settings.application.host, port);
+                let handle = shutdown::drain_on(&shutdown, drain_timeout);
+                let app = tls::redirect(settings.application.port);
+                jobs.spawn(async move {
+                    if let Err(e) = axum_server::from_tcp(redirects).handle(handle).serve(app.into_make_service()).await {
+                        tracing::error!("HTTPS redirect listener failed: {e}");
+                    }
+                });
+            }
+            axum_server::from_tcp_rustls(listener, config).handle(handle).serve(service).await
+        }
+    };
+    served.expect("server failed");
+    jobs.close();
+
+    if tokio::time::timeout(drain_timeout, jobs.wait()).await.is_err() {
+        tracing::warn!("background jobs still running after {drain_timeout:?}, dropping them");
+    }
+    events_listener.abort();
+    pool.close().await;
+    tracing::info!("shut down");
+}
+
+fn bind(host: &str, port: u16) -> std::net::TcpListener {
+    let listener = std::net::TcpListener::bind((host, port)).expect("failed to bind to address");
+    listener.set_nonblocking(true).expect("failed to make the listener non-blocking");
     tracing::info!("running on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+    listener
 }
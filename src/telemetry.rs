@@ -1,4 +1,18 @@
-use crate::configuration::{self, Environment};
+use std::time::Instant;
+
+use axum::extract::{MatchedPath, Request};
+use axum::http::Method;
+use tracing::Span;
+use axum::middleware::Next;
+use axum::response::Response;
+use metrics::{describe_counter, describe_gauge, describe_histogram, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use secrecy::ExposeSecret;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use tokio_util::sync::CancellationToken;
+
+use crate::configuration::{self, Environment, MetricsSettings};
 use tracing_appender::non_blocking;
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_subscriber::{filter::LevelFilter, EnvFilter};
@@ -39,3 +53,250 @@ pub fn init_test_subscriber() {
         .from_env_lossy();
     let _ = tracing_subscriber::fmt().with_env_filter(filter).try_init();
 }
+
+/// Smallest sizes of the mailbox size buckets after the first, which holds
+/// empty mailboxes. Counting aliases per bucket rather than reporting each
+/// mailbox keeps who receives how much private.
+const MAILBOX_BUCKETS: [i64; 5] = [1, 2, 11, 101, 1001];
+const MAILBOX_BUCKET_LABELS: [&str; 6] = ["0", "1", "2-10", "11-100", "101-1000", "1001+"];
+
+/// Bounds of latency histograms, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Renders what is recorded with the `metrics` macros in the Prometheus
+/// text format. Metrics are recorded from anywhere, like logs are with
+/// `tracing`, but never labelled with an alias.
+#[derive(Clone)]
+pub struct Metrics {
+    handle: PrometheusHandle,
+    token_digest: Option<[u8; 32]>,
+    /// whether anyone may scrape without a token
+    public: bool,
+}
+
+impl Metrics {
+    /// Installs the global recorder, which can only be done once.
+    pub fn install(settings: &MetricsSettings) -> Result<Self, String> {
+        let handle = recorder()
+            .install_recorder()
+            .map_err(|e| format!("failed to install metrics recorder: {e}"))?;
+        describe_metrics();
+        let public = configuration::get_environment().is_local();
+        if !public && settings.bearer_token.is_none() {
+            tracing::warn!("no metrics token configured, metrics are not served");
+        }
+        Ok(Self::new(handle, settings, public))
+    }
+    fn new(handle: PrometheusHandle, settings: &MetricsSettings, public: bool) -> Self {
+        Self {
+            handle,
+            token_digest: settings
+                .bearer_token
+                .as_ref()
+                .map(|t| Sha256::digest(t.expose_secret().as_bytes()).into()),
+            public,
+        }
+    }
+
+    /// Whether `token` may scrape the metrics. Digests are compared so the
+    /// time taken doesn't tell how much of the token was right.
+    pub fn authorizes(&self, token: Option<&str>) -> bool {
+        match (&self.token_digest, token) {
+            (None, _) => self.public,
+            (Some(expected), Some(token)) => Sha256::digest(token.as_bytes())[..] == expected[..],
+            (Some(_), None) => false,
+        }
+    }
+
+    pub fn render(&self) -> String {
+        self.handle.render()
+    }
+}
+
+fn recorder() -> PrometheusBuilder {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".into()), LATENCY_BUCKETS)
+        .expect("buckets are not empty")
+}
+
+fn describe_metrics() {
+    describe_counter!("blindchannel_http_requests_total", "HTTP requests by method, route and status");
+    describe_histogram!(
+        "blindchannel_http_request_duration_seconds",
+        Unit::Seconds,
+        "Time until the response headers were sent, by method, route and status"
+    );
+    describe_counter!("blindchannel_aliases_registered_total", "Aliases registered");
+    describe_counter!("blindchannel_messages_published_total", "Messages published, alone or in batches");
+    describe_counter!("blindchannel_messages_fetched_total", "Messages handed to recipients, by transport");
+    describe_counter!("blindchannel_key_cache_lookups_total", "Key set lookups by whether the cache had them");
+    describe_gauge!("blindchannel_db_pool_connections", "Database connections by state");
+    describe_gauge!("blindchannel_db_pool_max_connections", "Most database connections the pool opens");
+    describe_gauge!("blindchannel_mailboxes", "Aliases by how many messages wait in their mailbox");
+    describe_counter!("blindchannel_job_runs_total", "Runs of background jobs");
+    describe_counter!("blindchannel_job_errors_total", "Errors of background jobs");
+    describe_histogram!("blindchannel_job_duration_seconds", Unit::Seconds, "Duration of background job runs");
+    describe_counter!("blindchannel_reaped_total", "Rows and files deleted by the reaper, by kind");
+    describe_counter!("blindchannel_scheduled_delivered_total", "Scheduled messages delivered");
+    describe_counter!("blindchannel_webhook_attempts_total", "Webhook delivery attempts by outcome");
+}
+
+/// Counts and times requests by route pattern, so aliases in paths never
+/// become labels.
+pub async fn track_requests(req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", MatchedPath::as_str)
+        .to_owned();
+    let method = match *req.method() {
+        Method::GET | Method::HEAD | Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+        | Method::OPTIONS => req.method().to_string(),
+        _ => "other".to_owned(),
+    };
+    let start = Instant::now();
+    let response = next.run(req).await;
+    let labels = [
+        ("method", method),
+        ("route", route),
+        ("status", response.status().as_u16().to_string()),
+    ];
+    metrics::counter!("blindchannel_http_requests_total", &labels).increment(1);
+    metrics::histogram!("blindchannel_http_request_duration_seconds", &labels).record(start.elapsed());
+    response
+}
+
+/// Span of a request, like `TraceLayer`'s default but with the tokens of
+/// drop URLs redacted, as they are credentials.
+pub fn request_span(req: &Request) -> Span {
+    let uri = match req.uri().query() {
+        Some(query) => format!("{}?{query}", redact(req.uri().path())),
+        None => redact(req.uri().path()),
+    };
+    tracing::debug_span!("request", method = %req.method(), uri = %uri, version = ?req.version())
+}
+
+/// Replaces the segment after `drops/` or `drops/inbox/` in `path`.
+fn redact(path: &str) -> String {
+    let mut segments: Vec<&str> = path.split('/').collect();
+    for i in 1..segments.len() {
+        let token = match segments[i - 1] {
+            "drops" => segments[i] != "inbox",
+            "inbox" => i > 1 && segments[i - 2] == "drops",
+            _ => false,
+        };
+        if token && !segments[i].is_empty() {
+            segments[i] = "{token}";
+        }
+    }
+    segments.join("/")
+}
+
+/// Periodically updates gauges counted from the database, so scrapes
+/// don't query it. Returns once shutting down.
+pub async fn record_gauges(pool: PgPool, interval: std::time::Duration, shutdown: CancellationToken) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+        let start = Instant::now();
+        if let Err(e) = record_mailboxes(&pool).await {
+            record_job_error("gauges");
+            tracing::warn!("error counting mailbox sizes: {e}");
+        }
+        record_job("gauges", start.elapsed());
+    }
+}
+
+async fn record_mailboxes(pool: &PgPool) -> sqlx::Result<()> {
+    let rows = sqlx::query!(
+        r#"SELECT width_bucket(n, $1::bigint[]) as "bucket!", count(*) as "aliases!" FROM (
+            SELECT count(m.id) as n FROM keymap k
+            LEFT JOIN messages m ON m.recipient = k.name AND m.seq IS NOT NULL
+            GROUP BY k.name
+        ) sizes GROUP BY 1"#,
+        &MAILBOX_BUCKETS
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut counts = [0; MAILBOX_BUCKET_LABELS.len()];
+    for row in rows {
+        counts[row.bucket as usize] = row.aliases;
+    }
+    for (label, count) in MAILBOX_BUCKET_LABELS.into_iter().zip(counts) {
+        metrics::gauge!("blindchannel_mailboxes", "size" => label).set(count as f64);
+    }
+    Ok(())
+}
+
+/// Records a run of a background job taking `elapsed`.
+pub fn record_job(job: &'static str, elapsed: std::time::Duration) {
+    metrics::counter!("blindchannel_job_runs_total", "job" => job).increment(1);
+    metrics::histogram!("blindchannel_job_duration_seconds", "job" => job).record(elapsed);
+}
+
+/// Records an error of a background job.
+pub fn record_job_error(job: &'static str) {
+    metrics::counter!("blindchannel_job_errors_total", "job" => job).increment(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn labels_requests_by_route_pattern() {
+        let recorder = recorder().build_recorder();
+        let handle = recorder.handle();
+        let app = Router::new()
+            .route("/registry/:alias", get(|| async {}))
+            .layer(axum::middleware::from_fn(track_requests));
+        let request = Request::get("/registry/alice").body(Body::empty()).unwrap();
+        // the test runtime polls everything on this thread
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        app.oneshot(request).await.unwrap();
+        let rendered = handle.render();
+        assert!(rendered.contains(
+            r#"blindchannel_http_requests_total{method="GET",route="/registry/:alias",status="200"} 1"#
+        ));
+        assert!(!rendered.contains("alice"));
+        assert!(rendered.contains("blindchannel_http_request_duration_seconds_bucket"));
+    }
+
+    #[test]
+    fn drop_tokens_are_redacted() {
+        assert_eq!(redact("/api/v1/drops/secret"), "/api/v1/drops/{token}");
+        assert_eq!(redact("/api/v1/drops/secret/messages"), "/api/v1/drops/{token}/messages");
+        assert_eq!(redact("/api/v1/drops/inbox/secret"), "/api/v1/drops/inbox/{token}");
+        assert_eq!(redact("/api/v1/drops"), "/api/v1/drops");
+        assert_eq!(redact("/api/v1/registry/inbox/keys"), "/api/v1/registry/inbox/keys");
+    }
+
+    #[test]
+    fn bearer_token_guards_metrics() {
+        let handle = recorder().build_recorder().handle();
+        let open = Metrics::new(handle.clone(), &MetricsSettings::default(), true);
+        assert!(open.authorizes(None));
+        let closed = Metrics::new(handle.clone(), &MetricsSettings::default(), false);
+        assert!(!closed.authorizes(None));
+        assert!(!closed.authorizes(Some("")));
+        let guarded = Metrics::new(
+            handle,
+            &MetricsSettings {
+                bearer_token: Some("scraper".to_owned().into()),
+                ..Default::default()
+            },
+            false,
+        );
+        assert!(guarded.authorizes(Some("scraper")));
+        assert!(!guarded.authorizes(Some("scrape")));
+        assert!(!guarded.authorizes(None));
+    }
+}
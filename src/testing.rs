@@ -0,0 +1,59 @@
+//! Fixtures for tests run against a fresh, migrated database each, with
+//! `#[sqlx::test]`.
+
+use std::collections::BTreeMap;
+
+use sqlx::PgPool;
+
+use crate::domain::key::{Algorithm, KeyName, KeyType, KeyUse, Kid, PublicExponent, PublicJwk};
+use crate::domain::token::SecretToken;
+use crate::routes::api::messages::PublishMessage;
+
+pub fn public_key() -> PublicJwk {
+    PublicJwk {
+        e: PublicExponent,
+        alg: Algorithm::RsaOaep256,
+        kty: KeyType::Rsa,
+        key_use: KeyUse::Enc,
+        n: include_str!("domain/n").parse().unwrap(),
+    }
+}
+
+/// Registers `alias` with a device key for each of `kids`, returning its
+/// owner token.
+pub async fn register(pool: &PgPool, alias: &str, kids: &[&str]) -> SecretToken {
+    let token = SecretToken::generate();
+    sqlx::query!(
+        "INSERT INTO keymap (name, owner_token_hash) VALUES ($1, $2)",
+        alias,
+        token.digest()
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+    for kid in kids {
+        sqlx::query!(
+            "INSERT INTO device_keys (alias, kid, public_key) VALUES ($1, $2, $3)",
+            alias,
+            kid,
+            serde_json::to_value(public_key()).unwrap()
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+    token
+}
+
+/// A message to `recipient` with a copy for each of `kids`.
+pub fn message(recipient: &str, kids: &[&str]) -> PublishMessage {
+    PublishMessage {
+        content: kids
+            .iter()
+            .map(|kid| (Kid::parse(kid.to_string()).unwrap(), "AAAA".to_owned()))
+            .collect::<BTreeMap<_, _>>(),
+        recipient: KeyName::parse(recipient.to_owned()).unwrap(),
+        attachments: Vec::new(),
+        deliver_after: None,
+    }
+}
@@ -0,0 +1,162 @@
+//! TLS terminated by the server itself with rustls, for running without a
+//! reverse proxy.
+//!
+//! The certificate and key are read again on SIGHUP and whenever either
+//! file changes, so renewed certificates are picked up without a restart.
+//! Connections already open keep the certificate they were made with.
+//! A certificate that fails to load is logged and the previous one kept.
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use axum::extract::State;
+use axum::http::uri::{Authority, Scheme};
+use axum::http::{header, HeaderMap, StatusCode, Uri};
+use axum::response::Redirect;
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+
+use crate::configuration::{TlsSettings, TlsVersion};
+use crate::routes::api::error::ApiError;
+
+/// Reads the certificate chain and key into a server configuration.
+pub async fn load(settings: &TlsSettings) -> Result<Arc<ServerConfig>, String> {
+    let cert = read(&settings.cert_path).await?;
+    let key = read(&settings.key_path).await?;
+    server_config(&cert, &key, settings.min_version).map(Arc::new)
+}
+
+async fn read(path: &Path) -> Result<Vec<u8>, String> {
+    tokio::fs::read(path)
+        .await
+        .map_err(|e| format!("failed to read {}: {e}", path.display()))
+}
+
+fn server_config(cert: &[u8], key: &[u8], min_version: TlsVersion) -> Result<ServerConfig, String> {
+    let chain = rustls_pemfile::certs(&mut &cert[..])
+        .collect::<Result<Vec<CertificateDer>, _>>()
+        .map_err(|e| format!("invalid certificate: {e}"))?;
+    if chain.is_empty() {
+        return Err("no certificate found in the certificate file".into());
+    }
+    let key: PrivateKeyDer = rustls_pemfile::private_key(&mut &key[..])
+        .map_err(|e| format!("invalid private key: {e}"))?
+        .ok_or("no private key found in the key file")?;
+    let versions: &[_] = match min_version {
+        TlsVersion::V1_2 => &[&rustls::version::TLS13, &rustls::version::TLS12],
+        TlsVersion::V1_3 => &[&rustls::version::TLS13],
+    };
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_protocol_versions(versions)
+        .map_err(|e| format!("unsupported TLS versions: {e}"))?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .map_err(|e| format!("the key does not match the certificate: {e}"))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Reloads `config` on SIGHUP and when the certificate or key files change,
+/// until `shutdown` is cancelled.
+pub async fn reload(config: RustlsConfig, settings: TlsSettings, shutdown: CancellationToken) {
+    let mut hangup = signal(SignalKind::hangup()).expect("failed to listen for SIGHUP");
+    let mut ticker = tokio::time::interval(settings.reload_interval());
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut loaded = modified(&settings).await;
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => tracing::info!("received SIGHUP, reloading the TLS certificate"),
+            _ = ticker.tick() => {
+                let current = modified(&settings).await;
+                if current == loaded {
+                    continue;
+                }
+                tracing::info!("TLS certificate or key changed, reloading");
+            }
+            _ = shutdown.cancelled() => return,
+        }
+        // remembered even if loading fails, so a broken file is reported once
+        loaded = modified(&settings).await;
+        match load(&settings).await {
+            Ok(new) => config.reload_from_config(new),
+            Err(e) => tracing::error!("keeping the previous TLS certificate: {e}"),
+        }
+    }
+}
+
+/// Modification times of the certificate and key. Symbolic links are
+/// followed, as certificate managers swap what they point to.
+async fn modified(settings: &TlsSettings) -> [Option<SystemTime>; 2] {
+    let mtime = |path| async move { tokio::fs::metadata(path).await.and_then(|m| m.modified()).ok() };
+    [mtime(&settings.cert_path).await, mtime(&settings.key_path).await]
+}
+
+/// Answers every plain HTTP request with a permanent redirect to the same
+/// URL over HTTPS on `https_port`.
+pub fn redirect(https_port: u16) -> Router {
+    Router::new().fallback(to_https).with_state(https_port)
+}
+
+async fn to_https(State(https_port): State<u16>, headers: HeaderMap, uri: Uri) -> Result<Redirect, ApiError> {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse::<Authority>().ok())
+        .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "invalid_host").with_detail("a valid `Host` is required"))?;
+    let authority = match https_port {
+        443 => host.host().to_owned(),
+        port => format!("{}:{port}", host.host()),
+    };
+    let mut parts = uri.into_parts();
+    parts.scheme = Some(Scheme::HTTPS);
+    parts.authority = Some(authority.parse().map_err(|_| ApiError::bad_request("invalid_host"))?);
+    if parts.path_and_query.is_none() {
+        parts.path_and_query = Some("/".parse().expect("valid path"));
+    }
+    let target = Uri::from_parts(parts).map_err(|_| ApiError::bad_request("invalid_host"))?;
+    Ok(Redirect::permanent(&target.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    async fn redirect_location(https_port: u16, host: &str, uri: &str) -> (StatusCode, Option<String>) {
+        let request = Request::get(uri).header(header::HOST, host).body(Body::empty()).unwrap();
+        let response = redirect(https_port).oneshot(request).await.unwrap();
+        let location = response
+            .headers()
+            .get(header::LOCATION)
+            .map(|l| l.to_str().unwrap().to_owned());
+        (response.status(), location)
+    }
+
+    #[tokio::test]
+    async fn redirects_to_https() {
+        assert_eq!(
+            redirect_location(443, "example.com:80", "/api/v1/registry?q=a").await,
+            (StatusCode::PERMANENT_REDIRECT, Some("https://example.com/api/v1/registry?q=a".into()))
+        );
+        assert_eq!(
+            redirect_location(8443, "example.com", "/").await,
+            (StatusCode::PERMANENT_REDIRECT, Some("https://example.com:8443/".into()))
+        );
+        assert_eq!(redirect_location(443, "bad host", "/").await.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn rejects_missing_or_mismatched_keys() {
+        let cert = b"-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n";
+        assert!(server_config(b"", b"", TlsVersion::V1_2).unwrap_err().contains("no certificate"));
+        assert!(server_config(cert, b"", TlsVersion::V1_2).is_err());
+    }
+}
@@ -0,0 +1,231 @@
+//! Privacy Pass style anonymous tokens: RSA blind signatures (Chaum) over a
+//! full domain hash of a random nonce.
+//!
+//! A client picks a nonce, hashes it with [`token_input`] and
+//! [`full_domain_hash`], multiplies the result by `r^e` for a random `r` and
+//! has the server sign that. Dividing the signature by `r` leaves a
+//! signature of the nonce the server never saw, so it cannot tell which
+//! issuance a redeemed token came from. Tokens are `nonce || signature`,
+//! and each one can be spent once.
+//!
+//! Blind signing is a raw RSA private key operation on a value of the
+//! client's choosing, as is decryption. The `rsa` crate's is not constant
+//! time (RUSTSEC-2023-0071, the Marvin attack), so enough timed signatures
+//! may let a client recover the key, and it has no fix yet. Randomized
+//! blinding of each operation makes that costly, and beyond it: the key is
+//! dedicated to tokens, so a recovered one forges tokens but decrypts
+//! nothing, and `/tokens/issue` is rate limited per client and paid for
+//! with proof of work, which bounds how many timings a client can gather.
+//! Rotating the key, which voids unspent tokens, ends any such attempt.
+
+use std::sync::Arc;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as b64;
+use base64::Engine;
+use rsa::hazmat::{rsa_decrypt_and_check, rsa_encrypt};
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::traits::PublicKeyParts;
+use rsa::{BigUint, RsaPrivateKey, RsaPublicKey};
+use secrecy::ExposeSecret;
+use sha2::{Digest, Sha256};
+
+use crate::configuration::TokenSettings;
+
+pub const NONCE_LEN: usize = 32;
+const DOMAIN: &[u8] = b"blindchannel token v1";
+
+/// Signs blinded tokens and checks redeemed ones.
+#[derive(Clone)]
+pub struct TokenIssuer {
+    key: Arc<RsaPrivateKey>,
+    key_id: [u8; 32],
+    settings: TokenSettings,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenError {
+    Malformed,
+    InvalidSignature,
+}
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "expected a base64url encoded nonce and signature"),
+            Self::InvalidSignature => write!(f, "the token was not issued by this server's current key"),
+        }
+    }
+}
+
+/// A redeemable token, to be marked spent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub nonce: [u8; NONCE_LEN],
+}
+
+impl TokenIssuer {
+    pub fn new(settings: &TokenSettings) -> Result<Self, String> {
+        let key = match &settings.issuer_key {
+            Some(pem) => RsaPrivateKey::from_pkcs8_pem(pem.expose_secret())
+                .map_err(|e| format!("invalid token issuer key: {e}"))?,
+            None => {
+                tracing::warn!("no token issuer key configured, tokens only redeem on this instance");
+                RsaPrivateKey::new(&mut rand::thread_rng(), settings.generated_key_bits)
+                    .map_err(|e| format!("failed to generate token issuer key: {e}"))?
+            }
+        };
+        Ok(Self::with_key(key, settings))
+    }
+    pub fn with_key(key: RsaPrivateKey, settings: &TokenSettings) -> Self {
+        let key_id = Sha256::digest(key.to_public_key().n().to_bytes_be()).into();
+        Self {
+            key: Arc::new(key),
+            key_id,
+            settings: settings.clone(),
+        }
+    }
+
+    /// Zero bits of proof of work a batch of tokens costs.
+    pub fn issue_difficulty(&self) -> u8 {
+        self.settings.issue_difficulty
+    }
+    pub fn max_batch(&self) -> usize {
+        self.settings.max_batch
+    }
+    /// Whether the key comes from the configuration, rather than being
+    /// generated at startup.
+    pub fn is_configured(&self) -> bool {
+        self.settings.issuer_key.is_some()
+    }
+
+    pub fn public_key(&self) -> RsaPublicKey {
+        self.key.to_public_key()
+    }
+    /// SHA-256 of the modulus, which clients bind their tokens to.
+    pub fn key_id(&self) -> &[u8; 32] {
+        &self.key_id
+    }
+    fn modulus_len(&self) -> usize {
+        self.key.size()
+    }
+
+    /// Signs a blinded message, learning nothing about the token within.
+    /// Its timing may leak the key, see the module docs.
+    pub fn blind_sign(&self, blinded: &[u8]) -> Result<Vec<u8>, TokenError> {
+        let m = BigUint::from_bytes_be(blinded);
+        if blinded.len() != self.modulus_len() || &m >= self.key.n() {
+            return Err(TokenError::Malformed);
+        }
+        let s = rsa_decrypt_and_check(&*self.key, Some(&mut rand::thread_rng()), &m)
+            .map_err(|_| TokenError::Malformed)?;
+        Ok(left_pad(&s, self.modulus_len()))
+    }
+
+    /// Checks a token's signature. Whether it was spent is up to the caller.
+    pub fn verify(&self, token: &str) -> Result<Token, TokenError> {
+        let bytes = b64.decode(token).map_err(|_| TokenError::Malformed)?;
+        if bytes.len() != NONCE_LEN + self.modulus_len() {
+            return Err(TokenError::Malformed);
+        }
+        let (nonce, signature) = bytes.split_at(NONCE_LEN);
+        let s = BigUint::from_bytes_be(signature);
+        if &s >= self.key.n() {
+            return Err(TokenError::InvalidSignature);
+        }
+        let public = self.public_key();
+        let expected = full_domain_hash(&token_input(&self.key_id, nonce), self.modulus_len());
+        let m = rsa_encrypt(&public, &s).map_err(|_| TokenError::InvalidSignature)?;
+        if m != expected {
+            return Err(TokenError::InvalidSignature);
+        }
+        Ok(Token {
+            nonce: nonce.try_into().expect("split at NONCE_LEN"),
+        })
+    }
+}
+
+/// What is signed for a token: the nonce, bound to the issuer key.
+pub fn token_input(key_id: &[u8; 32], nonce: &[u8]) -> Vec<u8> {
+    [DOMAIN, key_id, nonce].concat()
+}
+
+/// MGF1 with SHA-256 expanding `input` to one byte less than the modulus,
+/// so the result is always smaller than it.
+pub fn full_domain_hash(input: &[u8], modulus_len: usize) -> BigUint {
+    let len = modulus_len - 1;
+    let mut out = Vec::with_capacity(len + 32);
+    for counter in 0u32.. {
+        if out.len() >= len {
+            break;
+        }
+        out.extend_from_slice(&Sha256::new().chain_update(input).chain_update(counter.to_be_bytes()).finalize());
+    }
+    out.truncate(len);
+    BigUint::from_bytes_be(&out)
+}
+
+fn left_pad(n: &BigUint, len: usize) -> Vec<u8> {
+    let bytes = n.to_bytes_be();
+    let mut padded = vec![0; len - bytes.len()];
+    padded.extend_from_slice(&bytes);
+    padded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_bigint_dig::{ModInverse, RandBigInt};
+    use rand::RngCore;
+
+    fn issuer() -> TokenIssuer {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        TokenIssuer::with_key(key, &TokenSettings::default())
+    }
+
+    /// What a client does: blinds a fresh nonce, has it signed and unblinds the signature.
+    fn obtain(issuer: &TokenIssuer) -> String {
+        let public = issuer.public_key();
+        let len = public.size();
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let m = full_domain_hash(&token_input(issuer.key_id(), &nonce), len);
+        let (r, r_inv) = loop {
+            let r = rand::thread_rng().gen_biguint_below(public.n());
+            if let Some(inv) = (&r).mod_inverse(public.n()) {
+                break (r, inv.to_biguint().unwrap());
+            }
+        };
+        let blinded = (m * r.modpow(public.e(), public.n())) % public.n();
+        let signed = BigUint::from_bytes_be(&issuer.blind_sign(&left_pad(&blinded, len)).unwrap());
+        let signature = (signed * r_inv) % public.n();
+        let token = [&nonce[..], &left_pad(&signature, len)].concat();
+        b64.encode(token)
+    }
+
+    #[test]
+    fn unblinded_tokens_verify() {
+        let issuer = issuer();
+        let token = obtain(&issuer);
+        let redeemed = issuer.verify(&token).unwrap();
+        assert_eq!(redeemed.nonce[..], b64.decode(token).unwrap()[..NONCE_LEN]);
+    }
+
+    #[test]
+    fn rejects_forged_tokens() {
+        let issuer = issuer();
+        let token = obtain(&issuer);
+        let mut bytes = b64.decode(&token).unwrap();
+        bytes[0] ^= 1;
+        assert_eq!(issuer.verify(&b64.encode(&bytes)), Err(TokenError::InvalidSignature));
+        assert_eq!(issuer.verify("AAAA"), Err(TokenError::Malformed));
+        // another issuer's tokens are worthless
+        assert_eq!(self::issuer().verify(&token), Err(TokenError::InvalidSignature));
+    }
+
+    #[test]
+    fn refuses_to_sign_out_of_range_messages() {
+        let issuer = issuer();
+        let n = issuer.public_key().n().to_bytes_be();
+        assert_eq!(issuer.blind_sign(&n), Err(TokenError::Malformed));
+        assert_eq!(issuer.blind_sign(&[1, 2, 3]), Err(TokenError::Malformed));
+    }
+}
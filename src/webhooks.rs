@@ -0,0 +1,523 @@
+//! Delivery of new messages to webhooks registered by alias owners.
+//!
+//! Publishing a message writes a row to `webhook_outbox` in the same
+//! transaction, and [`run`] sends the due rows, retrying failures with
+//! exponential backoff. Every attempt is recorded in `webhook_deliveries`.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::Url;
+use serde::Serialize;
+use sha2::Sha256;
+use sqlx::PgPool;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::configuration::WebhookSettings;
+use crate::telemetry;
+
+pub const SIGNATURE_HEADER: &str = "x-blindchannel-signature";
+pub const DELIVERY_HEADER: &str = "x-blindchannel-delivery";
+const BATCH_SIZE: i64 = 10;
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+/// Time beyond the request timeouts a claimed batch is leased for.
+const LEASE_SLACK: Duration = Duration::from_secs(30);
+
+/// Body POSTed to a webhook.
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookPayload {
+    pub id: Uuid,
+    pub seq: i64,
+    pub recipient: String,
+    /// base64 encoded ciphertext for each recipient device, keyed by `kid`
+    pub content: BTreeMap<String, String>,
+    #[serde(rename = "sentAt")]
+    pub sent_at: DateTime<Utc>,
+    /// hex encoded hashes of attached encrypted blobs
+    pub attachments: Vec<String>,
+}
+
+/// Checks a URL the server POSTs to is absolute http(s), and unless
+/// `allow_insecure` is set, that it uses https and doesn't point at a loopback or private address.
+/// Host names are checked again as they resolve, by the [`client`] resolver.
+pub fn validate_url(url: &str, allow_insecure: bool) -> Result<Url, String> {
+    let url = Url::parse(url).map_err(|e| format!("invalid url: {e}"))?;
+    match url.scheme() {
+        "https" => {}
+        "http" if allow_insecure => {}
+        _ => return Err("webhook url must use https".into()),
+    }
+    let host = url.host_str().ok_or("webhook url must have a host")?;
+    if allow_insecure {
+        return Ok(url);
+    }
+    // parsing normalizes addresses, such as `0x7f000001` to `127.0.0.1`
+    let local = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => !is_public(ip),
+        Err(_) => host.eq_ignore_ascii_case("localhost"),
+    };
+    if local {
+        return Err("webhook url must not point at a local address".into());
+    }
+    Ok(url)
+}
+
+/// Whether the server may send requests to `ip`, which excludes loopback,
+/// private, link-local, shared and other special-purpose addresses, also
+/// when embedded in an IPv6 address.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // "this network", 0.0.0.0/8
+                || a == 0
+                // shared address space of carrier-grade NATs, 100.64.0.0/10
+                || (a == 100 && b & 0xc0 == 64)
+                // IETF protocol assignments, 192.0.0.0/24
+                || (a == 192 && b == 0 && c == 0)
+                // benchmarking, 198.18.0.0/15
+                || (a == 198 && b & 0xfe == 18)
+                // reserved, 240.0.0.0/4
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_public(v4.into());
+            }
+            let s = ip.segments();
+            let embedded = |hi: u16, lo: u16| is_public(Ipv4Addr::from((u32::from(hi) << 16) | u32::from(lo)).into());
+            match s {
+                // NAT64, 64:ff9b::/96
+                [0x64, 0xff9b, 0, 0, 0, 0, hi, lo] => embedded(hi, lo),
+                // 6to4, 2002::/16
+                [0x2002, hi, lo, ..] => embedded(hi, lo),
+                _ => {
+                    !(ip.is_multicast()
+                        // unspecified, loopback and IPv4-compatible, ::/96
+                        || s[..6] == [0; 6]
+                        // unique local, fc00::/7
+                        || s[0] & 0xfe00 == 0xfc00
+                        // link-local, fe80::/10, and deprecated site-local, fec0::/10
+                        || s[0] & 0xff80 == 0xfe80
+                        // Teredo, 2001::/32
+                        || (s[0] == 0x2001 && s[1] == 0)
+                        // documentation, 2001:db8::/32
+                        || (s[0] == 0x2001 && s[1] == 0xdb8))
+                }
+            }
+        }
+    }
+}
+
+/// Resolves host names only if every address they resolve to is public.
+/// Clients connect to the addresses returned, so a name can't resolve
+/// differently between the check and the connection.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if addrs.iter().any(|addr| !is_public(addr.ip())) {
+                return Err(format!("{} resolves to a local address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Client for requests to URLs given by users, such as webhooks and push
+/// endpoints. Unless `allow_insecure` is set, it refuses to connect to
+/// hosts resolving to anything but public addresses.
+pub fn client(timeout: Duration, allow_insecure: bool) -> reqwest::Result<reqwest::Client> {
+    let builder = reqwest::Client::builder()
+        .timeout(timeout)
+        .redirect(reqwest::redirect::Policy::none());
+    if allow_insecure {
+        return builder.build();
+    }
+    builder.dns_resolver(Arc::new(PublicResolver)).build()
+}
+
+/// Value of the signature header: the unix timestamp of the delivery and
+/// the hex HMAC-SHA256 of `"{timestamp}.{body}"` keyed with the webhook secret.
+pub fn signature(secret: &[u8], timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("t={timestamp},v1={hex}")
+}
+
+/// Delay before retrying after `attempts` failed attempts.
+pub fn backoff(base: Duration, attempts: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+    base.saturating_mul(factor).min(MAX_BACKOFF)
+}
+
+/// Outcome of a single delivery attempt.
+#[derive(Debug)]
+pub struct Attempt {
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+}
+impl Attempt {
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// POSTs a signed payload. Any 2xx answer counts as delivered.
+pub async fn send(
+    client: &reqwest::Client,
+    url: &str,
+    secret: &[u8],
+    delivery_id: Uuid,
+    payload: &WebhookPayload,
+) -> Attempt {
+    let body = serde_json::to_vec(payload).unwrap();
+    let signature = signature(secret, Utc::now().timestamp(), &body);
+    let response = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(SIGNATURE_HEADER, signature)
+        .header(DELIVERY_HEADER, delivery_id.to_string())
+        .body(body)
+        .send()
+        .await;
+    match response {
+        Ok(r) if r.status().is_success() => Attempt {
+            status_code: Some(r.status().as_u16()),
+            error: None,
+        },
+        Ok(r) => Attempt {
+            status_code: Some(r.status().as_u16()),
+            error: Some(format!("webhook answered {}", r.status())),
+        },
+        Err(e) => Attempt {
+            status_code: None,
+            error: Some(e.without_url().to_string()),
+        },
+    }
+}
+
+/// Sends due outbox rows until `shutdown` is cancelled, finishing the
+/// current batch first.
+pub async fn run(pool: PgPool, settings: WebhookSettings, shutdown: CancellationToken) {
+    let client = client(settings.timeout(), settings.allow_insecure).expect("failed to build webhook client");
+    let mut ticker = tokio::time::interval(settings.poll_interval());
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+        let start = std::time::Instant::now();
+        // keep going while full batches are due
+        loop {
+            match deliver_due(&pool, &client, &settings).await {
+                Ok(n) if n == BATCH_SIZE as usize && !shutdown.is_cancelled() => continue,
+                Ok(_) => break,
+                Err(e) => {
+                    telemetry::record_job_error("webhooks");
+                    tracing::error!("error delivering webhooks: {e}");
+                    break;
+                }
+            }
+        }
+        telemetry::record_job("webhooks", start.elapsed());
+    }
+}
+
+struct Due {
+    id: Uuid,
+    alias: String,
+    message_id: Uuid,
+    attempts: i32,
+    url: String,
+    secret: Vec<u8>,
+}
+
+/// Claims a batch of due deliveries and attempts them one after another.
+/// Claimed rows are leased until the whole batch could have timed out, so
+/// other instances skip them meanwhile.
+async fn deliver_due(pool: &PgPool, client: &reqwest::Client, settings: &WebhookSettings) -> sqlx::Result<usize> {
+    let lease = lease(settings.timeout()).as_secs_f64();
+    let due = sqlx::query_as!(
+        Due,
+        r#"UPDATE webhook_outbox o SET next_attempt_at = now() + make_interval(secs => $1)
+        FROM webhooks w
+        WHERE w.alias = o.alias AND o.id IN (
+            SELECT id FROM webhook_outbox WHERE next_attempt_at <= now()
+            ORDER BY next_attempt_at LIMIT $2 FOR UPDATE SKIP LOCKED
+        )
+        RETURNING o.id, o.alias, o.message_id, o.attempts, w.url, w.secret"#,
+        lease,
+        BATCH_SIZE
+    )
+    .fetch_all(pool)
+    .await?;
+    let count = due.len();
+    for delivery in due {
+        let Some(payload) = load_payload(pool, delivery.message_id).await? else {
+            // the message was deleted, which also dropped the outbox row
+            continue;
+        };
+        // literal addresses aren't resolved, so are checked here, against
+        // the current policy rather than the one at registration
+        let attempt = match validate_url(&delivery.url, settings.allow_insecure) {
+            Ok(_) => send(client, &delivery.url, &delivery.secret, delivery.id, &payload).await,
+            Err(error) => Attempt {
+                status_code: None,
+                error: Some(error),
+            },
+        };
+        let outcome = if attempt.succeeded() { "delivered" } else { "failed" };
+        metrics::counter!("blindchannel_webhook_attempts_total", "outcome" => outcome).increment(1);
+        record_attempt(pool, &delivery, &attempt, settings).await?;
+    }
+    Ok(count)
+}
+
+/// How long a claimed batch is leased: every request timing out, plus time
+/// for the queries around them.
+fn lease(timeout: Duration) -> Duration {
+    timeout * BATCH_SIZE as u32 + LEASE_SLACK
+}
+
+async fn load_payload(pool: &PgPool, message_id: Uuid) -> sqlx::Result<Option<WebhookPayload>> {
+    let Some(msg) = sqlx::query!(
+        r#"SELECT m.id, m.seq as "seq!", m.recipient as "recipient!", m.sent_at, array(
+            SELECT encode(blob_hash, 'hex') FROM message_blobs b WHERE b.message_id = m.id
+        ) as "attachments!"
+        FROM messages m WHERE m.id = $1"#,
+        message_id
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+    let copies = sqlx::query!(
+        "SELECT kid, content FROM message_copies WHERE message_id = $1",
+        message_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(Some(WebhookPayload {
+        id: msg.id,
+        seq: msg.seq,
+        recipient: msg.recipient,
+        content: copies.into_iter().map(|c| (c.kid, c.content)).collect(),
+        sent_at: msg.sent_at,
+        attachments: msg.attachments,
+    }))
+}
+
+async fn record_attempt(
+    pool: &PgPool,
+    delivery: &Due,
+    attempt: &Attempt,
+    settings: &WebhookSettings,
+) -> sqlx::Result<()> {
+    let attempts = delivery.attempts + 1;
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        r#"INSERT INTO webhook_deliveries (alias, delivery_id, message_id, attempt, status_code, error)
+        VALUES ($1, $2, $3, $4, $5, $6)"#,
+        delivery.alias,
+        delivery.id,
+        delivery.message_id,
+        attempts,
+        attempt.status_code.map(i32::from),
+        attempt.error
+    )
+    .execute(&mut *tx)
+    .await?;
+    if attempt.succeeded() || attempts as u32 >= settings.max_attempts {
+        if !attempt.succeeded() {
+            tracing::warn!("giving up webhook delivery {} after {attempts} attempts", delivery.id);
+        }
+        sqlx::query!("DELETE FROM webhook_outbox WHERE id = $1", delivery.id)
+            .execute(&mut *tx)
+            .await?;
+    } else {
+        let delay = backoff(settings.base_backoff(), attempts as u32).as_secs_f64();
+        sqlx::query!(
+            r#"UPDATE webhook_outbox
+            SET attempts = $2, next_attempt_at = now() + make_interval(secs => $3)
+            WHERE id = $1"#,
+            delivery.id,
+            attempts,
+            delay
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{HeaderMap, StatusCode};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn backoff_doubles_and_caps() {
+        let base = Duration::from_secs(5);
+        assert_eq!(backoff(base, 1), Duration::from_secs(5));
+        assert_eq!(backoff(base, 2), Duration::from_secs(10));
+        assert_eq!(backoff(base, 4), Duration::from_secs(40));
+        assert_eq!(backoff(base, 40), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn insecure_urls_rejected() {
+        for url in [
+            "http://example.com/hook",
+            "https://localhost/hook",
+            "https://127.0.0.1/hook",
+            "https://10.1.2.3/hook",
+            "https://[::1]/hook",
+            "https://[::ffff:127.0.0.1]/hook",
+            "https://[fe80::1]/hook",
+            "https://100.64.0.1/hook",
+            "https://0x7f000001/hook",
+            "ftp://example.com",
+        ] {
+            assert!(validate_url(url, false).is_err(), "accepted {url}");
+        }
+        assert!(validate_url("https://example.com/hook", false).is_ok());
+        assert!(validate_url("http://127.0.0.1:9000/hook", true).is_ok());
+    }
+
+    #[test]
+    fn classifies_addresses() {
+        for ip in ["93.184.215.14", "2606:2800:21f:cb07:6820:80da:af6b:8b2c", "64:ff9b::5db8:d70e"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip} is public");
+        }
+        for ip in [
+            "0.1.2.3",
+            "169.254.169.254",
+            "100.127.255.255",
+            "192.0.0.8",
+            "198.19.0.1",
+            "255.255.255.255",
+            "::",
+            "::127.0.0.1",
+            "fd00::1",
+            "fec0::1",
+            "ff02::1",
+            "64:ff9b::a00:1",
+            "2002:c0a8:101::1",
+            "2001::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip} is not public");
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_names_resolving_to_local_addresses() {
+        let error = PublicResolver.resolve("localhost".parse().unwrap()).await.err().unwrap();
+        assert!(error.to_string().contains("local address"));
+
+        let (url, seen) = stub(StatusCode::NO_CONTENT).await;
+        let url = url.replace("127.0.0.1", "localhost");
+        let client = client(Duration::from_secs(5), false).unwrap();
+        let attempt = send(&client, &url, b"secret", Uuid::new_v4(), &payload()).await;
+        assert!(!attempt.succeeded());
+        assert!(seen.lock().unwrap().is_empty());
+    }
+
+    fn payload() -> WebhookPayload {
+        WebhookPayload {
+            id: Uuid::new_v4(),
+            seq: 1,
+            recipient: "alice".into(),
+            content: [("default".to_string(), "AAAA".to_string())].into(),
+            sent_at: Utc::now(),
+            attachments: vec![],
+        }
+    }
+
+    /// Local HTTP stub recording the requests it gets and answering `status`.
+    async fn stub(status: StatusCode) -> (String, Arc<Mutex<Vec<(HeaderMap, Vec<u8>)>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorder = seen.clone();
+        let app = axum::Router::new().route(
+            "/hook",
+            axum::routing::post(move |headers: HeaderMap, body: axum::body::Bytes| async move {
+                recorder.lock().unwrap().push((headers, body.to_vec()));
+                status
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, seen)
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_payload() {
+        let (url, seen) = stub(StatusCode::NO_CONTENT).await;
+        let secret = b"webhook secret";
+        let delivery_id = Uuid::new_v4();
+        let attempt = send(&reqwest::Client::new(), &url, secret, delivery_id, &payload()).await;
+        assert!(attempt.succeeded(), "delivery failed: {attempt:?}");
+        assert_eq!(attempt.status_code, Some(204));
+
+        let seen = seen.lock().unwrap();
+        let (headers, body) = &seen[0];
+        assert_eq!(headers[DELIVERY_HEADER], delivery_id.to_string());
+        let header = headers[SIGNATURE_HEADER].to_str().unwrap();
+        let timestamp: i64 = header
+            .strip_prefix("t=")
+            .and_then(|h| h.split(',').next())
+            .and_then(|t| t.parse().ok())
+            .expect("signature header has no timestamp");
+        assert_eq!(header, signature(secret, timestamp, body));
+        assert_ne!(header, signature(b"other secret", timestamp, body));
+    }
+
+    #[tokio::test]
+    async fn error_status_is_a_failure() {
+        let (url, _) = stub(StatusCode::SERVICE_UNAVAILABLE).await;
+        let attempt = send(&reqwest::Client::new(), &url, b"secret", Uuid::new_v4(), &payload()).await;
+        assert!(!attempt.succeeded());
+        assert_eq!(attempt.status_code, Some(503));
+    }
+
+    #[tokio::test]
+    async fn unreachable_webhook_is_a_failure() {
+        let attempt = send(
+            &reqwest::Client::new(),
+            "http://127.0.0.1:1/hook",
+            b"secret",
+            Uuid::new_v4(),
+            &payload(),
+        )
+        .await;
+        assert!(!attempt.succeeded());
+        assert_eq!(attempt.status_code, None);
+    }
+}
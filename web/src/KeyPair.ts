@@ -78,6 +78,19 @@ export class EncryptedContent {
     const b64 = Base64.fromUint8Array(encrypted, true);
     return new EncryptedContent(b64);
   }
+
+  /// encrypts content for a device key fetched from the registry
+  static async fromJwk(jwk: PublicJwk, content: string): Promise<EncryptedContent> {
+    const publicKey = await window.crypto.subtle.importKey(
+      "jwk",
+      {kty: jwk.kty, n: jwk.n, e: jwk.e, alg: jwk.alg, ext: true},
+      {name: "RSA-OAEP", hash: "SHA-256"},
+      false,
+      ["encrypt"],
+    );
+    const encrypted = await window.crypto.subtle.encrypt({name: "RSA-OAEP"}, publicKey, new TextEncoder().encode(content));
+    return new EncryptedContent(Base64.fromUint8Array(new Uint8Array(encrypted), true));
+  }
   async toDecrypted(keyPair: KeyPair): Promise<string> {
     const bytes = Base64.toUint8Array(this.base64);
     return await keyPair.decrypt(bytes);
@@ -1,9 +1,67 @@
-import {DefaultApi, Message, PublicJwk, ResponseError} from './oapi';
+import {DefaultApi, InitOverrideFunction, Message, PublicJwk, ResponseError} from './oapi';
 import {KeyPair} from './KeyPair';
 import { EncryptedContent } from './KeyPair';
 
+/// one device key of an alias, as served in its JWK set
+export interface DeviceKey extends PublicJwk {
+    kid?: string;
+}
+
+interface JwkSet {
+    keys: DeviceKey[];
+}
+
 const Api = new DefaultApi();
 
+interface PowChallenge {
+    nonce: string;
+    bits: number;
+}
+
+function base64url(bytes: ArrayBuffer): string {
+    return btoa(String.fromCharCode(...new Uint8Array(bytes)))
+        .replace(/\+/g, '-').replace(/\//g, '_').replace(/=+$/, '');
+}
+
+function leadingZeroBits(hash: Uint8Array): number {
+    let bits = 0;
+    for (const byte of hash) {
+        if (byte !== 0) {
+            return bits + Math.clz32(byte) - 24;
+        }
+        bits += 8;
+    }
+    return bits;
+}
+
+/**
+ * solves a proof of work challenge for the alias, bound to the request body
+ * @returns {string} stamp for the `X-Hashcash` header
+ */
+async function solveChallenge(alias: string, action: 'publish' | 'register', body: string): Promise<string> {
+    const params = new URLSearchParams({alias, action});
+    const response = await fetch(`/api/v1/pow/challenge?${params}`);
+    const challenge: PowChallenge = await response.json();
+    const encoder = new TextEncoder();
+    const contentHash = base64url(await crypto.subtle.digest('SHA-256', encoder.encode(body)));
+    const prefix = `1:${challenge.bits}:${challenge.nonce}:${alias}:${contentHash}:`;
+    for (let counter = 0; ; counter++) {
+        const stamp = prefix + counter.toString(16);
+        const hash = await crypto.subtle.digest('SHA-256', encoder.encode(stamp));
+        if (leadingZeroBits(new Uint8Array(hash)) >= challenge.bits) {
+            return stamp;
+        }
+    }
+}
+
+/// adds a proof of work stamp for the alias to a request, made over the body as it will be sent
+function withStamp(alias: string, action: 'publish' | 'register'): InitOverrideFunction {
+    return async ({init}) => {
+        const stamp = await solveChallenge(alias, action, JSON.stringify(init.body));
+        return {headers: {...init.headers, 'X-Hashcash': stamp}};
+    };
+}
+
 /**
  * fetches messages sent to a recipient, with given limit.
  * @returns {(Message[]|undefined)} list of messages, or `undefined` if recipient not found
@@ -31,10 +89,10 @@ export async function registerAlias(keyPair: KeyPair): Promise<void | RegisterAl
     try {
         await Api.apiRegisterPost({
             registerRequest: {
-                alias: keyPair.alias,
+                name: keyPair.alias,
                 publicKey: await keyPair.publicJwk()
             }
-        });
+        }, withStamp(keyPair.alias, 'register'));
     } catch (e) {
         if (e instanceof ResponseError) {
             if (e.response.status === 409) {
@@ -49,32 +107,46 @@ export async function registerAlias(keyPair: KeyPair): Promise<void | RegisterAl
 export async function searchAlias(alias: string): Promise<string[]> {
     return await Api.apiSearchAliasGet({alias});
 }
-/// fetches a key associated with an alias
+/// fetches the device keys associated with an alias
 /// returns `undefined` if alias is not found
-export async function fetchAliasKey(alias: string): Promise<PublicJwk | undefined> {
-    try {
-        return await Api.apiRegistryAliasGet({alias});
-    } catch (e) {
-        if (e instanceof ResponseError) {
-            return undefined;
-        }
-        throw e;
+export async function fetchAliasKeys(alias: string): Promise<DeviceKey[] | undefined> {
+    const response = await fetch(`/api/v1/registry/${encodeURIComponent(alias)}`);
+    if (response.status === 404) {
+        return undefined;
+    }
+    if (!response.ok) {
+        throw new ResponseError(response);
     }
+    const jwks: JwkSet = await response.json();
+    return jwks.keys;
 }
 export enum PublishMessageError {
     RecipientDoesNotExist
 }
-/// publishes a message to a given recipient
+/// encrypts a message for every device of a given recipient and publishes it
 /// returns error if recipient does not exist
-export async function publishMessage(recipient: string, content: EncryptedContent): Promise<void | PublishMessageError> {
-    try {
-        await Api.apiPublishPost({publishMessage: {content: content.base64, recipient}});
-    } catch (e) {
-        if (e instanceof ResponseError) {
-            if (e.response.status == 404) {
-                return PublishMessageError.RecipientDoesNotExist;
-            }
-        }
-        throw e;
+export async function publishMessage(recipient: string, content: string): Promise<void | PublishMessageError> {
+    const keys = await fetchAliasKeys(recipient);
+    if (keys === undefined) {
+        return PublishMessageError.RecipientDoesNotExist;
+    }
+    const ciphertexts: Record<string, string> = {};
+    for (const key of keys) {
+        ciphertexts[key.kid ?? 'default'] = (await EncryptedContent.fromJwk(key, content)).base64;
+    }
+    const body = JSON.stringify({content: ciphertexts, recipient});
+    const response = await fetch('/api/v1/publish', {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json',
+            'X-Hashcash': await solveChallenge(recipient, 'publish', body),
+        },
+        body,
+    });
+    if (response.status === 404) {
+        return PublishMessageError.RecipientDoesNotExist;
+    }
+    if (!response.ok) {
+        throw new ResponseError(response);
     }
 }
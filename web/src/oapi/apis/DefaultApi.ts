@@ -81,7 +81,7 @@ export class DefaultApi extends runtime.BaseAPI {
         const headerParameters: runtime.HTTPHeaders = {};
 
         const response = await this.request({
-            path: `/api/messages`,
+            path: `/api/v1/messages`,
             method: 'GET',
             headers: headerParameters,
             query: queryParameters,
@@ -109,7 +109,7 @@ export class DefaultApi extends runtime.BaseAPI {
         headerParameters['Content-Type'] = 'application/json';
 
         const response = await this.request({
-            path: `/api/publish`,
+            path: `/api/v1/publish`,
             method: 'POST',
             headers: headerParameters,
             query: queryParameters,
@@ -137,7 +137,7 @@ export class DefaultApi extends runtime.BaseAPI {
         headerParameters['Content-Type'] = 'application/json';
 
         const response = await this.request({
-            path: `/api/register`,
+            path: `/api/v1/register`,
             method: 'POST',
             headers: headerParameters,
             query: queryParameters,
@@ -170,7 +170,7 @@ export class DefaultApi extends runtime.BaseAPI {
         const headerParameters: runtime.HTTPHeaders = {};
 
         const response = await this.request({
-            path: `/api/registry/{alias}`.replace(`{${"alias"}}`, encodeURIComponent(String(requestParameters['alias']))),
+            path: `/api/v1/registry/{alias}`.replace(`{${"alias"}}`, encodeURIComponent(String(requestParameters['alias']))),
             method: 'GET',
             headers: headerParameters,
             query: queryParameters,
@@ -203,7 +203,7 @@ export class DefaultApi extends runtime.BaseAPI {
         const headerParameters: runtime.HTTPHeaders = {};
 
         const response = await this.request({
-            path: `/api/search/{alias}`.replace(`{${"alias"}}`, encodeURIComponent(String(requestParameters['alias']))),
+            path: `/api/v1/search/{alias}`.replace(`{${"alias"}}`, encodeURIComponent(String(requestParameters['alias']))),
             method: 'GET',
             headers: headerParameters,
             query: queryParameters,
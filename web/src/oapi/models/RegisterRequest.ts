@@ -31,7 +31,7 @@ export interface RegisterRequest {
      * @type {string}
      * @memberof RegisterRequest
      */
-    alias: string;
+    name: string;
     /**
      * 
      * @type {PublicJwk}
@@ -44,7 +44,7 @@ export interface RegisterRequest {
  * Check if a given object implements the RegisterRequest interface.
  */
 export function instanceOfRegisterRequest(value: object): value is RegisterRequest {
-    if (!('alias' in value) || value['alias'] === undefined) return false;
+    if (!('name' in value) || value['name'] === undefined) return false;
     if (!('publicKey' in value) || value['publicKey'] === undefined) return false;
     return true;
 }
@@ -59,7 +59,7 @@ export function RegisterRequestFromJSONTyped(json: any, ignoreDiscriminator: boo
     }
     return {
         
-        'alias': json['alias'],
+        'name': json['name'],
         'publicKey': PublicJwkFromJSON(json['publicKey']),
     };
 }
@@ -70,7 +70,7 @@ export function RegisterRequestToJSON(value?: RegisterRequest | null): any {
     }
     return {
         
-        'alias': value['alias'],
+        'name': value['name'],
         'publicKey': PublicJwkToJSON(value['publicKey']),
     };
 }